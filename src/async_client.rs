@@ -0,0 +1,278 @@
+use std::ops::RangeBounds;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::client::{closed, unexpected};
+use crate::engines::{bytes_bound, into_string, into_string_pairs};
+use crate::protocol::{
+    decode, read_frame_async, split_id, write_message, write_tagged, Hello, HelloReply, Request,
+    Response, ScanRange, CAP_BATCH, CAP_CAS, CAP_SCAN, CAP_TTL, MAGIC, VERSION_REQUEST_IDS,
+};
+use crate::{KvsError, Result, WriteBatch};
+
+/// K-V store client whose requests are futures.
+///
+/// It has the API of `KvsClient`, with one request at a time per client. A request whose
+/// future is dropped once the request is sent does not disturb the next ones, as long as
+/// the server speaks a protocol version with request IDs.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    // agreed on with the server in the handshake
+    version: u32,
+    capabilities: Vec<String>,
+    next_id: u64,
+}
+
+impl AsyncKvsClient {
+    /// Connect to the address of a server, and agree on the protocol version and
+    /// capabilities to use with it.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut buf = MAGIC.to_vec();
+        write_message(&mut buf, &Hello::new())?;
+        writer.write_all(&buf).await?;
+        match read_frame_async(&mut reader).await? {
+            Some(payload) => match decode(&payload)? {
+                HelloReply::Accept {
+                    version,
+                    capabilities,
+                } => Ok(AsyncKvsClient {
+                    reader,
+                    writer,
+                    version,
+                    capabilities,
+                    next_id: 0,
+                }),
+                HelloReply::Reject(reason) => Err(KvsError::Protocol(reason)),
+            },
+            None => Err(closed()),
+        }
+    }
+
+    /// The protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// The capabilities both the client and the server support.
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Set the value of a key in the server.
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let id = self.send(&Request::Set { key, value }).await?;
+        self.receive_ok(id).await
+    }
+
+    /// Set the value of a key in the server, which expires after `ttl`.
+    pub async fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.require(CAP_TTL)?;
+        let id = self.send(&Request::SetWithTtl { key, value, ttl }).await?;
+        self.receive_ok(id).await
+    }
+
+    /// Get the value of a given key from the server.
+    ///
+    /// Returns `Ok(None)` if the key is not found.
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let id = self.send(&Request::Get { key }).await?;
+        match self.receive(id).await? {
+            Response::Ok(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove a given key in the server.
+    ///
+    /// Returns error if the key is not found.
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let id = self.send(&Request::Rm { key }).await?;
+        self.receive_ok(id).await
+    }
+
+    /// Set the value of a key in the server to `new` if its current value is `expected`.
+    /// `None` stands for a missing key.
+    ///
+    /// Returns whether the value was swapped.
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.require(CAP_CAS)?;
+        let id = self.send(&Request::Cas { key, expected, new }).await?;
+        match self.receive(id).await? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Apply all writes of a batch in the server, or none of them if it fails.
+    pub async fn batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.require(CAP_BATCH)?;
+        let id = self.send(&Request::Batch(batch)).await?;
+        self.receive_ok(id).await
+    }
+
+    /// Get the keys in `range` with their values from the server, in key order.
+    pub async fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = ScanRange::Range(range.start_bound().cloned(), range.end_bound().cloned());
+        self.scan_range(range).await
+    }
+
+    /// Get the keys starting with `prefix` with their values from the server, in key order.
+    pub async fn scan_prefix_bytes(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_range(ScanRange::Prefix(prefix)).await
+    }
+
+    /// Set the value of a string key in the server to a string.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Set the value of a string key in the server to a string, which expires after `ttl`.
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+            .await
+    }
+
+    /// Get the string value of a given string key from the server.
+    ///
+    /// Returns `Ok(None)` if the key is not found.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())
+            .await?
+            .map(into_string)
+            .transpose()
+    }
+
+    /// Remove a given string key in the server.
+    ///
+    /// Returns error if the key is not found.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Like `compare_and_swap_bytes`, with string values.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+        .await
+    }
+
+    /// Get the string keys in `range` with their string values from the server, in key
+    /// order.
+    pub async fn scan<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            bytes_bound(range.start_bound().cloned()),
+            bytes_bound(range.end_bound().cloned()),
+        );
+        into_string_pairs(self.scan_bytes(range).await?)
+    }
+
+    /// Get the string keys starting with `prefix` with their string values from the
+    /// server, in key order.
+    pub async fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes()).await?)
+    }
+
+    async fn scan_range(&mut self, range: ScanRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.require(CAP_SCAN)?;
+        let id = self.send(&Request::Scan(range)).await?;
+        let mut entries = Vec::new();
+        loop {
+            match self.receive(id).await? {
+                Response::Entry(key, value) => entries.push((key, value)),
+                Response::Ok(None) => return Ok(entries),
+                response => return Err(unexpected(response)),
+            }
+        }
+    }
+
+    // Fails unless the server agreed to use a capability.
+    fn require(&self, capability: &str) -> Result<()> {
+        if self.capabilities.iter().any(|cap| cap == capability) {
+            Ok(())
+        } else {
+            Err(KvsError::Protocol(format!(
+                "The server does not support {}",
+                capability
+            )))
+        }
+    }
+
+    // Sends a request with a new ID, which is returned.
+    async fn send(&mut self, request: &Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut buf = Vec::new();
+        if self.version >= VERSION_REQUEST_IDS {
+            write_tagged(&mut buf, id, request)?;
+        } else {
+            write_message(&mut buf, request)?;
+        }
+        self.writer.write_all(&buf).await?;
+        Ok(id)
+    }
+
+    // Reads the next response to the request `id`, turning an error sent by the server
+    // into an `Err`. The responses to the earlier requests, given up on, are skipped.
+    async fn receive(&mut self, id: u64) -> Result<Response> {
+        let response = loop {
+            let frame = read_frame_async(&mut self.reader)
+                .await?
+                .ok_or_else(closed)?;
+            if self.version < VERSION_REQUEST_IDS {
+                break decode(&frame)?;
+            }
+            let (response_id, payload) = split_id(&frame)?;
+            if response_id == id {
+                break decode(payload)?;
+            }
+            if response_id > id {
+                return Err(KvsError::Protocol(format!(
+                    "Response to request {} while expecting request {}",
+                    response_id, id
+                )));
+            }
+        };
+        match response {
+            Response::Err(kind, msg) => Err(KvsError::from_server(kind, msg)),
+            response => Ok(response),
+        }
+    }
+
+    async fn receive_ok(&mut self, id: u64) -> Result<()> {
+        match self.receive(id).await? {
+            Response::Ok(None) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
+
+use crate::protocol::legacy::{JsonRequest, JsonResponses, JsonSplitter, MAX_LEGACY_REQUEST_LEN};
+use crate::protocol::{
+    decode, read_frame_async, split_id, write_message, write_tagged, Hello, HelloReply, Request,
+    Response, CAP_ADMIN, CAP_AUTH, CAP_REPLICATION, MAGIC, VERSION_REQUEST_IDS,
+};
+use crate::server::{execute, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::thread_pool::ThreadPool;
+use crate::{ErrorKind, KvsEngine, KvsError, Result};
+
+// how many responses to a request can wait for the connection to send them
+const RESPONSE_QUEUE_LEN: usize = 64;
+
+/// K-V store server serving each connection on a task instead of a thread, so that idle
+/// connections cost next to nothing.
+///
+/// It speaks the same protocols as `KvsServer`, but without authentication, so it runs
+/// neither the admin requests nor replication. The engine is only called from the thread
+/// pool, as its calls block.
+pub struct AsyncKvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+    shutdown_timeout: Duration,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsServer<E, P> {
+    /// Create an `AsyncKvsServer` with given store engine and the pool running the calls
+    /// to the engine.
+    pub fn new(engine: E, pool: P) -> Self {
+        AsyncKvsServer {
+            engine,
+            pool: Arc::new(pool),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a shutdown waits for the requests being served before dropping their
+    /// connections.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serves clients on `addr` forever.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_until(addr, future::pending()).await
+    }
+
+    /// Serves clients on `addr` until `shutdown` completes. Then the connections read no
+    /// more requests, the requests already read are answered, and the engine is flushed.
+    pub async fn run_until<A, F>(self, addr: A, shutdown: F) -> Result<()>
+    where
+        A: ToSocketAddrs,
+        F: Future<Output = ()>,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let started = Instant::now();
+        info!("AsyncKvsServer: start working!");
+        let (stop_sender, stop) = watch::channel(false);
+        // every connection holds a sender, so receiving `None` means they are all done
+        let (done_sender, mut done) = mpsc::channel::<()>(1);
+
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let connection = Connection {
+                engine: self.engine.clone(),
+                pool: self.pool.clone(),
+                peer_addr,
+                started,
+                stop: stop.clone(),
+            };
+            let done_sender = done_sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection.serve(stream).await {
+                    error!("Error when serving client: {}", e);
+                }
+                drop(done_sender);
+            });
+        }
+
+        drop(listener);
+        let _ = stop_sender.send(true);
+        drop(done_sender);
+        if time::timeout(self.shutdown_timeout, done.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Dropping the connections still busy after {:?}",
+                self.shutdown_timeout
+            );
+        }
+        let engine = self.engine.clone();
+        run_blocking(&*self.pool, move || engine.flush()).await??;
+        info!("AsyncKvsServer: shut down");
+        Ok(())
+    }
+}
+
+struct Connection<E, P> {
+    engine: E,
+    pool: Arc<P>,
+    peer_addr: SocketAddr,
+    started: Instant,
+    // becomes true on a shutdown
+    stop: watch::Receiver<bool>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> Connection<E, P> {
+    async fn serve(mut self, stream: TcpStream) -> Result<()> {
+        debug!("Connected to {}", self.peer_addr);
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let writer = BufWriter::new(writer);
+
+        // a JSON request cannot start with the magic bytes
+        let first = self
+            .next(async { Ok(reader.fill_buf().await?.first().cloned()) })
+            .await?;
+        if first == Some(Some(MAGIC[0])) {
+            self.serve_binary(reader, writer).await
+        } else {
+            debug!("Serve {} with the legacy JSON protocol", self.peer_addr);
+            self.serve_legacy(reader, writer).await
+        }
+    }
+
+    // Serves a client of the binary protocol, starting with the handshake.
+    async fn serve_binary(
+        &mut self,
+        mut reader: BufReader<OwnedReadHalf>,
+        mut writer: BufWriter<OwnedWriteHalf>,
+    ) -> Result<()> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).await?;
+        if magic != *MAGIC {
+            return Err(KvsError::Protocol("bad magic bytes".to_owned()));
+        }
+        let hello: Hello = match read_frame_async(&mut reader).await? {
+            Some(payload) => decode(&payload)?,
+            None => return Ok(()),
+        };
+        let reply = hello.reply(&[CAP_AUTH, CAP_ADMIN, CAP_REPLICATION]);
+        let mut buf = Vec::new();
+        write_message(&mut buf, &reply)?;
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+        let version = match reply {
+            HelloReply::Accept { version, .. } => version,
+            HelloReply::Reject(reason) => return Err(KvsError::Protocol(reason)),
+        };
+        let tagged = version >= VERSION_REQUEST_IDS;
+
+        while let Some(frame) = self.next(read_frame_async(&mut reader)).await?.flatten() {
+            let (id, payload) = if tagged {
+                split_id(&frame)?
+            } else {
+                (0, &frame[..])
+            };
+            let mut responses = match decode(payload) {
+                // anyone could run them, as no client is authenticated
+                Ok(Request::Stats | Request::Compact | Request::Flush | Request::Info) => {
+                    Responses::one(Response::Err(
+                        ErrorKind::Protocol,
+                        "Admin requests are not supported by this server".to_owned(),
+                    ))
+                }
+                Ok(request) => {
+                    debug!("Receive request from {}: {:?}", self.peer_addr, request);
+                    let engine = self.engine.clone();
+                    let started = self.started;
+                    run_streaming(&*self.pool, move |respond| {
+                        execute(&engine, started, request, respond)
+                    })
+                }
+                // the frame is skipped, so the next requests can still be served
+                Err(e) => {
+                    error!("Bad request from {}: {}", self.peer_addr, e);
+                    Responses::one(Response::Err(e.kind(), e.to_string()))
+                }
+            };
+            while let Some(response) = responses.next().await? {
+                buf.clear();
+                if tagged {
+                    write_tagged(&mut buf, id, &response)?;
+                } else {
+                    write_message(&mut buf, &response)?;
+                }
+                writer.write_all(&buf).await?;
+            }
+            // the responses to pipelined requests which have already arrived go out together
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    // Serves a client of the legacy JSON protocol.
+    async fn serve_legacy(
+        &mut self,
+        mut reader: BufReader<OwnedReadHalf>,
+        mut writer: BufWriter<OwnedWriteHalf>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut splitter = JsonSplitter::default();
+        loop {
+            // the requests complete so far, leaving the rest for the next read
+            let mut requests = Vec::new();
+            while let Some(len) = splitter.next_value(&buf) {
+                let request = serde_json::from_slice::<JsonRequest>(&buf[..len])
+                    .map_err(|e| KvsError::Protocol(format!("deserializing error {}", e)))?;
+                requests.push(request);
+                buf.drain(..len);
+            }
+            if buf.len() > MAX_LEGACY_REQUEST_LEN {
+                return Err(KvsError::Protocol(format!(
+                    "Request longer than {} bytes",
+                    MAX_LEGACY_REQUEST_LEN
+                )));
+            }
+
+            for request in requests {
+                debug!("Receive request from {}: {:?}", self.peer_addr, request);
+                let engine = self.engine.clone();
+                let started = self.started;
+                let mut responses = run_streaming(&*self.pool, move |respond| {
+                    let mut json = JsonResponses::default();
+                    execute(&engine, started, request.into(), &mut |response| match json
+                        .convert(response)
+                    {
+                        Some(response) => respond(response),
+                        None => Ok(()),
+                    })
+                });
+                let mut out = Vec::new();
+                while let Some(response) = responses.next().await? {
+                    out.clear();
+                    serde_json::to_writer(&mut out, &response)?;
+                    writer.write_all(&out).await?;
+                }
+                writer.flush().await?;
+            }
+
+            let mut chunk = [0; 4096];
+            let read = match self
+                .next(async { Ok(reader.read(&mut chunk).await?) })
+                .await?
+            {
+                Some(read) if read > 0 => read,
+                _ if buf.iter().all(u8::is_ascii_whitespace) => return Ok(()),
+                _ => {
+                    return Err(KvsError::Protocol(
+                        "Connection closed in the middle of a request".to_owned(),
+                    ))
+                }
+            };
+            buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    // Waits for the next read, unless the server shuts down first. Once it does, only
+    // what has already arrived is read.
+    async fn next<T, F: Future<Output = Result<T>>>(&mut self, read: F) -> Result<Option<T>> {
+        tokio::pin!(read);
+        if !*self.stop.borrow() {
+            tokio::select! {
+                biased;
+                result = &mut read => return result.map(Some),
+                _ = self.stop.changed() => {}
+            }
+        }
+        tokio::select! {
+            biased;
+            result = &mut read => result.map(Some),
+            _ = future::ready(()) => Ok(None),
+        }
+    }
+}
+
+// The responses to a request run on the pool, which come through a bounded queue as they
+// are made, so that the entries of a scan are never all in memory.
+struct Responses<T> {
+    receiver: mpsc::Receiver<Option<T>>,
+}
+
+impl<T> Responses<T> {
+    fn one(response: T) -> Self {
+        let (sender, receiver) = mpsc::channel(2);
+        let _ = sender.try_send(Some(response));
+        let _ = sender.try_send(None);
+        Responses { receiver }
+    }
+
+    // Waits for the next response, or returns `None` once there are no more.
+    async fn next(&mut self) -> Result<Option<T>> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or_else(|| KvsError::Other("A job of the thread pool panicked".to_owned()))
+    }
+}
+
+// Runs a request on the pool, which passes its responses to `respond` and blocks while the
+// queue of the connection is full.
+fn run_streaming<P, T, F>(pool: &P, run: F) -> Responses<T>
+where
+    P: ThreadPool,
+    T: Send + 'static,
+    F: FnOnce(&mut dyn FnMut(T) -> Result<()>) -> Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(RESPONSE_QUEUE_LEN);
+    pool.spawn(move || {
+        let mut respond = |response| {
+            sender
+                .blocking_send(Some(response))
+                .map_err(|_| KvsError::Other("The connection is closed".to_owned()))
+        };
+        // `None` ends the responses, unless the connection is gone
+        if run(&mut respond).is_ok() {
+            let _ = sender.blocking_send(None);
+        }
+    });
+    Responses { receiver }
+}
+
+// Runs a blocking call on the pool.
+async fn run_blocking<P, F, T>(pool: &P, f: F) -> Result<T>
+where
+    P: ThreadPool,
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    pool.spawn(move || {
+        let _ = sender.send(f());
+    });
+    receiver
+        .await
+        .map_err(|_| KvsError::Other("A job of the thread pool panicked".to_owned()))
+}
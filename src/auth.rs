@@ -0,0 +1,193 @@
+//! Principals of a `KvsServer`, and the keys they may read and write.
+//!
+//! The credentials file is a JSON object mapping the name of each principal to its secret,
+//! a password or a token, and the key prefixes it may read and write:
+//!
+//! ```json
+//! {
+//!     "admin": {"secret": "hunter2", "read": [""], "write": [""], "admin": true},
+//!     "app": {"secret": "c2VjcmV0", "read": ["app/", "shared/"], "write": ["app/"]},
+//!     "anonymous": {"read": ["public/"]}
+//! }
+//! ```
+//!
+//! The empty prefix covers every key. Writing a key does not imply reading it, and a
+//! compare-and-swap needs both. Only the principals with `admin` set may run the admin
+//! requests, such as `Request::Compact`. Clients which do not authenticate get the ACL of
+//! `anonymous`, whose secret is ignored, and are refused everything if there is none.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::protocol::Request;
+use crate::{KvsError, Result};
+
+// The principal of the clients which do not authenticate
+const ANONYMOUS: &str = "anonymous";
+
+/// Principals allowed to use a `KvsServer`, with their secrets and ACLs.
+pub struct Credentials {
+    principals: HashMap<String, Arc<Principal>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Principal {
+    #[serde(default)]
+    secret: Option<String>,
+    // key prefixes
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+impl Credentials {
+    /// Loads the credentials file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let principals: HashMap<String, Principal> = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| {
+                KvsError::Serialization(format!("Bad credentials file {}: {}", path.display(), e))
+            })?;
+        Ok(Credentials {
+            principals: principals
+                .into_iter()
+                .map(|(name, principal)| (name, Arc::new(principal)))
+                .collect(),
+        })
+    }
+
+    // Checks the secret of a principal, returning its access.
+    pub(crate) fn authenticate(&self, name: &str, secret: &str) -> Result<Access> {
+        match self.principals.get(name) {
+            Some(principal) if name != ANONYMOUS => match &principal.secret {
+                Some(expected) if secrets_match(expected.as_bytes(), secret.as_bytes()) => {
+                    debug!("Authenticated {}", name);
+                    Ok(Access::Principal(name.to_owned(), principal.clone()))
+                }
+                _ => Err(bad_credentials(name)),
+            },
+            _ => Err(bad_credentials(name)),
+        }
+    }
+
+    // The access of a client which has not authenticated.
+    pub(crate) fn anonymous(&self) -> Access {
+        let principal = self.principals.get(ANONYMOUS).cloned().unwrap_or_default();
+        Access::Principal(ANONYMOUS.to_owned(), principal)
+    }
+}
+
+// Compares secrets in a time which does not depend on where they differ.
+fn secrets_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn bad_credentials(name: &str) -> KvsError {
+    warn!("Failed authentication as {}", name);
+    KvsError::PermissionDenied("bad name or secret".to_owned())
+}
+
+// What a connection may do.
+#[derive(Clone)]
+pub(crate) enum Access {
+    // the server has no credentials
+    Unrestricted,
+    Principal(String, Arc<Principal>),
+}
+
+impl Access {
+    // The access of a client which has not authenticated to a server with `credentials`.
+    pub(crate) fn anonymous(credentials: Option<&Credentials>) -> Access {
+        credentials.map_or(Access::Unrestricted, Credentials::anonymous)
+    }
+
+    pub(crate) fn can_read(&self, key: &[u8]) -> bool {
+        match self {
+            Access::Unrestricted => true,
+            Access::Principal(_, principal) => covers(&principal.read, key),
+        }
+    }
+
+    pub(crate) fn can_write(&self, key: &[u8]) -> bool {
+        match self {
+            Access::Unrestricted => true,
+            Access::Principal(_, principal) => covers(&principal.write, key),
+        }
+    }
+
+    pub(crate) fn check_read(&self, key: &[u8]) -> Result<()> {
+        if self.can_read(key) {
+            Ok(())
+        } else {
+            Err(self.denied("read", key))
+        }
+    }
+
+    pub(crate) fn check_write(&self, key: &[u8]) -> Result<()> {
+        if self.can_write(key) {
+            Ok(())
+        } else {
+            Err(self.denied("write", key))
+        }
+    }
+
+    // Checks that a request only touches keys the ACL allows. A scan is allowed as long
+    // as its entries are filtered with `can_read`.
+    pub(crate) fn check(&self, request: &Request) -> Result<()> {
+        match request {
+            Request::Get { key } => self.check_read(key),
+            Request::Set { key, .. } | Request::SetWithTtl { key, .. } | Request::Rm { key } => {
+                self.check_write(key)
+            }
+            Request::Cas { key, .. } => {
+                self.check_read(key)?;
+                self.check_write(key)
+            }
+            Request::Batch(batch) => batch.keys().try_for_each(|key| self.check_write(key)),
+            Request::Scan(_) => Ok(()),
+            Request::Stats
+            | Request::Compact
+            | Request::Flush
+            | Request::Info
+            | Request::Replicate
+            | Request::Promote => self.check_admin(),
+        }
+    }
+
+    fn check_admin(&self) -> Result<()> {
+        match self {
+            Access::Unrestricted => Ok(()),
+            Access::Principal(_, principal) if principal.admin => Ok(()),
+            Access::Principal(name, _) => {
+                warn!("Refused to let {} run an admin request", name);
+                Err(KvsError::PermissionDenied(format!(
+                    "{} may not run admin requests",
+                    name
+                )))
+            }
+        }
+    }
+
+    fn denied(&self, what: &str, key: &[u8]) -> KvsError {
+        let name = match self {
+            Access::Unrestricted => unreachable!("an unrestricted access is never denied"),
+            Access::Principal(name, _) => name,
+        };
+        let key = String::from_utf8_lossy(key);
+        warn!("Refused to let {} {} the key {}", name, what, key);
+        KvsError::PermissionDenied(format!("{} may not {} the key {}", name, what, key))
+    }
+}
+
+fn covers(prefixes: &[String], key: &[u8]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| key.starts_with(prefix.as_bytes()))
+}
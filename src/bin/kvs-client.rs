@@ -1,30 +1,92 @@
 use clap::{App, AppSettings, Arg, SubCommand};
 use env_logger::Env;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::process;
+use std::time::Duration;
 
-use kvs::{KvsClient, Result};
+use kvs::{ClientOptions, ClientTls, KvsClient, Result};
 
 struct Opt {
     addr: String,
+    tls: Option<TlsOpt>,
+    login: Option<(String, String)>,
     cmd: Command,
 }
 
+struct TlsOpt {
+    ca: String,
+    identity: Option<(String, String)>,
+    server_name: Option<String>,
+}
+
 enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    },
+    Rm {
+        key: String,
+    },
+    Get {
+        key: String,
+    },
+    Scan {
+        prefix: Option<String>,
+        start: Option<String>,
+        end: Option<String>,
+    },
+    Stats,
+    Compact,
+    Flush,
+    Info,
+    Promote,
 }
 
 fn get_opt() -> Opt {
-    let addr;
-    let cmd;
-
     let addr_arg = Arg::with_name("addr")
         .long("addr")
         .takes_value(true)
-        .value_name("IP-PORT")
+        .value_name("ADDR")
         .default_value("127.0.0.1:4000")
-        .help("the server address");
+        .help("the server address, IP:PORT or unix:PATH for a Unix domain socket");
+    let connect_args = [
+        Arg::with_name("tls-ca")
+            .long("tls-ca")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("connect over TLS, trusting the CA certificates in this PEM file"),
+        Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires_all(&["tls-ca", "tls-key"])
+            .help("the PEM certificate chain to present to the server"),
+        Arg::with_name("tls-key")
+            .long("tls-key")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("tls-cert")
+            .help("the PEM private key of --tls-cert"),
+        Arg::with_name("tls-server-name")
+            .long("tls-server-name")
+            .takes_value(true)
+            .value_name("NAME")
+            .requires("tls-ca")
+            .help("the name the server certificate must be valid for [default: the server IP]"),
+        Arg::with_name("user")
+            .long("user")
+            .takes_value(true)
+            .value_name("NAME")
+            .help("authenticate as this principal, with --secret"),
+        Arg::with_name("secret")
+            .long("secret")
+            .takes_value(true)
+            .value_name("SECRET")
+            .env("KVS_SECRET")
+            .hide_env_values(true)
+            .help("the password or token of --user"),
+    ];
     let matches = App::new("kvs-client")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -37,46 +99,155 @@ fn get_opt() -> Opt {
                     "<KEY> 'the key you want to set'
                     <VALUE> 'the value you want to set to the key'",
                 )
-                .arg(addr_arg.clone()),
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .validator(|s| {
+                            s.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| "not a number".to_owned())
+                        })
+                        .help("remove the key after this many seconds"),
+                )
+                .arg(addr_arg.clone())
+                .args(&connect_args),
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
                 .arg_from_usage("<KEY> 'the key you want to look up'")
-                .arg(addr_arg.clone()),
+                .arg(addr_arg.clone())
+                .args(&connect_args),
             SubCommand::with_name("rm")
                 .about("Remove a given key")
                 .arg_from_usage("<KEY> 'the key you want to remove'")
-                .arg(addr_arg),
+                .arg(addr_arg.clone())
+                .args(&connect_args),
+            SubCommand::with_name("scan")
+                .about("List keys and their values in key order")
+                .arg_from_usage("[PREFIX] 'only list the keys starting with it'")
+                .arg(
+                    Arg::from_usage("--start=[KEY] 'the first key to list'")
+                        .conflicts_with("PREFIX"),
+                )
+                .arg(
+                    Arg::from_usage("--end=[KEY] 'list the keys before it'")
+                        .conflicts_with("PREFIX"),
+                )
+                .arg(addr_arg.clone())
+                .args(&connect_args),
+            SubCommand::with_name("admin")
+                .about("Administer the server")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .subcommands(
+                    [
+                        ("stats", "Show figures about the storage of the engine"),
+                        ("compact", "Compact the engine now"),
+                        ("flush", "Make every write so far durable"),
+                        (
+                            "info",
+                            "Show the version, engine, uptime and role of the server",
+                        ),
+                        ("promote", "Make a follower a leader"),
+                    ]
+                    .iter()
+                    .map(|&(name, about)| {
+                        SubCommand::with_name(name)
+                            .about(about)
+                            .arg(addr_arg.clone())
+                            .args(&connect_args)
+                    }),
+                ),
         ])
         .get_matches();
-    match matches.subcommand() {
-        ("set", Some(matches)) => {
-            addr = matches.value_of("addr").expect("wtf").to_owned();
+    let (name, matches) = match matches.subcommand() {
+        // the admin commands take the connection arguments themselves
+        ("admin", Some(matches)) => match matches.subcommand() {
+            (name, Some(matches)) => (name, matches),
+            _ => unreachable!(),
+        },
+        (name, Some(matches)) => (name, matches),
+        _ => {
+            eprintln!("No command specified");
+            process::exit(1);
+        }
+    };
+    let addr = matches.value_of("addr").expect("wtf").to_owned();
+    let tls = matches.value_of("tls-ca").map(|ca| TlsOpt {
+        ca: ca.to_owned(),
+        identity: matches.value_of("tls-cert").map(|cert| {
+            let key = matches.value_of("tls-key").unwrap();
+            (cert.to_owned(), key.to_owned())
+        }),
+        server_name: matches.value_of("tls-server-name").map(str::to_owned),
+    });
+    let login = matches.value_of("user").map(|user| {
+        let secret = matches.value_of("secret").unwrap_or_else(|| {
+            eprintln!("--user needs --secret or KVS_SECRET");
+            process::exit(1);
+        });
+        (user.to_owned(), secret.to_owned())
+    });
+    let cmd = match name {
+        "set" => {
             let key = matches.value_of("KEY").unwrap().to_owned();
             let value = matches.value_of("VALUE").unwrap().to_owned();
-            cmd = Command::Set { key, value };
+            let ttl = matches
+                .value_of("ttl")
+                .map(|s| Duration::from_secs(s.parse().unwrap()));
+            Command::Set { key, value, ttl }
         }
-        ("get", Some(matches)) => {
-            addr = matches.value_of("addr").expect("wtf").to_owned();
+        "get" => {
             let key = matches.value_of("KEY").unwrap().to_owned();
-            cmd = Command::Get { key };
+            Command::Get { key }
         }
-        ("rm", Some(matches)) => {
-            addr = matches.value_of("addr").expect("wtf").to_owned();
+        "rm" => {
             let key = matches.value_of("KEY").unwrap().to_owned();
-            cmd = Command::Rm { key };
+            Command::Rm { key }
         }
-        _ => {
-            eprintln!("No command specified");
-            process::exit(1);
+        "scan" => {
+            let prefix = matches.value_of("PREFIX").map(str::to_owned);
+            let start = matches.value_of("start").map(str::to_owned);
+            let end = matches.value_of("end").map(str::to_owned);
+            Command::Scan { prefix, start, end }
         }
+        "stats" => Command::Stats,
+        "compact" => Command::Compact,
+        "flush" => Command::Flush,
+        "info" => Command::Info,
+        "promote" => Command::Promote,
+        _ => unreachable!(),
     };
-    Opt { addr, cmd }
+    Opt {
+        addr,
+        tls,
+        login,
+        cmd,
+    }
 }
 
 fn run(opt: Opt) -> Result<()> {
-    let mut client = KvsClient::connect(opt.addr)?;
+    let mut options = ClientOptions::default();
+    if let Some(tls) = opt.tls {
+        let mut config = ClientTls::from_ca_file(tls.ca)?;
+        if let Some((cert, key)) = tls.identity {
+            config = config.identity(cert, key)?;
+        }
+        if let Some(name) = tls.server_name {
+            config = config.server_name(&name)?;
+        }
+        options = options.tls(config);
+    }
+    if let Some((user, secret)) = &opt.login {
+        options = options.login(user, secret);
+    }
+    let mut client = KvsClient::connect_with(opt.addr, options)?;
     match opt.cmd {
-        Command::Set { key, value } => client.set(key, value),
+        Command::Set { key, value, ttl } => match ttl {
+            Some(ttl) => client.set_with_ttl(key, value, ttl),
+            None => client.set(key, value),
+        },
         Command::Rm { key } => client.remove(key),
         Command::Get { key } => {
             if let Some(value) = client.get(key)? {
@@ -86,6 +257,52 @@ fn run(opt: Opt) -> Result<()> {
             }
             Ok(())
         }
+        Command::Scan { prefix, start, end } => {
+            let entries = match prefix {
+                Some(prefix) => client.scan_prefix(prefix)?,
+                None => client.scan((
+                    start.map_or(Unbounded, Included),
+                    end.map_or(Unbounded, Excluded),
+                ))?,
+            };
+            for (key, value) in entries {
+                println!("{}\t{}", key, value);
+            }
+            Ok(())
+        }
+        Command::Stats => {
+            let stats = client.stats()?;
+            let figures = [
+                ("keys", stats.keys),
+                ("disk_bytes", Some(stats.disk_bytes)),
+                ("dead_entries", stats.dead_entries),
+                ("compactions", stats.compactions),
+            ];
+            // the engine does not track the others
+            for (name, value) in &figures {
+                if let Some(value) = value {
+                    println!("{}\t{}", name, value);
+                }
+            }
+            Ok(())
+        }
+        Command::Compact => client.compact(),
+        Command::Flush => client.flush(),
+        Command::Info => {
+            let info = client.info()?;
+            println!("version\t{}", info.version);
+            println!("engine\t{}", info.engine);
+            println!("uptime\t{}s", info.uptime.as_secs());
+            match info.leader {
+                Some(leader) => {
+                    println!("role\tfollower");
+                    println!("leader\t{}", leader);
+                }
+                None => println!("role\tleader"),
+            }
+            Ok(())
+        }
+        Command::Promote => client.promote(),
     }
 }
 
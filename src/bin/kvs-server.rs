@@ -1,17 +1,23 @@
 #[macro_use]
 extern crate log;
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use env_logger::Env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::time::Duration;
 use std::{env, process};
 
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsServer, Result, SledKvsEngine};
+#[cfg(feature = "async")]
+use kvs::AsyncKvsServer;
+use kvs::{
+    ClientOptions, Credentials, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    KvsServer, RecoveryMode, Result, ServerTls, SledKvsEngine,
+};
 
 fn main() {
-    let matches = App::new("kvs-server")
+    let app = App::new("kvs-server")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("A key-value store server")
@@ -20,9 +26,37 @@ fn main() {
             Arg::with_name("addr")
                 .long("addr")
                 .takes_value(true)
-                .value_name("IP-PORT")
+                .value_name("ADDR")
                 .default_value("127.0.0.1:4000")
-                .help("the server address"),
+                .help("the server address, IP:PORT or unix:PATH for a Unix domain socket"),
+        )
+        .arg(
+            Arg::with_name("unix-socket")
+                .long("unix-socket")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("also serve clients on the Unix domain socket at this path"),
+        )
+        .arg(
+            Arg::with_name("resp-addr")
+                .long("resp-addr")
+                .takes_value(true)
+                .value_name("IP-PORT")
+                .help("also serve clients of the Redis protocol (RESP2) on this address"),
+        )
+        .arg(
+            Arg::with_name("http-addr")
+                .long("http-addr")
+                .takes_value(true)
+                .value_name("IP-PORT")
+                .help("also serve the HTTP gateway on this address"),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .takes_value(true)
+                .value_name("IP-PORT")
+                .help("also serve Prometheus metrics at /metrics on this address"),
         )
         .arg(
             Arg::with_name("engine")
@@ -32,11 +66,186 @@ fn main() {
                 .possible_values(&["kvs", "sled"])
                 .help("the server address"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("recovery")
+                .long("recovery")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["truncate", "refuse"])
+                .default_value("truncate")
+                .help("what to do with a damaged log tail left by a crash"),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .long("durability")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["none", "write", "interval", "group"])
+                .help("when writes are synced to the disk: never explicitly, on every write, every --sync-interval, or on every write with concurrent writes sharing a sync [default: none for kvs, write for sled]"),
+        )
+        .arg(
+            Arg::with_name("sync-interval")
+                .long("sync-interval")
+                .takes_value(true)
+                .value_name("MS")
+                .default_value("1000")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| "not a number".to_owned())
+                })
+                .help("milliseconds between syncs with --durability interval"),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| "not a number".to_owned())
+                })
+                .help("how long to wait for the requests being served on SIGINT or SIGTERM, before closing their connections"),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(positive)
+                .help("close a connection when a request it has started does not arrive in full for this long"),
+        )
+        .arg(
+            Arg::with_name("write-timeout")
+                .long("write-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(positive)
+                .help("close a connection when its client does not take a response for this long"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(positive)
+                .help("close a connection when no request starts for this long"),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .takes_value(true)
+                .value_name("N")
+                .validator(positive)
+                .help("turn clients away with an error beyond this many connections over all listeners"),
+        )
+        .arg(
+            Arg::with_name("backlog")
+                .long("backlog")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("128")
+                .validator(positive)
+                .help("how many connections the OS queues for each listener before refusing more"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls-key")
+                .help("serve --addr, --resp-addr and --http-addr over TLS with the PEM certificate chain in this file"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls-cert")
+                .help("the PEM private key of --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .long("tls-client-ca")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls-cert")
+                .help("require client certificates signed by a CA in this PEM file"),
+        )
+        .arg(
+            Arg::with_name("credentials")
+                .long("credentials")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("let clients authenticate as the principals of this JSON file, and use only the keys their ACLs allow"),
+        )
+        .arg(
+            Arg::with_name("replica-of")
+                .long("replica-of")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("serve reads as a follower of the leader at this address, until promoted"),
+        )
+        .arg(
+            Arg::with_name("replica-user")
+                .long("replica-user")
+                .takes_value(true)
+                .value_name("NAME")
+                .requires("replica-of")
+                .help("authenticate to the leader as this admin principal, with --replica-secret"),
+        )
+        .arg(
+            Arg::with_name("replica-secret")
+                .long("replica-secret")
+                .takes_value(true)
+                .value_name("SECRET")
+                .env("KVS_REPLICA_SECRET")
+                .hide_env_values(true)
+                .help("the password or token of --replica-user"),
+        );
+    #[cfg(feature = "async")]
+    let app = app.arg(
+        Arg::with_name("async")
+            .long("async")
+            .conflicts_with_all(&[
+                "unix-socket",
+                "resp-addr",
+                "http-addr",
+                "metrics-addr",
+                "read-timeout",
+                "write-timeout",
+                "idle-timeout",
+                "max-connections",
+                "tls-cert",
+                "credentials",
+                "replica-of",
+            ])
+            .help("serve the connections on tasks instead of threads"),
+    );
+    let matches = app.get_matches();
 
     let addr = matches.value_of("addr").unwrap();
     let input_engine = matches.value_of("engine");
     let engine = &get_engine(input_engine);
+    let durability = match matches.value_of("durability") {
+        Some("none") => Some(Durability::None),
+        Some("write") => Some(Durability::EveryWrite),
+        Some("interval") => {
+            let ms = matches.value_of("sync-interval").unwrap().parse().unwrap();
+            Some(Durability::Interval(Duration::from_millis(ms)))
+        }
+        Some("group") => Some(Durability::GroupCommit),
+        _ => None,
+    };
+    let mut options = KvStoreOptions::default();
+    if let Some(durability) = durability {
+        options = options.durability(durability);
+    }
+    if matches.value_of("recovery") == Some("refuse") {
+        options = options.recovery(RecoveryMode::Refuse);
+    }
 
     env_logger::from_env(Env::default().default_filter_or("info")).init();
 
@@ -44,7 +253,7 @@ fn main() {
     info!("Storage engine: {}", engine);
     info!("Listening on {}", addr);
 
-    if let Err(e) = run_engine(engine, addr) {
+    if let Err(e) = run_engine(engine, &matches, options, durability) {
         eprintln!("{}", e);
         process::exit(1);
     }
@@ -87,19 +296,136 @@ fn get_engine(input_engine: Option<&str>) -> String {
     engine.to_owned()
 }
 
-fn run_engine(engine: &str, addr: &str) -> Result<()> {
+fn run_engine(
+    engine: &str,
+    matches: &ArgMatches,
+    options: KvStoreOptions,
+    durability: Option<Durability>,
+) -> Result<()> {
     let mut f = File::create("ENGINE")?;
     f.write_all(engine.as_bytes())?;
-    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
     match engine {
-        "kvs" => {
-            let mut server = KvsServer::new(KvStore::open(env::current_dir()?)?, pool);
-            server.run(addr)
+        "kvs" => serve(KvStore::open_with(env::current_dir()?, options)?, matches),
+        "sled" => serve(
+            SledKvsEngine::open_with(
+                env::current_dir()?,
+                durability.unwrap_or(Durability::EveryWrite),
+            )?,
+            matches,
+        ),
+        _ => panic!("invalid engine {}", engine),
+    }
+}
+
+// Runs the server with the listeners given on the command line, until SIGINT or SIGTERM.
+fn serve<E: KvsEngine>(engine: E, matches: &ArgMatches) -> Result<()> {
+    let addr = matches.value_of("addr").unwrap();
+    let secs = matches
+        .value_of("shutdown-timeout")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let shutdown_timeout = Duration::from_secs(secs);
+    #[cfg(feature = "async")]
+    {
+        if matches.is_present("async") {
+            return serve_async(engine, addr, shutdown_timeout);
+        }
+    }
+
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let mut server = KvsServer::new(engine, pool)
+        .shutdown_timeout(shutdown_timeout)
+        .backlog(number(matches, "backlog").unwrap());
+    if let Some(secs) = number(matches, "read-timeout") {
+        server = server.read_timeout(Duration::from_secs(secs.into()));
+    }
+    if let Some(secs) = number(matches, "write-timeout") {
+        server = server.write_timeout(Duration::from_secs(secs.into()));
+    }
+    if let Some(secs) = number(matches, "idle-timeout") {
+        server = server.idle_timeout(Duration::from_secs(secs.into()));
+    }
+    if let Some(max) = number(matches, "max-connections") {
+        server = server.max_connections(max as usize);
+    }
+    if let Some(cert) = matches.value_of("tls-cert") {
+        let mut tls = ServerTls::from_pem_files(cert, matches.value_of("tls-key").unwrap())?;
+        if let Some(ca) = matches.value_of("tls-client-ca") {
+            tls = tls.client_ca(ca)?;
         }
-        "sled" => {
-            let mut server = KvsServer::new(SledKvsEngine::open(env::current_dir()?)?, pool);
-            server.run(addr)
+        server = server.tls(&tls)?;
+    }
+    if let Some(path) = matches.value_of("credentials") {
+        server = server.credentials(Credentials::from_file(path)?);
+    }
+    if let Some(leader) = matches.value_of("replica-of") {
+        let mut options = ClientOptions::default();
+        if let Some(user) = matches.value_of("replica-user") {
+            let secret = matches.value_of("replica-secret").unwrap_or_else(|| {
+                eprintln!("--replica-user needs --replica-secret or KVS_REPLICA_SECRET");
+                process::exit(1);
+            });
+            options = options.login(user, secret);
         }
-        _ => panic!("invalid engine {}", engine),
+        server = server.replica_of(leader, options)?;
+    }
+    if let Some(path) = matches.value_of("unix-socket") {
+        server = server.unix_socket(path);
+    }
+    if let Some(addr) = matches.value_of("resp-addr") {
+        server = server.resp_addr(addr)?;
+    }
+    if let Some(addr) = matches.value_of("http-addr") {
+        server = server.http_addr(addr)?;
+    }
+    if let Some(addr) = matches.value_of("metrics-addr") {
+        server = server.metrics_addr(addr)?;
     }
+
+    let handle = server.shutdown_handle();
+    on_signal(move || handle.shutdown())?;
+
+    server.run(addr)
+}
+
+#[cfg(feature = "async")]
+fn serve_async<E: KvsEngine>(engine: E, addr: &str, shutdown_timeout: Duration) -> Result<()> {
+    // the pool only runs calls to the engine, so jobs are queued rather than blocking a task
+    let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    let server = AsyncKvsServer::new(engine, pool).shutdown_timeout(shutdown_timeout);
+    let (stop, stopped) = tokio::sync::oneshot::channel();
+    on_signal(move || {
+        let _ = stop.send(());
+    })?;
+    tokio::runtime::Runtime::new()?.block_on(server.run_until(addr, async {
+        let _ = stopped.await;
+    }))
+}
+
+// Calls `shutdown` on the first SIGINT or SIGTERM, or Ctrl-C on Windows.
+fn on_signal<F: FnOnce() + Send + 'static>(shutdown: F) -> Result<()> {
+    let mut shutdown = Some(shutdown);
+    ctrlc::set_handler(move || match shutdown.take() {
+        Some(shutdown) => shutdown(),
+        // a second signal does not wait
+        None => {
+            warn!("Exiting without a clean shutdown");
+            process::exit(1);
+        }
+    })
+    .map_err(|e| KvsError::Other(e.to_string()))
+}
+
+fn positive(s: String) -> std::result::Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("must be positive".to_owned()),
+        Ok(_) => Ok(()),
+        Err(_) => Err("not a number".to_owned()),
+    }
+}
+
+// The value of an argument checked by `positive`.
+fn number(matches: &ArgMatches, name: &str) -> Option<u32> {
+    matches.value_of(name).map(|s| s.parse().unwrap())
 }
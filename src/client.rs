@@ -1,60 +1,549 @@
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
+use std::collections::VecDeque;
 use std::io::prelude::*;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter};
+use std::ops::RangeBounds;
+use std::time::Duration;
 
-use crate::protocol::{Request, Response};
-use crate::Result;
+use crate::engines::{bytes_bound, into_string, into_string_pairs};
+use crate::net::{Stream, ToServerAddr};
+use crate::protocol::{
+    decode, read_frame, read_message, split_id, write_message, write_tagged, Auth, Hello,
+    HelloReply, Replicated, Request, Response, ScanRange, ServerInfo, CAP_ADMIN, CAP_AUTH,
+    CAP_BATCH, CAP_CAS, CAP_REPLICATION, CAP_SCAN, CAP_TTL, MAGIC, VERSION_REQUEST_IDS,
+};
+use crate::tls::ClientTls;
+use crate::{EngineStats, KvsError, Result, WriteBatch};
 
 /// K-V store client.
+///
+/// Keys and values are arbitrary bytes. The methods taking strings are a convenience on top
+/// of the byte ones; they fail with a UTF-8 error on a value which is not a valid string.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<TcpStream>>,
-    writer: TcpStream,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    // agreed on with the server in the handshake
+    version: u32,
+    capabilities: Vec<String>,
+    next_id: u64,
+    // IDs of the requests sent whose responses are not read yet, in order
+    in_flight: VecDeque<u64>,
 }
 
+/// How `KvsClient::connect_with` connects to a server.
+#[derive(Default)]
+pub struct ClientOptions {
+    tls: Option<ClientTls>,
+    login: Option<Auth>,
+}
+
+impl ClientOptions {
+    /// Connects over TLS.
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates as the principal `name` of the server in the handshake. A server
+    /// without credentials does not ask for them.
+    pub fn login(mut self, name: &str, secret: &str) -> Self {
+        self.login = Some(Auth {
+            name: name.to_owned(),
+            secret: secret.to_owned(),
+        });
+        self
+    }
+}
+
+// how many requests a pipeline sends before reading some responses, so that neither side
+// gets stuck writing to a full socket
+const PIPELINE_WINDOW: usize = 256;
+
 impl KvsClient {
-    /// Connect to the address of a server.
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let writer = TcpStream::connect(addr)?;
-        let reader = Deserializer::from_reader(writer.try_clone()?);
+    /// Connect to the address of a server, and agree on the protocol version and
+    /// capabilities to use with it. `addr` may be a TCP address, or `unix:/path` for a
+    /// Unix domain socket.
+    pub fn connect<A: ToServerAddr>(addr: A) -> Result<Self> {
+        KvsClient::open(addr, None, None)
+    }
+
+    /// Like `connect`, over TLS.
+    pub fn connect_tls<A: ToServerAddr>(addr: A, tls: &ClientTls) -> Result<Self> {
+        KvsClient::open(addr, Some(tls), None)
+    }
+
+    /// Like `connect`, with the given options.
+    pub fn connect_with<A: ToServerAddr>(addr: A, options: ClientOptions) -> Result<Self> {
+        KvsClient::reconnect(addr, &options)
+    }
+
+    // Like `connect_with`, keeping the options to connect again.
+    pub(crate) fn reconnect<A: ToServerAddr>(addr: A, options: &ClientOptions) -> Result<Self> {
+        KvsClient::open(addr, options.tls.as_ref(), options.login.as_ref())
+    }
+
+    fn open<A: ToServerAddr>(
+        addr: A,
+        tls: Option<&ClientTls>,
+        login: Option<&Auth>,
+    ) -> Result<Self> {
+        let addr = addr.to_server_addr()?;
+        let stream = Stream::connect(&addr)?;
+        let stream = match tls {
+            Some(tls) => tls.connect(stream, &addr)?,
+            None => stream,
+        };
+        KvsClient::handshake(stream, login)
+    }
+
+    fn handshake(stream: Stream, login: Option<&Auth>) -> Result<Self> {
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        let mut reader = BufReader::new(stream);
         //        debug!("Connected to {}", writer.peer_addr()?);
-        Ok(KvsClient { reader, writer })
+        let mut hello = Hello::new();
+        if login.is_some() {
+            hello.capabilities.push(CAP_AUTH.to_owned());
+        }
+        writer.write_all(MAGIC)?;
+        write_message(&mut writer, &hello)?;
+        writer.flush()?;
+        let (version, capabilities) = match read_message(&mut reader)? {
+            Some(HelloReply::Accept {
+                version,
+                capabilities,
+            }) => (version, capabilities),
+            Some(HelloReply::Reject(reason)) => return Err(KvsError::Protocol(reason)),
+            None => return Err(closed()),
+        };
+        // a server without credentials lets anyone in, and does not ask for them
+        if let Some(login) = login.filter(|_| capabilities.iter().any(|cap| cap == CAP_AUTH)) {
+            write_message(&mut writer, login)?;
+            writer.flush()?;
+            match read_message(&mut reader)? {
+                Some(Response::Ok(None)) => {}
+                Some(Response::Err(kind, message)) => {
+                    return Err(KvsError::from_server(kind, message))
+                }
+                Some(response) => return Err(unexpected(response)),
+                None => return Err(closed()),
+            }
+        }
+        Ok(KvsClient {
+            reader,
+            writer,
+            version,
+            capabilities,
+            next_id: 0,
+            in_flight: VecDeque::new(),
+        })
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
-            Response::Ok(None) => Ok(()),
-            Response::Ok(Some(v)) => Err(failure::err_msg(format!("Unexpected response: {:}", v))),
-            Response::Err(msg) => Err(failure::err_msg(msg)),
+    /// The protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// The capabilities both the client and the server support.
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Start a pipeline, which sends requests without waiting for their responses.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            results: Vec::new(),
         }
     }
 
-    /// Get the string value of a given string key from the server.
+    /// Set the value of a key in the server.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send(&Request::Set { key, value })?;
+        self.receive_ok()
+    }
+
+    /// Set the value of a key in the server, which expires after `ttl`.
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.require(CAP_TTL)?;
+        self.send(&Request::SetWithTtl { key, value, ttl })?;
+        self.receive_ok()
+    }
+
+    /// Get the value of a given key from the server.
     ///
     /// Returns `Ok(None)` if the key is not found.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.send(&Request::Get { key })?;
+        match self.receive()? {
             Response::Ok(value) => Ok(value),
-            Response::Err(msg) => Err(failure::err_msg(msg)),
+            response => Err(unexpected(response)),
         }
     }
 
     /// Remove a given key in the server.
     ///
     /// Returns error if the key is not found.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.send(&Request::Rm { key })?;
+        self.receive_ok()
+    }
+
+    /// Set the value of a key in the server to `new` if its current value is `expected`.
+    /// `None` stands for a missing key.
+    ///
+    /// Returns whether the value was swapped.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.require(CAP_CAS)?;
+        self.send(&Request::Cas { key, expected, new })?;
+        match self.receive()? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Apply all writes of a batch in the server, or none of them if it fails.
+    pub fn batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.require(CAP_BATCH)?;
+        self.send(&Request::Batch(batch))?;
+        self.receive_ok()
+    }
+
+    /// Get the keys in `range` with their values from the server, in key order.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = ScanRange::Range(range.start_bound().cloned(), range.end_bound().cloned());
+        self.scan_range(range)
+    }
+
+    /// Get the keys starting with `prefix` with their values from the server, in key order.
+    pub fn scan_prefix_bytes(&mut self, prefix: Vec<u8>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_range(ScanRange::Prefix(prefix))
+    }
+
+    /// Set the value of a string key in the server to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a string key in the server to a string, which expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Get the string value of a given string key from the server.
+    ///
+    /// Returns `Ok(None)` if the key is not found.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(into_string)
+            .transpose()
+    }
+
+    /// Remove a given string key in the server.
+    ///
+    /// Returns error if the key is not found.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Rm { key })?;
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Like `compare_and_swap_bytes`, with string values.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Get the string keys in `range` with their string values from the server, in key order.
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R) -> Result<Vec<(String, String)>> {
+        let range = ScanRange::Range(
+            bytes_bound(range.start_bound().cloned()),
+            bytes_bound(range.end_bound().cloned()),
+        );
+        into_string_pairs(self.scan_range(range)?)
+    }
+
+    /// Get the string keys starting with `prefix` with their string values from the server,
+    /// in key order.
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes())?)
+    }
+
+    /// Get figures about the storage of the engine of the server.
+    ///
+    /// This and the other admin requests need a principal with `admin` set if the server
+    /// has credentials.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        self.require(CAP_ADMIN)?;
+        self.send(&Request::Stats)?;
+        match self.receive()? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Make the server compact its engine now, returning once it is done.
+    pub fn compact(&mut self) -> Result<()> {
+        self.require(CAP_ADMIN)?;
+        self.send(&Request::Compact)?;
+        self.receive_ok()
+    }
+
+    /// Make every write so far durable in the server.
+    pub fn flush(&mut self) -> Result<()> {
+        self.require(CAP_ADMIN)?;
+        self.send(&Request::Flush)?;
+        self.receive_ok()
+    }
+
+    /// Get the version, engine and uptime of the server.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        self.require(CAP_ADMIN)?;
+        self.send(&Request::Info)?;
+        match self.receive()? {
+            Response::Info(info) => Ok(info),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Make the server, a follower, take writes from clients and stop following its
+    /// leader. A leader is left as it is.
+    pub fn promote(&mut self) -> Result<()> {
+        self.require(CAP_REPLICATION)?;
+        self.send(&Request::Promote)?;
+        self.receive_ok()
+    }
+
+    // Asks the server to replicate to this client. The connection then only carries the
+    // messages of the server, read with `next_replicated`.
+    pub(crate) fn replicate(&mut self) -> Result<()> {
+        self.require(CAP_REPLICATION)?;
+        self.send(&Request::Replicate)?;
+        self.receive_ok()
+    }
+
+    // Reads the next message of a server replicating to this client, or `None` once it
+    // closes the connection.
+    pub(crate) fn next_replicated(&mut self) -> Result<Option<Replicated>> {
+        let frame = match read_frame(&mut self.reader)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        // every message carries the ID of the `Replicate`
+        let payload = if self.version >= VERSION_REQUEST_IDS {
+            split_id(&frame)?.1
+        } else {
+            &frame[..]
+        };
+        decode(payload).map(Some)
+    }
+
+    // A handle on the connection, to shut it down from another thread.
+    pub(crate) fn try_clone_stream(&self) -> Result<Stream> {
+        Ok(self.reader.get_ref().try_clone()?)
+    }
+
+    fn scan_range(&mut self, range: ScanRange) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.require(CAP_SCAN)?;
+        self.send(&Request::Scan(range))?;
+        let mut entries = Vec::new();
+        loop {
+            match self.receive()? {
+                Response::Entry(key, value) => entries.push((key, value)),
+                Response::Ok(None) => return Ok(entries),
+                response => return Err(unexpected(response)),
+            }
+        }
+    }
+
+    // Fails unless the server agreed to use a capability.
+    fn require(&self, capability: &str) -> Result<()> {
+        if self.capabilities.iter().any(|cap| cap == capability) {
+            Ok(())
+        } else {
+            Err(KvsError::Protocol(format!(
+                "The server does not support {}",
+                capability
+            )))
+        }
+    }
+
+    // Writes a request with a new ID, without flushing it.
+    fn write_request(&mut self, request: &Request) -> Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.version >= VERSION_REQUEST_IDS {
+            write_tagged(&mut self.writer, id, request)?;
+        } else {
+            write_message(&mut self.writer, request)?;
+        }
+        self.in_flight.push_back(id);
+        Ok(())
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        self.write_request(request)?;
         self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
+        Ok(())
+    }
+
+    // Reads the next response, which belongs to the oldest request in flight.
+    fn receive_response(&mut self) -> Result<Response> {
+        let expected = match self.in_flight.front() {
+            Some(&id) => id,
+            None => return Err(KvsError::Protocol("No request in flight".to_owned())),
+        };
+        let frame = read_frame(&mut self.reader)?.ok_or_else(closed)?;
+        let response = if self.version >= VERSION_REQUEST_IDS {
+            let (id, payload) = split_id(&frame)?;
+            if id != expected {
+                return Err(KvsError::Protocol(format!(
+                    "Response to request {} while expecting request {}",
+                    id, expected
+                )));
+            }
+            decode(payload)?
+        } else {
+            decode(&frame)?
+        };
+        // the entries of a scan are followed by one more response
+        if !matches!(response, Response::Entry(..)) {
+            self.in_flight.pop_front();
+        }
+        Ok(response)
+    }
+
+    // Reads the next response, turning an error sent by the server into an `Err`.
+    fn receive(&mut self) -> Result<Response> {
+        match self.receive_response()? {
+            Response::Err(kind, msg) => Err(KvsError::from_server(kind, msg)),
+            response => Ok(response),
+        }
+    }
+
+    fn receive_ok(&mut self) -> Result<()> {
+        match self.receive()? {
             Response::Ok(None) => Ok(()),
-            Response::Ok(Some(v)) => Err(failure::err_msg(format!("Unexpected response: {:}", v))),
-            Response::Err(msg) => Err(failure::err_msg(msg)),
+            response => Err(unexpected(response)),
         }
     }
 }
+
+/// Requests sent to the server one after another without waiting for the responses,
+/// which are read by `finish`.
+///
+/// This saves a round trip per request when loading many keys. At most a few hundred
+/// requests are in flight at a time; the responses to older ones are read as new ones
+/// are sent.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    // results of the requests whose responses were already read
+    results: Vec<Result<Option<Vec<u8>>>>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Queue setting the value of a key.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.queue(&Request::Set { key, value })
+    }
+
+    /// Queue getting the value of a key.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.queue(&Request::Get { key })
+    }
+
+    /// Queue removing a key.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.queue(&Request::Rm { key })
+    }
+
+    /// Queue setting the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Queue getting the value of a string key.
+    pub fn get(&mut self, key: String) -> Result<()> {
+        self.get_bytes(key.into_bytes())
+    }
+
+    /// Queue removing a string key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Send the requests still queued and read all the responses.
+    ///
+    /// Returns the result of each request in the order they were queued: the value of
+    /// a key for a get, `None` for a set or a remove, or the error of the request, such
+    /// as `KvsError::KeyNotFound` for removing a missing key. An error of the connection
+    /// itself is returned as a whole.
+    pub fn finish(mut self) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        self.client.writer.flush()?;
+        while !self.client.in_flight.is_empty() {
+            self.read_result()?;
+        }
+        Ok(std::mem::take(&mut self.results))
+    }
+
+    fn queue(&mut self, request: &Request) -> Result<()> {
+        if self.client.in_flight.len() >= PIPELINE_WINDOW {
+            self.client.writer.flush()?;
+            self.read_result()?;
+        }
+        self.client.write_request(request)
+    }
+
+    fn read_result(&mut self) -> Result<()> {
+        let result = match self.client.receive_response()? {
+            Response::Ok(value) => Ok(value),
+            Response::Err(kind, msg) => Err(KvsError::from_server(kind, msg)),
+            response => return Err(unexpected(response)),
+        };
+        self.results.push(result);
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Pipeline<'a> {
+    // a pipeline given up on still has to read its responses, so that they are not taken
+    // for the responses to the next requests of the client
+    fn drop(&mut self) {
+        if self.client.in_flight.is_empty() {
+            return;
+        }
+        let drained = self.client.writer.flush().is_ok()
+            && (0..self.client.in_flight.len()).all(|_| self.client.receive_response().is_ok());
+        if !drained {
+            error!("fail to read the responses of an unfinished pipeline");
+        }
+    }
+}
+
+pub(crate) fn closed() -> KvsError {
+    KvsError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed by the server",
+    ))
+}
+
+pub(crate) fn unexpected(response: Response) -> KvsError {
+    KvsError::Protocol(format!("Unexpected response: {:?}", response))
+}
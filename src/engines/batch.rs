@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes which `KvsEngine::apply_batch` applies all together or not at all.
+///
+/// Writes are applied in the order they are added, so a later write to a key wins.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Rm { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Removes a given key.
+    ///
+    /// Unlike `KvsEngine::remove`, removing a key which is not found is not an error.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Rm { key });
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Removes a given string key, like `remove_bytes`.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // The keys written, in order.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Rm { key } => &key[..],
+        })
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::Result;
+
+/// When acknowledged writes are synced to the disk.
+///
+/// `KvStore` defaults to `None`, and `SledKvsEngine` to `EveryWrite`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Do not sync explicitly. `KvStore` hands every write to the OS, so writes survive
+    /// a crash of the process but not a power failure. Sled keeps them in its own buffers,
+    /// which it flushes in the background.
+    None,
+    /// Sync every write before acknowledging it.
+    EveryWrite,
+    /// Sync in the background at a fixed interval, so at most that much of
+    /// acknowledged writes can be lost.
+    Interval(Duration),
+    /// Sync every write before acknowledging it, but let concurrent writers waiting
+    /// for a sync share a single one.
+    GroupCommit,
+}
+
+/// Lets concurrent writers waiting for their writes to become durable share one sync.
+///
+/// Writes are numbered by a sequence number increasing in write order. A sync started
+/// after a write makes it durable, so a writer arriving while a sync is running waits
+/// for the next one, which is run by one of the waiting writers on behalf of all of them.
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+struct GroupState {
+    // all writes up to this sequence number are durable
+    synced_seq: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        GroupCommit {
+            state: Mutex::new(GroupState {
+                synced_seq: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Waits until the write numbered `seq` is durable.
+    ///
+    /// If no sync is running, the current thread runs `sync`, which must make every
+    /// write done so far durable and return the largest sequence number it covers.
+    pub fn wait<F>(&self, seq: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<u64>,
+    {
+        let mut sync = Some(sync);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced_seq >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            let sync = match sync.take() {
+                Some(sync) => sync,
+                // a sync run by this thread always covers its own write
+                None => return Ok(()),
+            };
+
+            state.syncing = true;
+            drop(state);
+            let res = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if let Ok(synced_seq) = res {
+                state.synced_seq = state.synced_seq.max(synced_seq);
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+}
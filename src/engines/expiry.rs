@@ -0,0 +1,66 @@
+use crossbeam::crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+
+/// How often expired keys are reclaimed in the background.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Current time in milliseconds since the Unix epoch, which expiry times are measured in.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Expiry time of a key set now with the given time to live.
+pub fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Whether a key with the given expiry time has expired at `now`.
+pub fn is_expired(expire_at: Option<u64>, now: u64) -> bool {
+    matches!(expire_at, Some(t) if t <= now)
+}
+
+/// Background thread reclaiming expired keys every `SWEEP_INTERVAL`.
+pub(crate) struct Sweeper {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn spawn<F>(sweep: F) -> Result<Self>
+    where
+        F: Fn() -> Result<()> + Send + 'static,
+    {
+        // nothing is ever sent: dropping the sender stops the thread
+        let (sender, receiver) = bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(SWEEP_INTERVAL) {
+                    if let Err(e) = sweep() {
+                        error!("fail to reclaim expired keys: {}", e);
+                    }
+                }
+            })?;
+        Ok(Sweeper {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background sweeper thread panicked");
+            }
+        }
+    }
+}
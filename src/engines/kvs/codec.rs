@@ -0,0 +1,63 @@
+//! Little-endian integers and length-prefixed byte strings, which the log records and the
+//! hint files are made of.
+
+use crate::{KvsError, Result};
+
+/// Appends `bytes` after their `u32` length.
+pub fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Reads a `u32` from exactly 4 bytes.
+pub fn u32_from(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(buf);
+    u32::from_le_bytes(bytes)
+}
+
+/// Reads the fields of a buffer in order. A buffer which ends in the middle of a field is
+/// damaged, which is reported with the error `truncated` makes.
+pub struct Fields<'a> {
+    buf: &'a [u8],
+    truncated: fn() -> KvsError,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(buf: &'a [u8], truncated: fn() -> KvsError) -> Self {
+        Fields { buf, truncated }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err((self.truncated)());
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32_from(self.take(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads bytes written by `put_bytes`.
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
@@ -0,0 +1,166 @@
+//! Hint files, which let a generation be loaded into the index without reading its values.
+//!
+//! `<gen>.hint` lists the commands of `<gen>.log` in log order, each with the position of
+//! its record but without the value. The header records how many bytes of the log the hint
+//! covers, so that only the log written after it needs to be replayed. The file is
+//!
+//! ```text
+//! | magic `KVSH` | version: u32 | covered log length: u64 | entries | CRC32 of all before: u32 |
+//! ```
+//!
+//! with all integers in little-endian. An entry is a tag byte, the key as its `u32` byte
+//! length followed by its bytes, and for a `Set` the `u64` position and length of the
+//! record, followed by the `u64` expiry time if the key has one.
+//!
+//! Hints are written to a temporary file which is then renamed, so a hint file is either
+//! complete or missing.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::codec::{put_bytes, u32_from, Fields};
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const FORMAT_VERSION: u32 = 1;
+
+const TAG_SET: u8 = 0;
+const TAG_RM: u8 = 1;
+const TAG_SET_EXPIRING: u8 = 2;
+
+/// A command of the log, without the value.
+#[derive(Debug, Clone)]
+pub enum HintEntry {
+    /// A `Set` whose record is at `pos` and `len` bytes long.
+    Set {
+        key: Vec<u8>,
+        pos: u64,
+        len: u64,
+        expire_at: Option<u64>,
+    },
+    /// A `Rm`.
+    Rm { key: Vec<u8> },
+}
+
+/// Content of a hint file.
+pub struct Hint {
+    /// How many bytes of the log the entries cover.
+    pub log_len: u64,
+    pub entries: Vec<HintEntry>,
+}
+
+/// Path of the hint file of a generation.
+pub fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Writes the hint file of a generation, replacing the existing one.
+pub fn write_hint(dir: &Path, gen: u64, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&log_len.to_le_bytes());
+    for entry in entries {
+        match entry {
+            HintEntry::Set {
+                key,
+                pos,
+                len,
+                expire_at,
+            } => {
+                buf.push(if expire_at.is_some() {
+                    TAG_SET_EXPIRING
+                } else {
+                    TAG_SET
+                });
+                put_bytes(&mut buf, key);
+                buf.extend_from_slice(&pos.to_le_bytes());
+                buf.extend_from_slice(&len.to_le_bytes());
+                if let Some(expire_at) = expire_at {
+                    buf.extend_from_slice(&expire_at.to_le_bytes());
+                }
+            }
+            HintEntry::Rm { key } => {
+                buf.push(TAG_RM);
+                put_bytes(&mut buf, key);
+            }
+        }
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let path = hint_path(dir, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the hint file of a generation.
+///
+/// Returns `Ok(None)` if there is none.
+pub fn read_hint(dir: &Path, gen: u64) -> Result<Option<Hint>> {
+    let mut buf = Vec::new();
+    match File::open(hint_path(dir, gen)) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if buf.len() < 20 || &buf[..4] != MAGIC {
+        return Err(KvsError::Corruption("Not a kvs hint file".to_owned()));
+    }
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(content) != u32_from(crc) {
+        return Err(KvsError::Corruption(
+            "Corrupted hint file: checksum mismatch".to_owned(),
+        ));
+    }
+    let mut fields = Fields::new(&content[4..], truncated);
+    if fields.u32()? != FORMAT_VERSION {
+        return Err(KvsError::Corruption(
+            "Unsupported hint format version".to_owned(),
+        ));
+    }
+    let log_len = fields.u64()?;
+
+    let mut entries = Vec::new();
+    while !fields.is_empty() {
+        let tag = fields.u8()?;
+        let key = fields.bytes()?;
+        entries.push(match tag {
+            TAG_SET | TAG_SET_EXPIRING => HintEntry::Set {
+                key,
+                pos: fields.u64()?,
+                len: fields.u64()?,
+                expire_at: if tag == TAG_SET_EXPIRING {
+                    Some(fields.u64()?)
+                } else {
+                    None
+                },
+            },
+            TAG_RM => HintEntry::Rm { key },
+            _ => {
+                return Err(KvsError::Corruption(
+                    "Corrupted hint file: unknown entry tag".to_owned(),
+                ))
+            }
+        });
+    }
+    Ok(Some(Hint { log_len, entries }))
+}
+
+/// Removes the hint file of a generation if there is one.
+pub fn remove_hint(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+fn truncated() -> KvsError {
+    KvsError::Corruption("Corrupted hint file: truncated entry".to_owned())
+}
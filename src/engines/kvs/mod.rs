@@ -0,0 +1,1407 @@
+use crossbeam::crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
+use std::mem;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::vec;
+
+use super::batch::BatchOp;
+use super::durability::GroupCommit;
+use super::expiry::{self, Sweeper};
+use super::{prefix_end, Durability, EngineStats, KeyIter, KvsEngine, ScanIter, WriteBatch};
+use crate::{KvsError, Result};
+
+mod codec;
+mod hint;
+mod record;
+
+use self::hint::HintEntry;
+
+const COMPACTION_THRESHOLD: u64 = 1024;
+// the active log segment is sealed and a new generation is started once it grows beyond this size
+const SEGMENT_SIZE_LIMIT: u64 = 1024 * 1024;
+// how many keys a scan reads at a time
+const SCAN_CHUNK_LEN: usize = 256;
+// the single log file used before the log was split into generations
+const LEGACY_LOG_NAME: &str = "log.json";
+
+/// The key-value database. Log-structured file I/O is used internally for persistant storage.
+/// Commands are stored as length-prefixed binary records protected by a CRC, so that
+/// damaged data is detected instead of being returned.
+///
+/// The log is split into numbered generation files (`<gen>.log`). Only the one with the
+/// largest number is appended to; it is sealed once it reaches `SEGMENT_SIZE_LIMIT` bytes
+/// and never modified afterwards, so compaction only needs to rewrite sealed segments.
+///
+/// Compaction runs on a background thread, so reads and writes are not blocked by it.
+///
+/// Sealed, compacted and cleanly closed generations get a hint file (`<gen>.hint`) with
+/// the positions of their records, so that opening the store only needs to read the hints
+/// and the part of the log written after them.
+///
+/// When writes are synced to the disk is set by the `Durability` option.
+///
+/// The commands of a write batch are framed by a marker in the log, so that opening the
+/// store after a crash either applies a whole batch or drops it.
+///
+/// A key set with a TTL has its expiry time in its record. It is invisible once expired,
+/// removed from the index by a background sweeper and dropped from the log by compaction.
+#[derive(Clone)]
+pub struct KvStore {
+    inner: Arc<KvStoreInner>,
+    // handle of the background compaction thread, which is stopped when the last clone is dropped
+    compactor: Arc<Compactor>,
+    // handle of the background sync thread with `Durability::Interval`, only kept to stop it
+    _syncer: Option<Arc<Syncer>>,
+    // handle of the background thread reclaiming expired keys, only kept to stop it
+    _sweeper: Arc<Sweeper>,
+}
+
+// State shared by all clones of a `KvStore` and its background compaction thread
+struct KvStoreInner {
+    // index map, ordered by key for scans
+    imap: RwLock<BTreeMap<Vec<u8>, LogIndex>>,
+    log_dir: PathBuf,
+    // writer of the active generation
+    writer: Mutex<LogWriter>,
+    // number of redundant logs
+    dead: Mutex<u64>,
+    // Readers hold it while looking up the index and opening the log file, and compaction
+    // holds it exclusively while removing stale generations. A reader thus always opens a
+    // file that is still there, and an opened file stays readable after it is unlinked.
+    gens_lock: RwLock<()>,
+    // only one compaction runs at a time
+    compaction_lock: Mutex<()>,
+    durability: Durability,
+    group_commit: GroupCommit,
+    // whether any key has an expiry time, so that the sweeper has something to do
+    expiring: AtomicBool,
+    // number of compactions finished, for `stats`
+    compactions: AtomicU64,
+}
+
+/// How `KvStore::open` handles a log that ends in a damaged record,
+/// which is what a crash in the middle of a write leaves behind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryMode {
+    /// Cut the log back to the last good record. This is the default.
+    TruncateTail,
+    /// Refuse to open the store, leaving the files untouched for inspection.
+    Refuse,
+}
+
+/// Options for opening a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    recovery: RecoveryMode,
+    durability: Durability,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            recovery: RecoveryMode::TruncateTail,
+            durability: Durability::None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets how a damaged log tail is handled when opening the store.
+    pub fn recovery(mut self, recovery: RecoveryMode) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Sets when writes are synced to the disk.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        {
+            let mut writer = self.inner.writer.lock().unwrap();
+            self.inner.log_set(&mut writer, key, value, None)?;
+            self.inner.roll_if_full(&mut writer)?;
+            self.inner.commit(writer)?;
+        }
+
+        // kill zombies
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        {
+            let mut writer = self.inner.writer.lock().unwrap();
+            let expire_at = expiry::expire_at(ttl);
+            self.inner
+                .log_set(&mut writer, key, value, Some(expire_at))?;
+            self.inner.roll_if_full(&mut writer)?;
+            self.inner.commit(writer)?;
+        }
+
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_bytes_with_expiry(key)?.map(|(value, _)| value))
+    }
+
+    fn get_bytes_with_expiry(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let (file, index) = {
+            let _gens = self.inner.gens_lock.read().unwrap();
+            match self.inner.imap.read().unwrap().get(&key) {
+                Some(index) if !expiry::is_expired(index.expire_at, expiry::now_millis()) => (
+                    File::open(log_path(&self.inner.log_dir, index.gen))?,
+                    index.clone(),
+                ),
+                // an expired key is as good as removed
+                _ => return Ok(None),
+            }
+        };
+        let value = read_value(&mut LogReader::new(file), &key, &index)?;
+        Ok(Some((value, index.expire_at)))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.inner.log_rm(&mut writer, key)?;
+        self.inner.roll_if_full(&mut writer)?;
+        self.inner.commit(writer)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.swap(key, expected, new, None)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        self.swap(key, expected, Some(new), Some(expiry::expire_at(ttl)))
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut writer = self.inner.writer.lock().unwrap();
+            let cmds: Vec<Command> = batch
+                .into_ops()
+                .into_iter()
+                .map(|op| match op {
+                    BatchOp::Set { key, value } => Command::Set {
+                        key,
+                        value,
+                        expire_at: None,
+                    },
+                    BatchOp::Rm { key } => Command::Rm { key },
+                })
+                .collect();
+            let indexes = writer.append_batch(&cmds)?;
+
+            // the batch marker is redundant once the batch is in the log
+            let mut dead = 1;
+            let mut imap = self.inner.imap.write().unwrap();
+            for (cmd, index) in cmds.into_iter().zip(indexes) {
+                match cmd {
+                    Command::Set { key, .. } => {
+                        if imap.insert(key, index).is_some() {
+                            dead += 1;
+                        }
+                    }
+                    Command::Rm { key } => {
+                        dead += if imap.remove(&key).is_some() { 2 } else { 1 };
+                    }
+                    Command::Batch { .. } => unreachable!(),
+                }
+            }
+            drop(imap);
+            *self.inner.dead.lock().unwrap() += dead;
+            self.inner.roll_if_full(&mut writer)?;
+            self.inner.commit(writer)?;
+        }
+
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        Ok(Box::new(Scan::<(Vec<u8>, Vec<u8>)>::new(
+            self.inner.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        let end = prefix_end(&prefix);
+        Ok(Box::new(Scan::<(Vec<u8>, Vec<u8>)>::new(
+            self.inner.clone(),
+            Included(prefix),
+            end,
+        )))
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        Ok(Box::new(Scan::<Vec<u8>>::new(
+            self.inner.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )))
+    }
+
+    // sealing leaves a hint covering the active generation so far, which the next open
+    // reads before replaying whatever is written after it
+    fn flush(&self) -> Result<()> {
+        self.inner.writer.lock().unwrap().seal(&self.inner.log_dir)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut disk_bytes = 0;
+        for entry in fs::read_dir(&self.inner.log_dir)? {
+            // a compaction may remove the file in the meantime
+            if let Ok(metadata) = entry?.metadata() {
+                if metadata.is_file() {
+                    disk_bytes += metadata.len();
+                }
+            }
+        }
+        Ok(EngineStats {
+            keys: Some(self.inner.imap.read().unwrap().len() as u64),
+            disk_bytes,
+            dead_entries: Some(*self.inner.dead.lock().unwrap()),
+            compactions: Some(self.inner.compactions.load(Ordering::Relaxed)),
+        })
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+}
+
+impl KvStore {
+    /// Restores an instance of the database located in some direcotry,
+    /// or create a new one if no logs exist in this directory
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Like `open`, but with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let path = path.into();
+        debug!("open KvStore {:?}", path);
+        std::fs::create_dir_all(&path)?;
+        migrate_legacy_log(&path)?;
+        for &gen in &sorted_gens(&path)? {
+            upgrade_json_log(&path, gen, options.recovery)?;
+        }
+        //let reader = LogReader::new(File::open(path.join("log.json")).unwrap());
+
+        // read the hints and logs to restore the database in the memory
+        let mut imap: BTreeMap<Vec<u8>, LogIndex> = BTreeMap::new();
+        let gens = sorted_gens(&path)?;
+        let mut dead = 0;
+        let mut active_entries = Vec::new();
+        for (i, &gen) in gens.iter().enumerate() {
+            let is_active = i + 1 == gens.len();
+            let (gen_dead, entries) = load_gen(&path, gen, &mut imap, options.recovery, is_active)?;
+            dead += gen_dead;
+            active_entries = entries;
+        }
+
+        // keep appending to the newest generation
+        let active_gen = gens.last().cloned().unwrap_or(1);
+        let mut writer = LogWriter::open(&path, active_gen)?;
+        writer.hints = active_entries;
+        let writer = Mutex::new(writer);
+
+        let expiring = imap.values().any(|index| index.expire_at.is_some());
+        let inner = Arc::new(KvStoreInner {
+            imap: RwLock::new(imap),
+            log_dir: path,
+            writer,
+            dead: Mutex::new(dead),
+            gens_lock: RwLock::new(()),
+            compaction_lock: Mutex::new(()),
+            durability: options.durability,
+            group_commit: GroupCommit::new(),
+            expiring: AtomicBool::new(expiring),
+            compactions: AtomicU64::new(0),
+        });
+        let compactor = Arc::new(Compactor::spawn(inner.clone())?);
+        let syncer = match options.durability {
+            Durability::Interval(interval) => {
+                Some(Arc::new(Syncer::spawn(inner.clone(), interval)?))
+            }
+            _ => None,
+        };
+        let sweeper = {
+            let inner = inner.clone();
+            let compactor = compactor.clone();
+            Sweeper::spawn(move || {
+                inner.sweep_expired();
+                if inner.compaction_due() {
+                    compactor.notify();
+                }
+                Ok(())
+            })?
+        };
+        Ok(KvStore {
+            inner,
+            compactor,
+            _syncer: syncer,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// Compacting the log.
+    ///
+    /// The active generation is sealed first, then the live entries of all sealed
+    /// generations are copied into a new generation and the stale files are removed.
+    /// Writes keep going to a fresh active generation in the meantime.
+    ///
+    /// Compaction is triggered automatically in the background; calling this waits for a
+    /// compaction to finish in the current thread.
+    pub fn compact(&self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    // wake up the background compaction once there are enough redundant entries
+    fn compact_if_needed(&self) {
+        if self.inner.compaction_due() {
+            self.compactor.notify();
+        }
+    }
+
+    // `expire_at` only applies when a new value is set
+    fn swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expire_at: Option<u64>,
+    ) -> Result<bool> {
+        {
+            // every write holds the writer, so the value cannot change until the swap is logged
+            let mut writer = self.inner.writer.lock().unwrap();
+            if self.get_bytes(key.clone())? != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => self.inner.log_set(&mut writer, key, value, expire_at)?,
+                None if expected.is_some() => self.inner.log_rm(&mut writer, key)?,
+                // the key is already missing
+                None => return Ok(true),
+            }
+            self.inner.roll_if_full(&mut writer)?;
+            self.inner.commit(writer)?;
+        }
+
+        self.compact_if_needed();
+        Ok(true)
+    }
+}
+
+impl KvStoreInner {
+    fn compact(&self) -> Result<()> {
+        let _compaction = self.compaction_lock.lock().unwrap();
+
+        // seal the active generation. The compacted entries go to `compaction_gen`,
+        // which sorts after every sealed generation and before the new active one,
+        // so replaying the generations in order still yields the latest values.
+        let compaction_gen = {
+            let mut writer = self.writer.lock().unwrap();
+            let compaction_gen = writer.gen + 1;
+            let next = LogWriter::open(&self.log_dir, writer.gen + 2)?.continue_from(&writer);
+            let mut sealed = mem::replace(&mut *writer, next);
+            sealed.seal(&self.log_dir)?;
+            // the entries made redundant so far are all in the generations compacted
+            *self.dead.lock().unwrap() = 0;
+            compaction_gen
+        };
+        debug!("compact log generations below {}", compaction_gen);
+
+        let mut compacted_writer = LogWriter::open(&self.log_dir, compaction_gen)?;
+        let mut readers: HashMap<u64, LogReader> = HashMap::new();
+        let now = expiry::now_millis();
+        // The index is walked `SCAN_CHUNK_LEN` keys at a time, so that only the entries of
+        // one chunk are copied out of it and writers are not held up for the whole walk.
+        let mut start = Unbounded;
+        loop {
+            let (chunk, last) = {
+                let imap = self.imap.read().unwrap();
+                let mut last = None;
+                let mut chunk = Vec::new();
+                for (key, index) in imap.range((start.clone(), Unbounded)).take(SCAN_CHUNK_LEN) {
+                    last = Some(key.clone());
+                    if index.gen < compaction_gen {
+                        chunk.push((key.clone(), index.clone()));
+                    }
+                }
+                (chunk, last)
+            };
+            let last = match last {
+                Some(last) => last,
+                None => break,
+            };
+
+            let mut moved = Vec::new();
+            let mut expired = Vec::new();
+            for (key, index) in chunk {
+                if expiry::is_expired(index.expire_at, now) {
+                    expired.push((key, index));
+                    continue;
+                }
+                let reader = match readers.entry(index.gen) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(LogReader::new(File::open(log_path(
+                        &self.log_dir,
+                        index.gen,
+                    ))?)),
+                };
+                reader.seek(SeekFrom::Start(index.pos))?;
+                let new_pos = compacted_writer.pos;
+                io::copy(&mut reader.take(index.len), &mut compacted_writer)?;
+                compacted_writer.hints.push(HintEntry::Set {
+                    key: key.clone(),
+                    pos: new_pos,
+                    len: index.len,
+                    expire_at: index.expire_at,
+                });
+                let new_index = LogIndex::new(compaction_gen, new_pos, index.len, index.expire_at);
+                moved.push((key, index, new_index));
+            }
+            // switch readers over to the compacted generation once the chunk is written
+            compacted_writer.flush()?;
+
+            let mut imap = self.imap.write().unwrap();
+            for (key, index, new_index) in moved {
+                // the key may have been overwritten or removed while compacting
+                if let Some(current) = imap.get_mut(&key) {
+                    if *current == index {
+                        *current = new_index;
+                    }
+                }
+            }
+            // expired entries are not copied, so they go away with their generation
+            for (key, index) in expired {
+                if imap.get(&key) == Some(&index) {
+                    imap.remove(&key);
+                }
+            }
+            drop(imap);
+            start = Excluded(last);
+        }
+        compacted_writer.seal(&self.log_dir)?;
+
+        // close file handlers and remove the stale generations
+        drop(readers);
+        let _gens = self.gens_lock.write().unwrap();
+        for gen in sorted_gens(&self.log_dir)? {
+            if gen < compaction_gen {
+                fs::remove_file(log_path(&self.log_dir, gen))?;
+                hint::remove_hint(&self.log_dir, gen)?;
+            }
+        }
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Appends a `Set` to the log and indexes it. The caller holds the writer, so that
+    // concurrent writes to the same key are indexed in log order.
+    fn log_set(
+        &self,
+        writer: &mut LogWriter,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    ) -> Result<()> {
+        let cmd = Command::Set {
+            key: key.clone(),
+            value,
+            expire_at,
+        };
+        if expire_at.is_some() {
+            self.expiring.store(true, Ordering::SeqCst);
+        }
+        let index = writer.append(&cmd)?;
+
+        if self.imap.write().unwrap().insert(key, index).is_some() {
+            *self.dead.lock().unwrap() += 1;
+        }
+        Ok(())
+    }
+
+    // Appends a `Rm` to the log and removes the key from the index, or fails if the key
+    // is not found. The caller holds the writer.
+    fn log_rm(&self, writer: &mut LogWriter, key: Vec<u8>) -> Result<()> {
+        match self.imap.write().unwrap().remove(&key) {
+            Some(index) if !expiry::is_expired(index.expire_at, expiry::now_millis()) => {}
+            Some(_) => {
+                *self.dead.lock().unwrap() += 1;
+                return Err(KvsError::KeyNotFound);
+            }
+            None => return Err(KvsError::KeyNotFound),
+        }
+        // both the removed `Set` and the `Rm` itself are redundant now
+        *self.dead.lock().unwrap() += 2;
+
+        let cmd = Command::Rm { key };
+        writer.append(&cmd)?;
+        Ok(())
+    }
+
+    // Removes expired keys from the index, leaving their records to compaction.
+    fn sweep_expired(&self) {
+        if !self.expiring.load(Ordering::SeqCst) {
+            return;
+        }
+        let now = expiry::now_millis();
+        let expired: Vec<Vec<u8>> = self
+            .imap
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, index)| expiry::is_expired(index.expire_at, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+
+        let mut removed = 0;
+        {
+            let mut imap = self.imap.write().unwrap();
+            for key in expired {
+                // the key may have been set again in the meantime
+                if matches!(imap.get(&key), Some(index) if expiry::is_expired(index.expire_at, now))
+                {
+                    imap.remove(&key);
+                    removed += 1;
+                }
+            }
+        }
+        debug!("reclaim {} expired keys", removed);
+        *self.dead.lock().unwrap() += removed;
+    }
+
+    // Whether there are enough redundant entries to compact the log, in which case the
+    // count starts over.
+    fn compaction_due(&self) -> bool {
+        let mut dead = self.dead.lock().unwrap();
+        if *dead >= COMPACTION_THRESHOLD {
+            *dead = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Reads the values of the keys `select` picks from the index, keeping their order.
+    fn read_values<F>(&self, select: F) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        F: FnOnce(&BTreeMap<Vec<u8>, LogIndex>) -> Vec<(Vec<u8>, LogIndex)>,
+    {
+        let (indexes, mut readers) = {
+            let _gens = self.gens_lock.read().unwrap();
+            let indexes = select(&self.imap.read().unwrap());
+            let mut readers = HashMap::new();
+            for (_, index) in &indexes {
+                if let Entry::Vacant(entry) = readers.entry(index.gen) {
+                    let file = File::open(log_path(&self.log_dir, index.gen))?;
+                    entry.insert(LogReader::new(file));
+                }
+            }
+            (indexes, readers)
+        };
+        indexes
+            .into_iter()
+            .map(|(key, index)| {
+                let value = read_value(readers.get_mut(&index.gen).unwrap(), &key, &index)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    // Makes a write durable as required by the durability option. `writer` is the guard the
+    // write was done with, which is released before waiting for a group commit.
+    fn commit(&self, mut writer: MutexGuard<LogWriter>) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => writer.sync(),
+            Durability::GroupCommit => {
+                let seq = writer.seq;
+                drop(writer);
+                self.group_commit.wait(seq, || {
+                    // sync a handle of the file so that other writers can go on meanwhile;
+                    // writes in sealed generations were synced when they were sealed
+                    let (seq, file) = {
+                        let writer = self.writer.lock().unwrap();
+                        (writer.seq, writer.writer.get_ref().try_clone()?)
+                    };
+                    file.sync_data()?;
+                    Ok(seq)
+                })
+            }
+            Durability::None | Durability::Interval(_) => Ok(()),
+        }
+    }
+
+    // seal the active generation once it is full
+    fn roll_if_full(&self, writer: &mut LogWriter) -> Result<()> {
+        if writer.pos >= SEGMENT_SIZE_LIMIT {
+            debug!("seal log generation {}", writer.gen);
+            let next = LogWriter::open(&self.log_dir, writer.gen + 1)?.continue_from(writer);
+            let mut sealed = mem::replace(writer, next);
+            sealed.seal(&self.log_dir)?;
+        }
+        Ok(())
+    }
+}
+
+// Iterator over a range of a `KvStore`, which reads `SCAN_CHUNK_LEN` keys at a time from
+// the index and, for the entries with values, the log. The index is only locked while a
+// chunk is picked, so writes go on in between.
+struct Scan<T> {
+    inner: Arc<KvStoreInner>,
+    // the range of the keys not read yet
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    chunk: vec::IntoIter<T>,
+    // set once the last chunk is read, or reading one failed
+    done: bool,
+}
+
+// What a `Scan` yields for a key picked from the index.
+trait ScanEntry: Sized {
+    fn read<F>(inner: &KvStoreInner, select: F) -> Result<Vec<Self>>
+    where
+        F: FnOnce(&BTreeMap<Vec<u8>, LogIndex>) -> Vec<(Vec<u8>, LogIndex)>;
+}
+
+// a key with its value
+impl ScanEntry for (Vec<u8>, Vec<u8>) {
+    fn read<F>(inner: &KvStoreInner, select: F) -> Result<Vec<Self>>
+    where
+        F: FnOnce(&BTreeMap<Vec<u8>, LogIndex>) -> Vec<(Vec<u8>, LogIndex)>,
+    {
+        inner.read_values(select)
+    }
+}
+
+// a key alone, which does not need the log
+impl ScanEntry for Vec<u8> {
+    fn read<F>(inner: &KvStoreInner, select: F) -> Result<Vec<Self>>
+    where
+        F: FnOnce(&BTreeMap<Vec<u8>, LogIndex>) -> Vec<(Vec<u8>, LogIndex)>,
+    {
+        let indexes = select(&inner.imap.read().unwrap());
+        Ok(indexes.into_iter().map(|(key, _)| key).collect())
+    }
+}
+
+impl<T: ScanEntry> Scan<T> {
+    fn new(inner: Arc<KvStoreInner>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        let done = is_empty_range(&(start.clone(), end.clone()));
+        Scan {
+            inner,
+            start,
+            end,
+            chunk: Vec::new().into_iter(),
+            done,
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Vec<T>> {
+        let range = (self.start.clone(), self.end.clone());
+        let mut last = None;
+        let entries = T::read(&self.inner, |imap| {
+            let now = expiry::now_millis();
+            let mut indexes = Vec::new();
+            for (key, index) in imap.range(range).take(SCAN_CHUNK_LEN) {
+                last = Some(key.clone());
+                if !expiry::is_expired(index.expire_at, now) {
+                    indexes.push((key.clone(), index.clone()));
+                }
+            }
+            indexes
+        })?;
+        match last {
+            Some(key) => self.start = Excluded(key),
+            None => self.done = true,
+        }
+        Ok(entries)
+    }
+}
+
+impl<T: ScanEntry> Iterator for Scan<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.chunk.next() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_chunk() {
+                Ok(chunk) => self.chunk = chunk.into_iter(),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// Reads the value of `key` from its record at `index`.
+fn read_value(reader: &mut LogReader, key: &[u8], index: &LogIndex) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(index.pos))?;
+    match record::read_record(&mut reader.take(index.len))? {
+        Some(Command::Set { key: k, value, .. }) if k == key => Ok(value),
+        // the index points at something other than the value of the key
+        _ => Err(KvsError::Corruption(format!(
+            "No value of the key at offset {} of generation {}",
+            index.pos, index.gen
+        ))),
+    }
+}
+
+// Whether `range` contains no key, which `BTreeMap::range` panics on.
+fn is_empty_range<K: Ord, R: RangeBounds<K>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Included(start), Included(end)) => start > end,
+        (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+// Replays one generation into the index map, reading its hint file if there is one and
+// then the log written after the hint. Returns the number of redundant entries found, and
+// also the commands of the generation if `keep_entries` is set.
+fn load_gen(
+    dir: &Path,
+    gen: u64,
+    map: &mut BTreeMap<Vec<u8>, LogIndex>,
+    recovery: RecoveryMode,
+    keep_entries: bool,
+) -> Result<(u64, Vec<HintEntry>)> {
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+    let (hinted_len, mut entries) = match hint::read_hint(dir, gen) {
+        Ok(Some(hint)) if hint.log_len <= log_len => (hint.log_len, hint.entries),
+        Ok(Some(_)) => {
+            warn!("ignore hint of log generation {}: the log is shorter", gen);
+            (0, Vec::new())
+        }
+        Ok(None) => (0, Vec::new()),
+        Err(e) => {
+            warn!("ignore hint of log generation {}: {}", gen, e);
+            (0, Vec::new())
+        }
+    };
+    if hinted_len < log_len {
+        load_log(dir, gen, hinted_len, &mut entries, recovery)?;
+    }
+
+    let mut dead = 0;
+    let now = expiry::now_millis();
+    let mut apply = |entry: HintEntry| match entry {
+        HintEntry::Set { key, expire_at, .. } if expiry::is_expired(expire_at, now) => {
+            // the expired value replaces the previous one, and is redundant itself
+            if map.remove(&key).is_some() {
+                2
+            } else {
+                1
+            }
+        }
+        HintEntry::Set {
+            key,
+            pos,
+            len,
+            expire_at,
+        } => {
+            if map
+                .insert(key, LogIndex::new(gen, pos, len, expire_at))
+                .is_some()
+            {
+                1
+            } else {
+                0
+            }
+        }
+        HintEntry::Rm { key } => {
+            if map.remove(&key).is_some() {
+                2
+            } else {
+                1
+            }
+        }
+    };
+    if keep_entries {
+        for entry in &entries {
+            dead += apply(entry.clone());
+        }
+        Ok((dead, entries))
+    } else {
+        for entry in entries {
+            dead += apply(entry);
+        }
+        Ok((dead, Vec::new()))
+    }
+}
+
+// Reads the commands in the log of a generation starting at `from`.
+//
+// A damaged record at the end of the log is what a crash in the middle of a write leaves
+// behind; it is handled according to `recovery`, as is a batch missing some of its
+// commands at the end of the log. Damage anywhere else is an error.
+fn load_log(
+    dir: &Path,
+    gen: u64,
+    from: u64,
+    entries: &mut Vec<HintEntry>,
+    recovery: RecoveryMode,
+) -> Result<()> {
+    let path = log_path(dir, gen);
+    let file_len = fs::metadata(&path)?.len();
+    let mut reader = LogReader::new(File::open(&path)?);
+    if from == 0 {
+        if let Err(e) = record::read_header(&mut reader) {
+            if !is_torn(&e, &reader, file_len) {
+                return Err(e);
+            }
+            return recover_tail(&path, 0, file_len, recovery, e);
+        }
+    } else {
+        reader.seek(SeekFrom::Start(from))?;
+    }
+    // position of the marker of the batch being read, the number of entries before it
+    // and the number of its commands still to be read
+    let mut batch: Option<(u64, usize, u32)> = None;
+    loop {
+        let start_pos = reader.pos;
+        let cmd = match record::read_record(&mut reader) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                if !is_torn(&e, &reader, file_len) {
+                    return Err(e);
+                }
+                // an unfinished batch goes away along with the torn record
+                let good_len = match batch {
+                    Some((batch_pos, entries_len, _)) => {
+                        entries.truncate(entries_len);
+                        batch_pos
+                    }
+                    None => start_pos,
+                };
+                return recover_tail(&path, good_len, file_len, recovery, e);
+            }
+        };
+        match cmd {
+            Some(Command::Set { key, expire_at, .. }) => entries.push(HintEntry::Set {
+                key,
+                pos: start_pos,
+                len: reader.pos - start_pos,
+                expire_at,
+            }),
+            Some(Command::Rm { key }) => entries.push(HintEntry::Rm { key }),
+            Some(Command::Batch { count }) => {
+                if batch.is_some() {
+                    return Err(record::corrupted("nested batch"));
+                }
+                if count > 0 {
+                    batch = Some((start_pos, entries.len(), count));
+                }
+                continue;
+            }
+            None => {
+                if let Some((batch_pos, entries_len, count)) = batch {
+                    entries.truncate(entries_len);
+                    let e = KvsError::Corruption(format!(
+                        "Incomplete write batch missing {} commands",
+                        count
+                    ));
+                    return recover_tail(&path, batch_pos, file_len, recovery, e);
+                }
+                return Ok(());
+            }
+        }
+        if let Some((_, _, count)) = batch.as_mut() {
+            *count -= 1;
+            if *count == 0 {
+                batch = None;
+            }
+        }
+    }
+}
+
+// Whether a read error comes from the last record of a log file
+fn is_torn(err: &KvsError, reader: &LogReader, file_len: u64) -> bool {
+    match err {
+        KvsError::TruncatedRecord => true,
+        // the whole damaged record was read, so it is the last one if the file ends here
+        KvsError::Corruption(_) => reader.pos == file_len,
+        _ => false,
+    }
+}
+
+// Cuts a log file back to its last good record at `good_len`, or refuses to do so.
+fn recover_tail(
+    path: &Path,
+    good_len: u64,
+    file_len: u64,
+    recovery: RecoveryMode,
+    err: KvsError,
+) -> Result<()> {
+    let dropped = file_len - good_len;
+    if recovery == RecoveryMode::Refuse {
+        return Err(KvsError::Corruption(format!(
+            "{} in {:?}: {} bytes after offset {} are damaged",
+            err, path, dropped, good_len
+        )));
+    }
+
+    warn!(
+        "{} in {:?}: truncate the log to {} bytes, dropping {} bytes",
+        err, path, good_len, dropped
+    );
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(good_len)?;
+    if good_len == 0 {
+        // the header itself was torn
+        record::write_header(&mut file)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+// Returns the generations of the log files in the directory in ascending order.
+fn sorted_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                gens.push(gen);
+            }
+        }
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// A store written before the log was split into generations keeps everything in a
+// single `log.json`, which becomes the first generation.
+fn migrate_legacy_log(dir: &Path) -> Result<()> {
+    let legacy = dir.join(LEGACY_LOG_NAME);
+    if legacy.is_file() && sorted_gens(dir)?.is_empty() {
+        info!("migrate {:?} to log generation 1", legacy);
+        fs::rename(legacy, log_path(dir, 1))?;
+    }
+    Ok(())
+}
+
+// Logs written by earlier versions are a stream of JSON commands. They are rewritten
+// in the binary format, and the original file is replaced only once the new one is complete.
+// A command cut off at the end of the file is handled as `recovery` says.
+fn upgrade_json_log(dir: &Path, gen: u64, recovery: RecoveryMode) -> Result<()> {
+    let path = log_path(dir, gen);
+    let mut prefix = Vec::new();
+    File::open(&path)?
+        .take(record::HEADER_LEN)
+        .read_to_end(&mut prefix)?;
+    if record::is_binary(&prefix) {
+        return Ok(());
+    }
+
+    info!(
+        "upgrade {:?} to log format version {}",
+        path,
+        record::FORMAT_VERSION
+    );
+    let upgraded_path = path.with_extension("upgrade");
+    let mut writer = BufWriter::new(File::create(&upgraded_path)?);
+    record::write_header(&mut writer)?;
+    let reader = BufReader::new(File::open(&path)?);
+    let mut stream = Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+    while let Some(cmd) = stream.next() {
+        match cmd {
+            Ok(cmd) => {
+                record::write_record(&mut writer, &cmd.into())?;
+            }
+            // a crash in the middle of a write leaves the last command incomplete
+            Err(e) if e.is_eof() => {
+                let good_len = stream.byte_offset();
+                if recovery == RecoveryMode::Refuse {
+                    drop(writer);
+                    fs::remove_file(&upgraded_path)?;
+                    return Err(KvsError::Corruption(format!(
+                        "{} in {:?}: the command after offset {} is incomplete",
+                        e, path, good_len
+                    )));
+                }
+                warn!(
+                    "{} in {:?}: drop the incomplete command after offset {}",
+                    e, path, good_len
+                );
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(upgraded_path, path)?;
+    Ok(())
+}
+
+// Background thread compacting the log whenever it is notified
+struct Compactor {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    fn spawn(store: Arc<KvStoreInner>) -> Result<Self> {
+        // notifications arriving while one is pending are merged into it
+        let (sender, receiver) = bounded::<()>(1);
+        let handle = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || {
+                for () in receiver.iter() {
+                    if let Err(e) = store.compact() {
+                        error!("background compaction failed: {}", e);
+                    }
+                }
+            })?;
+        Ok(Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    fn notify(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    // wait for the running compaction so that the store can be reopened right away
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background compaction thread panicked");
+            }
+        }
+    }
+}
+
+// Background thread syncing the log at a fixed interval
+struct Syncer {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    fn spawn(store: Arc<KvStoreInner>, interval: Duration) -> Result<Self> {
+        // nothing is ever sent: dropping the sender stops the thread
+        let (sender, receiver) = bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-syncer".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    if let Err(e) = store.writer.lock().unwrap().sync() {
+                        error!("background sync failed: {}", e);
+                    }
+                }
+            })?;
+        Ok(Syncer {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background sync thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for KvStoreInner {
+    // a clean shutdown leaves a hint for the active generation as well,
+    // so that the next open does not need to read any log
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            if let Err(e) = writer.seal(&self.log_dir) {
+                error!("fail to write hint of log generation {}: {}", writer.gen, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // milliseconds since the Unix epoch after which the key is gone
+        expire_at: Option<u64>,
+    },
+    Rm {
+        key: Vec<u8>,
+    },
+    // marks the start of a write batch made of the next `count` commands
+    Batch {
+        count: u32,
+    },
+}
+
+// A command as written in the JSON logs of earlier versions, which only had string keys
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        expire_at: Option<u64>,
+    },
+    Rm {
+        key: String,
+    },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Self {
+        match cmd {
+            JsonCommand::Set {
+                key,
+                value,
+                expire_at,
+            } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expire_at,
+            },
+            JsonCommand::Rm { key } => Command::Rm {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+// Record the reading position which is used when loading log file
+struct LogReader {
+    reader: BufReader<File>,
+    pos: u64,
+}
+
+impl LogReader {
+    fn new(file: File) -> Self {
+        let mut reader = BufReader::new(file);
+        let pos = reader.seek(SeekFrom::Start(0)).unwrap();
+        LogReader { reader, pos }
+    }
+}
+
+impl Read for LogReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for LogReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.reader.seek(pos)?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+// Record the writing position which is used by the index map
+struct LogWriter {
+    writer: BufWriter<File>,
+    gen: u64,
+    pos: u64,
+    // commands written to this generation, for its hint file
+    hints: Vec<HintEntry>,
+    // number of commands written so far by this store, across generations
+    seq: u64,
+}
+
+impl LogWriter {
+    // Opens the log file of a generation for appending, creating it if necessary.
+    fn open(dir: &Path, gen: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(dir, gen))?;
+        let mut writer = BufWriter::new(file);
+        let pos = writer.seek(SeekFrom::End(0))?;
+        let mut writer = LogWriter {
+            writer,
+            gen,
+            pos,
+            hints: Vec::new(),
+            seq: 0,
+        };
+        if pos == 0 {
+            record::write_header(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(writer)
+    }
+
+    // Writes a command to the log and returns where it is stored.
+    fn append(&mut self, cmd: &Command) -> Result<LogIndex> {
+        let index = self.write_cmd(cmd)?;
+        self.flush()?;
+        Ok(index)
+    }
+
+    // Writes the commands of a batch to the log behind a batch marker, and returns where
+    // each of them is stored.
+    fn append_batch(&mut self, cmds: &[Command]) -> Result<Vec<LogIndex>> {
+        self.write_cmd(&Command::Batch {
+            count: cmds.len() as u32,
+        })?;
+        let indexes = cmds
+            .iter()
+            .map(|cmd| self.write_cmd(cmd))
+            .collect::<Result<_>>()?;
+        self.flush()?;
+        Ok(indexes)
+    }
+
+    fn write_cmd(&mut self, cmd: &Command) -> Result<LogIndex> {
+        let start_pos = self.pos;
+        let len = record::write_record(self, cmd)?;
+        self.seq += 1;
+        let mut expire_at = None;
+        match cmd {
+            Command::Set {
+                key,
+                expire_at: expiry,
+                ..
+            } => {
+                expire_at = *expiry;
+                self.hints.push(HintEntry::Set {
+                    key: key.clone(),
+                    pos: start_pos,
+                    len,
+                    expire_at,
+                })
+            }
+            Command::Rm { key } => self.hints.push(HintEntry::Rm { key: key.clone() }),
+            // a hint only covers complete batches, so it does not need the marker
+            Command::Batch { .. } => {}
+        }
+        Ok(LogIndex::new(self.gen, start_pos, len, expire_at))
+    }
+
+    // Keeps numbering commands after those written by the writer of the previous generation.
+    fn continue_from(mut self, previous: &LogWriter) -> Self {
+        self.seq = previous.seq;
+        self
+    }
+
+    // Makes everything written so far durable.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // Syncs the log and writes the hint file covering everything written so far.
+    fn seal(&mut self, dir: &Path) -> Result<()> {
+        self.sync()?;
+        hint::write_hint(dir, self.gen, self.pos, &self.hints)
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct LogIndex {
+    // generation of the log file holding the entry
+    gen: u64,
+    pos: u64,
+    len: u64,
+    // expiry time of the key, if it has one
+    expire_at: Option<u64>,
+}
+
+impl LogIndex {
+    fn new(gen: u64, pos: u64, len: u64, expire_at: Option<u64>) -> Self {
+        LogIndex {
+            gen,
+            pos,
+            len,
+            expire_at,
+        }
+    }
+}
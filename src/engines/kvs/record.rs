@@ -0,0 +1,176 @@
+//! Binary format of the log files.
+//!
+//! Every log file starts with a header made of the magic bytes `KVSL` followed by the
+//! format version as a little-endian `u32`. The header is followed by records framed as
+//!
+//! ```text
+//! | payload length: u32 | CRC32 of payload: u32 | payload |
+//! ```
+//!
+//! with all integers in little-endian. The payload is an encoded `Command`: a tag byte,
+//! then the key and the value, each as its `u32` byte length followed by its bytes. A `Set`
+//! of a key which expires has its own tag, and the expiry time as a `u64` number of
+//! milliseconds since the Unix epoch after the value.
+//!
+//! The commands of a write batch are preceded by a `Batch` record holding their number
+//! as a `u32`, so that a batch cut short by a crash can be recognized and dropped.
+
+use std::io::{self, Read, Write};
+
+use super::codec::{put_bytes, u32_from, Fields};
+use super::Command;
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSL";
+/// Version of the record format written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+/// Length of the file header.
+pub const HEADER_LEN: u64 = 8;
+// length of the frame in front of each payload
+const FRAME_LEN: u64 = 8;
+
+const TAG_SET: u8 = 0;
+const TAG_RM: u8 = 1;
+const TAG_BATCH: u8 = 2;
+const TAG_SET_EXPIRING: u8 = 3;
+
+/// Error reading back a record whose content is damaged.
+pub fn corrupted(reason: &str) -> KvsError {
+    KvsError::Corruption(format!("Corrupted log record: {}", reason))
+}
+
+/// Writes the file header of a new log file.
+pub fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+/// Reads and checks the file header of a log file.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<()> {
+    let mut header = [0; HEADER_LEN as usize];
+    read_exact(reader, &mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(corrupted("bad magic bytes"));
+    }
+    let version = u32_from(&header[4..]);
+    if version != FORMAT_VERSION {
+        return Err(KvsError::Other(format!(
+            "Unsupported log format version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+/// Checks whether the content of a log file starts with the magic bytes of the binary format,
+/// or with a part of them if the header itself was torn.
+///
+/// Anything else is a log written as a JSON stream by earlier versions.
+pub fn is_binary(prefix: &[u8]) -> bool {
+    if prefix.len() < MAGIC.len() {
+        MAGIC.starts_with(prefix)
+    } else {
+        prefix.starts_with(MAGIC)
+    }
+}
+
+/// Frames an encoded command and writes it. Returns the number of bytes written.
+pub fn write_record<W: Write>(writer: &mut W, cmd: &Command) -> io::Result<u64> {
+    let payload = encode(cmd);
+    let mut frame = [0; FRAME_LEN as usize];
+    frame[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame[4..].copy_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    writer.write_all(&frame)?;
+    writer.write_all(&payload)?;
+    Ok(FRAME_LEN + payload.len() as u64)
+}
+
+/// Reads the next record and checks its CRC.
+///
+/// Returns `Ok(None)` at the end of the log.
+pub fn read_record<R: Read>(reader: &mut R) -> Result<Option<Command>> {
+    let mut frame = [0; FRAME_LEN as usize];
+    match reader.read_exact(&mut frame[..1]) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    read_exact(reader, &mut frame[1..])?;
+    let len = u32_from(&frame[..4]) as u64;
+    let crc = u32_from(&frame[4..]);
+
+    // a damaged length must not make us allocate a huge buffer up front
+    let mut payload = Vec::new();
+    if reader.take(len).read_to_end(&mut payload)? as u64 != len {
+        return Err(KvsError::TruncatedRecord);
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(corrupted("checksum mismatch"));
+    }
+    decode(&payload).map(Some)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    match reader.read_exact(buf) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(KvsError::TruncatedRecord),
+        res => Ok(res?),
+    }
+}
+
+fn encode(cmd: &Command) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expire_at,
+        } => {
+            buf.push(if expire_at.is_some() {
+                TAG_SET_EXPIRING
+            } else {
+                TAG_SET
+            });
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+            if let Some(expire_at) = expire_at {
+                buf.extend_from_slice(&expire_at.to_le_bytes());
+            }
+        }
+        Command::Rm { key } => {
+            buf.push(TAG_RM);
+            put_bytes(&mut buf, key);
+        }
+        Command::Batch { count } => {
+            buf.push(TAG_BATCH);
+            buf.extend_from_slice(&count.to_le_bytes());
+        }
+    }
+    buf
+}
+
+fn decode(payload: &[u8]) -> Result<Command> {
+    let mut fields = Fields::new(payload, || corrupted("truncated payload"));
+    let cmd = match fields.u8()? {
+        TAG_SET => Command::Set {
+            key: fields.bytes()?,
+            value: fields.bytes()?,
+            expire_at: None,
+        },
+        TAG_SET_EXPIRING => Command::Set {
+            key: fields.bytes()?,
+            value: fields.bytes()?,
+            expire_at: Some(fields.u64()?),
+        },
+        TAG_RM => Command::Rm {
+            key: fields.bytes()?,
+        },
+        TAG_BATCH => Command::Batch {
+            count: fields.u32()?,
+        },
+        _ => return Err(corrupted("unknown command tag")),
+    };
+    if !fields.is_empty() {
+        return Err(corrupted("trailing bytes"));
+    }
+    Ok(cmd)
+}
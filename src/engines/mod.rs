@@ -1,25 +1,214 @@
 //! This module provides pluggable storage engine trait and instances.
 
 use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
-pub use self::kvs::KvStore;
+pub use self::batch::WriteBatch;
+pub use self::durability::Durability;
+pub use self::kvs::{KvStore, KvStoreOptions, RecoveryMode};
 pub use self::sled::SledKvsEngine;
 
 /// Trait for a shared K-V store engine.
+///
+/// Keys and values are arbitrary bytes. The methods taking strings are a convenience on top
+/// of the byte ones; they fail with a UTF-8 error on a value which is not a valid string.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key, which expires after `ttl`.
+    ///
+    /// An expired key is treated as not found. Setting the key again without a TTL makes
+    /// it persistent.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `Ok(None)` if the key is not found.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key along with its expiry time, in milliseconds since the
+    /// Unix epoch, if it was set with a TTL.
+    ///
+    /// Returns `Ok(None)` if the key is not found.
+    fn get_bytes_with_expiry(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<u64>)>>;
+
+    /// Removes a given key.
+    ///
+    /// Returns error if the key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key to `new` if its current value is `expected`, atomically with
+    /// respect to other writes. `None` stands for a missing key, so that a key can be created
+    /// only if it does not exist yet, or removed.
+    ///
+    /// Returns whether the value was swapped.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets the value of a key to `new`, which expires after `ttl`, if its current value is
+    /// `expected`, like `compare_and_swap_bytes`.
+    ///
+    /// Returns whether the value was swapped.
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool>;
+
+    /// Applies all writes of a batch, or none of them if it fails.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Iterates over the keys in `range` with their values, in key order.
+    ///
+    /// The entries are read as the iterator advances, so that a large range is never held
+    /// in memory as a whole. The writes made meanwhile may or may not be seen.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter>;
+
+    /// Iterates over the keys starting with `prefix` with their values, in key order, like
+    /// `scan_bytes`.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanIter>;
+
+    /// Iterates over the keys in `range` without reading their values, in key order, like
+    /// `scan_bytes`.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter>;
+
+    /// Makes every write so far durable, whatever the durability of the engine, along with
+    /// the state which makes opening the store fast.
+    fn flush(&self) -> Result<()>;
+
+    /// Gets figures about the storage of the engine, for monitoring.
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// Reclaims the space taken by redundant data now, returning once it is done. Engines
+    /// which reclaim space on their own as they go do nothing.
+    fn compact(&self) -> Result<()>;
+
+    /// Name of the engine, as given to `kvs-server --engine`.
+    fn name(&self) -> &'static str;
+
     /// Sets the value of a string key to a string.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string, which expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `Ok(None)` if the key is not found.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(into_string)
+            .transpose()
+    }
 
     /// Removes a given key.
     ///
     /// Returns error if the key is not found.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Like `compare_and_swap_bytes`, with string values.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Gets the string keys in `range` with their string values, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        // strings sort like their UTF-8 bytes
+        let range = (
+            bytes_bound(range.start_bound().cloned()),
+            bytes_bound(range.end_bound().cloned()),
+        );
+        into_string_pairs(self.scan_bytes(range)?.collect::<Result<_>>()?)
+    }
+
+    /// Gets the string keys starting with `prefix` with their string values, in key order.
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        into_string_pairs(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .collect::<Result<_>>()?,
+        )
+    }
+}
+
+/// Iterator over the keys and values of a scan, in key order. See `KvsEngine::scan_bytes`.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// Iterator over the keys of a scan, in key order. See `KvsEngine::scan_keys`.
+pub type KeyIter = Box<dyn Iterator<Item = Result<Vec<u8>>>>;
+
+/// Figures about the storage of an engine. Those an engine does not track are `None`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EngineStats {
+    /// Number of keys, counting the expired ones not reclaimed yet.
+    pub keys: Option<u64>,
+    /// Size of the files of the engine on the disk, in bytes.
+    pub disk_bytes: u64,
+    /// Number of log entries made redundant by later writes, which a compaction drops,
+    /// since the last compaction started.
+    pub dead_entries: Option<u64>,
+    /// Number of compactions finished since the engine was opened.
+    pub compactions: Option<u64>,
+}
+
+pub(crate) fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
+
+pub(crate) fn into_string_pairs(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    entries
+        .into_iter()
+        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+        .collect()
+}
+
+pub(crate) fn bytes_bound(bound: Bound<String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(s) => Bound::Included(s.into_bytes()),
+        Bound::Excluded(s) => Bound::Excluded(s.into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// The end of the range of the keys starting with `prefix`: the first key after them.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match prefix.iter().rposition(|&b| b != 0xff) {
+        Some(i) => {
+            let mut end = prefix[..=i].to_vec();
+            end[i] += 1;
+            Bound::Excluded(end)
+        }
+        // every key starting with 0xff bytes only, or with nothing, is in the range
+        None => Bound::Unbounded,
+    }
 }
 
+mod batch;
+mod durability;
+pub(crate) mod expiry;
 mod kvs;
 mod sled;
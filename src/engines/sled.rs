@@ -1,44 +1,377 @@
+use sled::{
+    ConflictableTransactionError, ConflictableTransactionResult, IVec, TransactionError,
+    Transactional, TransactionalTree,
+};
+use std::ops::RangeBounds;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::KvsEngine;
-use crate::Result;
+use super::batch::BatchOp;
+use super::durability::GroupCommit;
+use super::expiry::{self, Sweeper};
+use super::{Durability, EngineStats, KeyIter, KvsEngine, ScanIter, WriteBatch};
+use crate::{KvsError, Result};
+
+// name of the tree holding the expiry times of the keys which have one
+const EXPIRY_TREE: &[u8] = b"kvs-expiry";
 
 /// Wrapper of `sled::Db`.
+///
+/// The expiry times of keys set with a TTL are kept in a separate tree, which is updated
+/// in the same transaction as the values. Expired keys are invisible, and removed by a
+/// background sweeper.
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    // expiry times as big-endian milliseconds since the Unix epoch
+    expiry: sled::Tree,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // number of writes done so far, for group commit
+    seq: Arc<AtomicU64>,
+    // handle of the background thread reclaiming expired keys, only kept to stop it
+    _sweeper: Arc<Sweeper>,
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.into_bytes())?;
-        self.db.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(|values, expiry| {
+            values.insert(&key[..], &value[..])?;
+            expiry.remove(&key[..])?;
+            Ok(())
+        })?;
+        self.commit()
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .db
-            .get(key)?
-            .map(|buf| String::from_utf8(buf.to_vec()))
-            .transpose()?)
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = expiry::expire_at(ttl).to_be_bytes();
+        self.transaction(|values, expiry| {
+            values.insert(&key[..], &value[..])?;
+            expiry.insert(&key[..], &expire_at[..])?;
+            Ok(())
+        })?;
+        self.commit()
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if self.db.remove(key)?.is_none() {
-            Err(failure::err_msg("Key not found"))
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let now = expiry::now_millis();
+        let value = if expiry::is_expired(self.expire_at(&key)?, now) {
+            // the key may have been set again in the meantime
+            self.transaction(|values, expiry| get_visible(values, expiry, &key, now))?
         } else {
-            self.db.flush()?;
-            Ok(())
+            self.db.get(&key)?
+        };
+        Ok(value.map(|buf| buf.to_vec()))
+    }
+
+    fn get_bytes_with_expiry(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let now = expiry::now_millis();
+        let entry = self.transaction(|values, expiry| {
+            let expire_at = expire_at_in(expiry, &key)?;
+            if expiry::is_expired(expire_at, now) {
+                return Ok(None);
+            }
+            Ok(values.get(&key[..])?.map(|value| (value, expire_at)))
+        })?;
+        Ok(entry.map(|(value, expire_at)| (value.to_vec(), expire_at)))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = expiry::now_millis();
+        let found = self.transaction(|values, expiry| {
+            let found = get_visible(values, expiry, &key, now)?.is_some();
+            values.remove(&key[..])?;
+            expiry.remove(&key[..])?;
+            Ok(found)
+        })?;
+        if !found {
+            return Err(KvsError::KeyNotFound);
         }
+        self.commit()
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.swap(key, expected, new, None)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        self.swap(key, expected, Some(new), Some(expiry::expire_at(ttl)))
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let ops = batch.into_ops();
+        self.transaction(|values, expiry| {
+            for op in &ops {
+                let key = match op {
+                    BatchOp::Set { key, value } => {
+                        values.insert(&key[..], &value[..])?;
+                        key
+                    }
+                    BatchOp::Rm { key } => {
+                        values.remove(&key[..])?;
+                        key
+                    }
+                };
+                expiry.remove(&key[..])?;
+            }
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        Ok(self.entries(self.db.range(range)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        Ok(self.entries(self.db.scan_prefix(prefix)))
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        let engine = self.clone();
+        let now = expiry::now_millis();
+        let check_expiry = !self.expiry.is_empty();
+        Ok(Box::new(self.db.range(range).keys().filter_map(
+            move |key| {
+                let read = key.map_err(KvsError::from).and_then(|key| {
+                    if check_expiry && expiry::is_expired(engine.expire_at(&key)?, now) {
+                        return Ok(None);
+                    }
+                    Ok(Some(key.to_vec()))
+                });
+                read.transpose()
+            },
+        )))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // counting the keys would scan the whole tree
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    // sled reclaims the space of its log segments in the background
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
     }
 }
 
 impl SledKvsEngine {
     /// Opens an existed sled instance or creates a new one at the specified path.
+    ///
+    /// Every write is flushed to the disk.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        SledKvsEngine::open_with(path, Durability::EveryWrite)
+    }
+
+    /// Like `open`, but syncing writes to the disk as required by `durability`.
+    pub fn open_with<P: AsRef<Path>>(path: P, durability: Durability) -> Result<Self> {
+        let mut config = sled::Config::new().path(path);
+        if let Durability::Interval(interval) = durability {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let db = config.open()?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let sweeper = {
+            let db = db.clone();
+            let expiry = expiry.clone();
+            Sweeper::spawn(move || sweep_expired(&db, &expiry))?
+        };
         Ok(SledKvsEngine {
-            db: sled::open(path)?,
+            db,
+            expiry,
+            durability,
+            group_commit: Arc::new(GroupCommit::new()),
+            seq: Arc::new(AtomicU64::new(0)),
+            _sweeper: Arc::new(sweeper),
         })
     }
+
+    // Runs `f` as a transaction over the values and their expiry times.
+    fn transaction<F, A>(&self, f: F) -> Result<A>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+    {
+        transaction(&self.db, &self.expiry, f)
+    }
+
+    fn expire_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        self.expiry
+            .get(key)?
+            .map(|buf| decode_expiry(&buf))
+            .transpose()
+    }
+
+    // `expire_at` only applies when a new value is set
+    fn swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expire_at: Option<u64>,
+    ) -> Result<bool> {
+        let now = expiry::now_millis();
+        let swapped = self.transaction(|values, expiry| {
+            let current = get_visible(values, expiry, &key, now)?;
+            if current.as_ref().map(|v| &v[..]) != expected.as_ref().map(|v| &v[..]) {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => values.insert(&key[..], &value[..])?,
+                None => values.remove(&key[..])?,
+            };
+            match expire_at.filter(|_| new.is_some()) {
+                Some(expire_at) => expiry.insert(&key[..], &expire_at.to_be_bytes()[..])?,
+                None => expiry.remove(&key[..])?,
+            };
+            Ok(true)
+        })?;
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    // Iterates over the entries of a scan, leaving out the expired keys.
+    fn entries(&self, iter: sled::Iter) -> ScanIter {
+        let engine = self.clone();
+        let now = expiry::now_millis();
+        let check_expiry = !self.expiry.is_empty();
+        Box::new(iter.filter_map(move |entry| {
+            let read = entry.map_err(KvsError::from).and_then(|(key, value)| {
+                if check_expiry && expiry::is_expired(engine.expire_at(&key)?, now) {
+                    return Ok(None);
+                }
+                Ok(Some((key.to_vec(), value.to_vec())))
+            });
+            read.transpose()
+        }))
+    }
+
+    // Makes a write durable as required by the durability option.
+    fn commit(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => {
+                self.db.flush()?;
+            }
+            Durability::GroupCommit => {
+                let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+                self.group_commit.wait(seq, || {
+                    // a flush covers every write done before it starts
+                    let seq = self.seq.load(Ordering::SeqCst);
+                    self.db.flush()?;
+                    Ok(seq)
+                })?;
+            }
+            // sled flushes in the background, every 500ms by default
+            Durability::None | Durability::Interval(_) => {}
+        }
+        Ok(())
+    }
+}
+
+fn transaction<F, A>(values: &sled::Tree, expiry: &sled::Tree, f: F) -> Result<A>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<A>,
+{
+    (values, expiry)
+        .transaction(|(values, expiry)| f(values, expiry))
+        .map_err(|e| match e {
+            TransactionError::Storage(e) => e.into(),
+            // only a damaged expiry time aborts a transaction
+            TransactionError::Abort(()) => KvsError::Corruption("Corrupted expiry time".to_owned()),
+        })
+}
+
+// Gets the value of a key within a transaction, unless the key has expired.
+fn get_visible(
+    values: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<Option<IVec>> {
+    let expire_at = expire_at_in(expiry, key)?;
+    if expiry::is_expired(expire_at, now) {
+        return Ok(None);
+    }
+    Ok(values.get(key)?)
+}
+
+// Removes the keys which have expired.
+fn sweep_expired(values: &sled::Tree, expiry: &sled::Tree) -> Result<()> {
+    let now = expiry::now_millis();
+    let mut removed = 0;
+    for entry in expiry.iter() {
+        let (key, expire_at) = entry?;
+        if !expiry::is_expired(Some(decode_expiry(&expire_at)?), now) {
+            continue;
+        }
+        // the key may have been set again in the meantime
+        let swept = transaction(values, expiry, |values, expiry| {
+            if !expiry::is_expired(expire_at_in(expiry, &key)?, now) {
+                return Ok(false);
+            }
+            values.remove(&key)?;
+            expiry.remove(&key)?;
+            Ok(true)
+        })?;
+        if swept {
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        debug!("reclaim {} expired keys", removed);
+    }
+    Ok(())
+}
+
+// Gets the expiry time of a key within a transaction, which is aborted if the time is
+// damaged.
+fn expire_at_in(
+    expiry: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<u64>> {
+    match expiry.get(key)? {
+        Some(buf) => match decode_expiry(&buf) {
+            Ok(expire_at) => Ok(Some(expire_at)),
+            Err(_) => Err(ConflictableTransactionError::Abort(())),
+        },
+        None => Ok(None),
+    }
+}
+
+fn decode_expiry(buf: &[u8]) -> Result<u64> {
+    if buf.len() != 8 {
+        return Err(KvsError::Corruption(format!(
+            "Corrupted expiry time: {} bytes instead of 8",
+            buf.len()
+        )));
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf);
+    Ok(u64::from_be_bytes(bytes))
 }
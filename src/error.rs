@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Error type for kvs.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KvsError {
+    /// Removing a key which is not found.
+    KeyNotFound,
+    /// I/O error.
+    Io(io::Error),
+    /// Serialization or deserialization error.
+    Serialization(String),
+    /// A key or value which is not valid UTF-8 was read with the string API.
+    Utf8(FromUtf8Error),
+    /// Data damaged on the disk.
+    Corruption(String),
+    /// A log record cut short, usually by a crash in the middle of a write.
+    TruncatedRecord,
+    /// The peer does not follow the protocol.
+    Protocol(String),
+    /// Error of the sled engine.
+    Sled(sled::Error),
+    /// TLS error, such as a certificate which cannot be loaded or a failed handshake.
+    Tls(String),
+    /// A client failed to authenticate, or its ACL does not allow a request.
+    PermissionDenied(String),
+    /// A write sent to a follower, which only takes the writes of the leader at the given
+    /// address.
+    ReadOnly(String),
+    /// Error reported by the server. `KeyNotFound` is reported as itself instead.
+    Server {
+        /// Kind of the error in the server.
+        kind: ErrorKind,
+        /// Description of the error in the server.
+        message: String,
+    },
+    /// Any other error.
+    Other(String),
+}
+
+/// Kind of a `KvsError`, which is also how the server reports errors to clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// See `KvsError::KeyNotFound`.
+    KeyNotFound,
+    /// See `KvsError::Io`.
+    Io,
+    /// See `KvsError::Serialization`.
+    Serialization,
+    /// See `KvsError::Utf8`.
+    Utf8,
+    /// See `KvsError::Corruption` and `KvsError::TruncatedRecord`.
+    Corruption,
+    /// See `KvsError::Protocol`.
+    Protocol,
+    /// See `KvsError::Sled`.
+    Storage,
+    /// See `KvsError::Other`.
+    Other,
+    /// See `KvsError::Tls`.
+    Tls,
+    /// See `KvsError::PermissionDenied`.
+    PermissionDenied,
+    /// See `KvsError::ReadOnly`.
+    ReadOnly,
+}
+
+impl KvsError {
+    /// Returns the kind of the error, which for an error reported by the server is the
+    /// kind of the error in the server.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            KvsError::KeyNotFound => ErrorKind::KeyNotFound,
+            KvsError::Io(_) => ErrorKind::Io,
+            KvsError::Serialization(_) => ErrorKind::Serialization,
+            KvsError::Utf8(_) => ErrorKind::Utf8,
+            KvsError::Corruption(_) | KvsError::TruncatedRecord => ErrorKind::Corruption,
+            KvsError::Protocol(_) => ErrorKind::Protocol,
+            KvsError::Sled(_) => ErrorKind::Storage,
+            KvsError::Tls(_) => ErrorKind::Tls,
+            KvsError::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            KvsError::ReadOnly(_) => ErrorKind::ReadOnly,
+            KvsError::Server { kind, .. } => *kind,
+            KvsError::Other(_) => ErrorKind::Other,
+        }
+    }
+
+    // Rebuilds an error reported by the server.
+    pub(crate) fn from_server(kind: ErrorKind, message: String) -> Self {
+        match kind {
+            ErrorKind::KeyNotFound => KvsError::KeyNotFound,
+            kind => KvsError::Server { kind, message },
+        }
+    }
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Io(e) => write!(f, "{}", e),
+            KvsError::Serialization(msg) => write!(f, "{}", msg),
+            KvsError::Utf8(e) => write!(f, "{}", e),
+            KvsError::Corruption(msg) => write!(f, "{}", msg),
+            KvsError::TruncatedRecord => write!(f, "Truncated log record"),
+            KvsError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            KvsError::Sled(e) => write!(f, "{}", e),
+            KvsError::Tls(msg) => write!(f, "TLS error: {}", msg),
+            KvsError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            KvsError::ReadOnly(leader) => {
+                write!(
+                    f,
+                    "Read-only follower, send writes to the leader {}",
+                    leader
+                )
+            }
+            KvsError::Server { message, .. } => write!(f, "{}", message),
+            KvsError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Utf8(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> Self {
+        // a failed TLS handshake comes out of the stream as an I/O error
+        match err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+        {
+            Some(e) => KvsError::Tls(e.to_string()),
+            None => KvsError::Io(err),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> Self {
+        KvsError::Utf8(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> Self {
+        KvsError::Serialization(err.to_string())
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(err) => KvsError::Io(err),
+            err => KvsError::Serialization(err.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> Self {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> Self {
+        KvsError::Tls(err.to_string())
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(err: rayon::ThreadPoolBuildError) -> Self {
+        KvsError::Other(err.to_string())
+    }
+}
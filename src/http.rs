@@ -0,0 +1,596 @@
+//! HTTP/1.1 gateway, for clients which cannot link `KvsClient`.
+//!
+//! | Request | Response |
+//! | --- | --- |
+//! | `GET /keys/{key}` | `200` with the value as the body, `404` if not found |
+//! | `PUT /keys/{key}[?ttl=seconds]` | `204` once the body is stored as the value |
+//! | `DELETE /keys/{key}` | `204`, `404` if not found |
+//! | `GET /keys[?prefix=p][&start=a][&end=b][&limit=n]` | `200` with a page of entries in key order, see below |
+//!
+//! Listing returns `{"entries": [{"key": ..., "value": ...}, ...], "next": ...}`, with at
+//! most `limit` entries, 100 unless given and never more than 1000. `next` is the first key
+//! left out, percent-encoded to be passed as `start` for the next page, or `null` after the
+//! last page.
+//!
+//! Keys in paths and queries are percent-encoded, and values are raw bytes in bodies.
+//! Listing keys or values which are not valid UTF-8 fails with `406`, as JSON cannot carry
+//! them. Errors are JSON objects `{"kind": ..., "error": ...}`: `400` for a bad request and
+//! `500` for an error of the engine.
+//!
+//! If the server has credentials, a request may authenticate with Basic authentication,
+//! failing with `401`, and is anonymous otherwise. A request the ACL does not allow fails
+//! with `403`, and listing skips the keys the client may not read. A write to a follower
+//! fails with `403` too.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+use serde_json::json;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::{Access, Credentials};
+use crate::net::Stream;
+use crate::server::{is_timeout, turn_away, Shared, Timeouts, TOO_MANY_CONNECTIONS};
+use crate::{ErrorKind, KvsEngine, KvsError, Result};
+
+// the largest head and body accepted
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+// the number of entries listed unless the client asks for another one, and the most it may
+const DEFAULT_PAGE_LEN: usize = 100;
+const MAX_PAGE_LEN: usize = 1000;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+    authorization: Option<Vec<u8>>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+    body: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Page {
+    entries: Vec<Entry>,
+    // the first key left out, percent-encoded
+    next: Option<String>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Response {
+            content_type: "application/octet-stream",
+            body,
+            ..Response::new(200)
+        }
+    }
+
+    fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        Response {
+            // serializing plain data cannot fail
+            body: serde_json::to_vec(body).unwrap(),
+            ..Response::new(status)
+        }
+    }
+
+    fn error(status: u16, kind: ErrorKind, msg: &str) -> Self {
+        Response::json(status, &json!({ "kind": kind, "error": msg }))
+    }
+
+    fn bad_request(msg: &str) -> Self {
+        Response::error(400, ErrorKind::Protocol, msg)
+    }
+
+    fn unauthorized(msg: &str) -> Self {
+        let mut response = Response::error(401, ErrorKind::PermissionDenied, msg);
+        response
+            .headers
+            .push(("WWW-Authenticate", "Basic realm=\"kvs\""));
+        response
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.body.len()
+        )?;
+        if !self.body.is_empty() {
+            write!(writer, "Content-Type: {}\r\n", self.content_type)?;
+        }
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves a client speaking HTTP/1.1, keeping the connection alive between requests
+/// unless asked otherwise.
+pub fn handle_client<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    timeouts: Timeouts,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let peer_addr = stream.peer();
+    debug!("Connected to {} with HTTP", peer_addr);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // the stream cannot be followed any more
+            Err(RequestError(response)) => {
+                response.write_to(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+        debug!(
+            "Receive HTTP request from {}: {} {}",
+            peer_addr, request.method, request.path
+        );
+        let response = match authenticate(shared.credentials.as_deref(), &request) {
+            Ok(access) => route(&engine, &access, &request),
+            Err(response) => response,
+        };
+        response.write_to(&mut writer, request.keep_alive)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Turns away a client with `503`.
+pub fn reject_client(stream: Stream) -> Result<()> {
+    let mut response = Vec::new();
+    Response::error(503, ErrorKind::Other, TOO_MANY_CONNECTIONS).write_to(&mut response, false)?;
+    turn_away(stream, &response)
+}
+
+// Answers a single request to the metrics listener with the output of `render`, then
+// closes the connection.
+pub(crate) fn serve_metrics(stream: Stream, render: impl FnOnce() -> Result<String>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let response = match read_request(&mut reader) {
+        Ok(Some(request)) if request.path != "/metrics" => {
+            Response::error(404, ErrorKind::Protocol, "No such endpoint")
+        }
+        Ok(Some(request)) if request.method != "GET" => method_not_allowed("GET"),
+        Ok(Some(_)) => match render() {
+            Ok(metrics) => Response {
+                content_type: "text/plain; version=0.0.4",
+                body: metrics.into_bytes(),
+                ..Response::new(200)
+            },
+            Err(e) => Response::error(500, e.kind(), &e.to_string()),
+        },
+        Ok(None) => return Ok(()),
+        Err(RequestError(response)) => response,
+    };
+    response.write_to(&mut writer, false)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Gets the access of the client sending `request`, or the response refusing it.
+fn authenticate(
+    credentials: Option<&Credentials>,
+    request: &Request,
+) -> std::result::Result<Access, Response> {
+    let (credentials, authorization) = match (credentials, &request.authorization) {
+        (Some(credentials), Some(authorization)) => (credentials, authorization),
+        (credentials, _) => return Ok(Access::anonymous(credentials)),
+    };
+    let (name, secret) = parse_basic(authorization)
+        .ok_or_else(|| Response::unauthorized("Only Basic authentication is supported"))?;
+    credentials
+        .authenticate(&name, &secret)
+        .map_err(|e| Response::unauthorized(&e.to_string()))
+}
+
+// Decodes the name and secret of a Basic `Authorization` header.
+fn parse_basic(authorization: &[u8]) -> Option<(String, String)> {
+    let authorization = std::str::from_utf8(authorization).ok()?.trim();
+    let space = authorization.find(' ')?;
+    if !authorization[..space].eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(authorization[space..].trim()).ok()?).ok()?;
+    let colon = decoded.find(':')?;
+    Some((decoded[..colon].to_owned(), decoded[colon + 1..].to_owned()))
+}
+
+fn route<E: KvsEngine>(engine: &E, access: &Access, request: &Request) -> Response {
+    let result = if request.path == "/keys" {
+        match request.method.as_str() {
+            "GET" => list(engine, access, &request.query),
+            _ => return method_not_allowed("GET"),
+        }
+    } else if let Some(key) = request.path.strip_prefix("/keys/") {
+        let key = match percent_decode(key.as_bytes(), false) {
+            Some(key) => key,
+            None => return Response::bad_request("Bad percent-encoding in the key"),
+        };
+        match request.method.as_str() {
+            "GET" => access
+                .check_read(&key)
+                .and_then(|()| engine.get_bytes(key))
+                .map(|value| match value {
+                    Some(value) => Response::bytes(value),
+                    None => not_found(),
+                }),
+            "PUT" => access
+                .check_write(&key)
+                .and_then(|()| put(engine, key, request)),
+            "DELETE" => match access
+                .check_write(&key)
+                .and_then(|()| engine.remove_bytes(key))
+            {
+                Ok(()) => Ok(Response::new(204)),
+                Err(KvsError::KeyNotFound) => Ok(not_found()),
+                Err(e) => Err(e),
+            },
+            _ => return method_not_allowed("GET, PUT, DELETE"),
+        }
+    } else {
+        return Response::error(404, ErrorKind::Protocol, "No such endpoint");
+    };
+
+    result.unwrap_or_else(|e| match e {
+        KvsError::PermissionDenied(_) | KvsError::ReadOnly(_) => {
+            Response::error(403, e.kind(), &e.to_string())
+        }
+        e => {
+            error!("engine error: {}", e);
+            Response::error(500, e.kind(), &e.to_string())
+        }
+    })
+}
+
+fn put<E: KvsEngine>(engine: &E, key: Vec<u8>, request: &Request) -> Result<Response> {
+    match query_param(&request.query, "ttl") {
+        None => engine.set_bytes(key, request.body.clone())?,
+        Some(ttl) => match parse_number(ttl) {
+            Some(secs) if secs > 0 => {
+                engine.set_bytes_with_ttl(key, request.body.clone(), Duration::from_secs(secs))?
+            }
+            _ => {
+                return Ok(Response::bad_request(
+                    "ttl must be a positive number of seconds",
+                ))
+            }
+        },
+    }
+    Ok(Response::new(204))
+}
+
+fn list<E: KvsEngine>(
+    engine: &E,
+    access: &Access,
+    query: &[(Vec<u8>, Vec<u8>)],
+) -> Result<Response> {
+    let limit = match query_param(query, "limit").map(parse_number) {
+        None => DEFAULT_PAGE_LEN,
+        Some(Some(limit)) => (limit as usize).min(MAX_PAGE_LEN),
+        Some(None) => return Ok(Response::bad_request("limit must be a number")),
+    };
+    let prefix = query_param(query, "prefix").unwrap_or_default().to_vec();
+    let start = query_param(query, "start").map(<[u8]>::to_vec);
+    let end = query_param(query, "end").map(<[u8]>::to_vec);
+
+    // the range is narrowed to the keys with the prefix
+    let start = match start {
+        Some(start) if start > prefix => Bound::Included(start),
+        _ => Bound::Included(prefix.clone()),
+    };
+    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+    let keys = engine
+        .scan_keys((start, end))?
+        .take_while(move |key| match key {
+            Ok(key) => key.starts_with(&prefix),
+            Err(_) => true,
+        });
+
+    let mut page = Page {
+        entries: Vec::new(),
+        next: None,
+    };
+    for key in keys {
+        let key = key?;
+        // the values of the keys skipped are not read
+        if !access.can_read(&key) {
+            continue;
+        }
+        if page.entries.len() == limit {
+            page.next = Some(percent_encode(&key));
+            break;
+        }
+        let value = match engine.get_bytes(key.clone())? {
+            Some(value) => value,
+            // removed since it was listed
+            None => continue,
+        };
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => page.entries.push(Entry { key, value }),
+            (Err(e), _) | (_, Err(e)) => {
+                let e = KvsError::Utf8(e);
+                return Ok(Response::error(406, e.kind(), &e.to_string()));
+            }
+        }
+    }
+    Ok(Response::json(200, &page))
+}
+
+fn not_found() -> Response {
+    Response::error(
+        404,
+        ErrorKind::KeyNotFound,
+        &KvsError::KeyNotFound.to_string(),
+    )
+}
+
+fn method_not_allowed(allow: &'static str) -> Response {
+    let mut response = Response::error(405, ErrorKind::Protocol, "Method not allowed");
+    response.headers.push(("Allow", allow));
+    response
+}
+
+fn query_param<'a>(query: &'a [(Vec<u8>, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    query
+        .iter()
+        .find(|(param, _)| param == name.as_bytes())
+        .map(|(_, value)| &value[..])
+}
+
+fn parse_number(s: &[u8]) -> Option<u64> {
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+// A request which cannot be read, with the response to close the connection with.
+struct RequestError(Response);
+
+impl From<KvsError> for RequestError {
+    fn from(err: KvsError) -> Self {
+        RequestError(Response::bad_request(&err.to_string()))
+    }
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(err: std::io::Error) -> Self {
+        if is_timeout(&err) {
+            return RequestError(Response::error(
+                408,
+                ErrorKind::Io,
+                "Timed out reading the request",
+            ));
+        }
+        KvsError::from(err).into()
+    }
+}
+
+// Reads the next request.
+//
+// Returns `Ok(None)` if the client closed the connection between two requests.
+fn read_request<R: BufRead>(reader: &mut R) -> std::result::Result<Option<Request>, RequestError> {
+    let mut head = Vec::new();
+    // the head ends with an empty line
+    loop {
+        let read = reader
+            .take((MAX_HEAD_LEN + 1 - head.len()) as u64)
+            .read_until(b'\n', &mut head)?;
+        if read == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(RequestError(Response::bad_request(
+                "Connection closed in the middle of a request",
+            )));
+        }
+        if head.len() > MAX_HEAD_LEN {
+            return Err(RequestError(Response::error(
+                431,
+                ErrorKind::Protocol,
+                "Request head too large",
+            )));
+        }
+        // empty lines before the request line are ignored, as RFC 7230 recommends
+        if head == b"\r\n" || head == b"\n" {
+            head.clear();
+            continue;
+        }
+        if head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(RequestError(Response::bad_request(
+                "Incomplete request head",
+            )))
+        }
+        Err(e) => return Err(RequestError(Response::bad_request(&e.to_string()))),
+    }
+    let method = parsed.method.unwrap_or_default().to_owned();
+    let target = parsed.path.unwrap_or_default();
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    let query = parse_query(query)
+        .ok_or_else(|| RequestError(Response::bad_request("Bad percent-encoding in the query")))?;
+
+    let mut keep_alive = parsed.version == Some(1);
+    let mut content_length = None;
+    let mut authorization = None;
+    for header in parsed.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            }
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            match value.trim().parse::<usize>() {
+                Ok(len) => content_length = Some(len),
+                Err(_) => return Err(RequestError(Response::bad_request("Bad Content-Length"))),
+            }
+        } else if header.name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(header.value.to_vec());
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(RequestError(Response::error(
+                501,
+                ErrorKind::Protocol,
+                "Transfer-Encoding is not supported, send Content-Length",
+            )));
+        }
+    }
+
+    let len = match (content_length, method.as_str()) {
+        (Some(len), _) => len,
+        (None, "PUT") => {
+            return Err(RequestError(Response::error(
+                411,
+                ErrorKind::Protocol,
+                "Content-Length required",
+            )))
+        }
+        (None, _) => 0,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(RequestError(Response::error(
+            413,
+            ErrorKind::Protocol,
+            "Request body too large",
+        )));
+    }
+    // the buffer only grows as the announced bytes actually arrive
+    let mut body = Vec::new();
+    if reader.take(len as u64).read_to_end(&mut body)? != len {
+        return Err(RequestError(Response::bad_request(
+            "Connection closed in the middle of a request",
+        )));
+    }
+
+    Ok(Some(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        body,
+        keep_alive,
+        authorization,
+    }))
+}
+
+fn parse_query(query: &str) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = match param.find('=') {
+                Some(i) => (&param[..i], &param[i + 1..]),
+                None => (param, ""),
+            };
+            Some((
+                percent_decode(name.as_bytes(), true)?,
+                percent_decode(value.as_bytes(), true)?,
+            ))
+        })
+        .collect()
+}
+
+// Escapes every byte but the unreserved characters of URIs as `%XX`.
+fn percent_encode(s: &[u8]) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &c in s {
+        if c.is_ascii_alphanumeric() || b"-._~".contains(&c) {
+            encoded.push(c as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", c));
+        }
+    }
+    encoded
+}
+
+// Decodes `%XX` escapes, and `+` as a space in a query.
+fn percent_decode(s: &[u8], query: bool) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.iter();
+    while let Some(&c) = bytes.next() {
+        match c {
+            b'%' => {
+                let hi = (*bytes.next()? as char).to_digit(16)?;
+                let lo = (*bytes.next()? as char).to_digit(16)?;
+                decoded.push((hi * 16 + lo) as u8);
+            }
+            b'+' if query => decoded.push(b' '),
+            c => decoded.push(c),
+        }
+    }
+    Some(decoded)
+}
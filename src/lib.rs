@@ -4,17 +4,41 @@
 #[macro_use]
 extern crate log;
 
-use failure::Error;
 use std::result;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use server::KvsServer;
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
+pub use auth::Credentials;
+pub use client::{ClientOptions, KvsClient, Pipeline};
+pub use engines::{
+    Durability, EngineStats, KeyIter, KvStore, KvStoreOptions, KvsEngine, RecoveryMode, ScanIter,
+    SledKvsEngine, WriteBatch,
+};
+pub use error::{ErrorKind, KvsError};
+pub use net::{ServerAddr, ToServerAddr};
+pub use protocol::ServerInfo;
+pub use server::{KvsServer, ShutdownHandle, DEFAULT_BACKLOG, DEFAULT_SHUTDOWN_TIMEOUT};
+pub use tls::{ClientTls, ServerTls};
 
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+mod auth;
 mod client;
 mod engines;
+mod error;
+mod http;
+mod metrics;
+mod net;
 mod protocol;
+mod replication;
+mod resp;
 mod server;
 pub mod thread_pool;
+mod tls;
 
-pub type Result<T> = result::Result<T, Error>;
+/// Result type for kvs.
+pub type Result<T> = result::Result<T, KvsError>;
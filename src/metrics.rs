@@ -0,0 +1,372 @@
+//! Metrics of a `KvsServer`, served at `GET /metrics` on the address given to
+//! `KvsServer::metrics_addr` in the Prometheus text format.
+//!
+//! | Metric | Type |
+//! | --- | --- |
+//! | `kvs_requests_total{type}` | counter of the operations run against the engine |
+//! | `kvs_request_errors_total{type}` | counter of those which failed, a missing key aside |
+//! | `kvs_request_duration_seconds{type}` | histogram of the time they took |
+//! | `kvs_active_connections` | gauge of the connections being served or queued |
+//! | `kvs_pool_queued_connections` | gauge of the connections waiting for a thread of the pool |
+//! | `kvs_engine_keys`, `kvs_engine_disk_bytes`, `kvs_engine_dead_entries` | gauges of `EngineStats` |
+//! | `kvs_engine_compactions_total` | counter of `EngineStats::compactions` |
+//!
+//! The types are `get`, `set`, `rm`, `cas`, `batch` and `scan`, whichever protocol the
+//! requests come in.
+
+use std::fmt::Write;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::engines::{EngineStats, KeyIter, ScanIter};
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+
+// upper bounds of the buckets of the latency histograms, in seconds
+const BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Clone, Copy)]
+enum Op {
+    Get,
+    Set,
+    Rm,
+    Cas,
+    Batch,
+    Scan,
+}
+
+const OPS: [Op; 6] = [Op::Get, Op::Set, Op::Rm, Op::Cas, Op::Batch, Op::Scan];
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Rm => "rm",
+            Op::Cas => "cas",
+            Op::Batch => "batch",
+            Op::Scan => "scan",
+        }
+    }
+}
+
+// The metrics of the requests of a type
+#[derive(Default)]
+struct OpMetrics {
+    count: AtomicU64,
+    errors: AtomicU64,
+    // the requests in each bucket, and slower than the last one
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl OpMetrics {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The metrics of a server, shared by its connections.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    ops: [OpMetrics; OPS.len()],
+    connections: AtomicI64,
+    queued: AtomicI64,
+}
+
+impl Metrics {
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Renders the metrics along with the stats of the engine.
+    pub(crate) fn render(&self, stats: &EngineStats) -> String {
+        // writing to a `String` cannot fail
+        let mut out = String::new();
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Operations run against the engine.",
+        );
+        for &op in &OPS {
+            let count = self.ops[op as usize].count.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "kvs_requests_total{{type=\"{}\"}} {}",
+                op.name(),
+                count
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "kvs_request_errors_total",
+            "counter",
+            "Operations which failed, a missing key aside.",
+        );
+        for &op in &OPS {
+            let errors = self.ops[op as usize].errors.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "kvs_request_errors_total{{type=\"{}\"}} {}",
+                op.name(),
+                errors
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time the operations took.",
+        );
+        for &op in &OPS {
+            let metrics = &self.ops[op as usize];
+            let mut count = 0;
+            for (i, bucket) in metrics.buckets.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let bound = BUCKETS.get(i).map_or("+Inf".to_owned(), f64::to_string);
+                writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    bound,
+                    count
+                )
+                .unwrap();
+            }
+            let sum = metrics.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{type=\"{}\"}} {}",
+                op.name(),
+                sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{type=\"{}\"}} {}",
+                op.name(),
+                count
+            )
+            .unwrap();
+        }
+
+        let gauges = [
+            (
+                "kvs_active_connections",
+                "Connections being served or waiting for a thread.",
+                Some(self.connections.load(Ordering::Relaxed).max(0) as u64),
+            ),
+            (
+                "kvs_pool_queued_connections",
+                "Connections waiting for a thread of the pool.",
+                Some(self.queued.load(Ordering::Relaxed).max(0) as u64),
+            ),
+            ("kvs_engine_keys", "Keys in the engine.", stats.keys),
+            (
+                "kvs_engine_disk_bytes",
+                "Size of the files of the engine.",
+                Some(stats.disk_bytes),
+            ),
+            (
+                "kvs_engine_dead_entries",
+                "Log entries made redundant since the last compaction started.",
+                stats.dead_entries,
+            ),
+        ];
+        for &(name, help, value) in &gauges {
+            if let Some(value) = value {
+                header(&mut out, name, "gauge", help);
+                writeln!(out, "{} {}", name, value).unwrap();
+            }
+        }
+        if let Some(compactions) = stats.compactions {
+            header(
+                &mut out,
+                "kvs_engine_compactions_total",
+                "counter",
+                "Compactions finished since the engine was opened.",
+            );
+            writeln!(out, "kvs_engine_compactions_total {}", compactions).unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+// An engine recording the count, failures and latency of each operation.
+#[derive(Clone)]
+pub(crate) struct MeteredEngine<E> {
+    engine: E,
+    metrics: Arc<Metrics>,
+}
+
+impl<E: KvsEngine> MeteredEngine<E> {
+    pub(crate) fn new(engine: E, metrics: Arc<Metrics>) -> Self {
+        MeteredEngine { engine, metrics }
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn measure<T>(&self, op: Op, run: impl FnOnce(&E) -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = run(&self.engine);
+        let failed = matches!(&result, Err(e) if !matches!(e, KvsError::KeyNotFound));
+        self.metrics.ops[op as usize].record(start.elapsed(), failed);
+        result
+    }
+
+    // A scan is measured until its iterator is dropped, as the entries are read meanwhile.
+    fn measure_scan<T: 'static>(
+        &self,
+        scan: impl FnOnce(&E) -> Result<Box<dyn Iterator<Item = Result<T>>>>,
+    ) -> Result<Box<dyn Iterator<Item = Result<T>>>> {
+        let start = Instant::now();
+        match scan(&self.engine) {
+            Ok(entries) => Ok(Box::new(MeteredScan {
+                entries,
+                metrics: self.metrics.clone(),
+                start,
+                failed: false,
+            })),
+            Err(e) => {
+                self.metrics.ops[Op::Scan as usize].record(start.elapsed(), true);
+                Err(e)
+            }
+        }
+    }
+}
+
+struct MeteredScan<T> {
+    entries: Box<dyn Iterator<Item = Result<T>>>,
+    metrics: Arc<Metrics>,
+    start: Instant,
+    failed: bool,
+}
+
+impl<T> Iterator for MeteredScan<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.entries.next();
+        self.failed |= matches!(entry, Some(Err(_)));
+        entry
+    }
+}
+
+impl<T> Drop for MeteredScan<T> {
+    fn drop(&mut self) {
+        self.metrics.ops[Op::Scan as usize].record(self.start.elapsed(), self.failed);
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for MeteredEngine<E> {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.measure(Op::Set, |engine| engine.set_bytes(key, value))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.measure(Op::Set, |engine| engine.set_bytes_with_ttl(key, value, ttl))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.measure(Op::Get, |engine| engine.get_bytes(key))
+    }
+
+    fn get_bytes_with_expiry(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        self.measure(Op::Get, |engine| engine.get_bytes_with_expiry(key))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.measure(Op::Rm, |engine| engine.remove_bytes(key))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.measure(Op::Cas, |engine| {
+            engine.compare_and_swap_bytes(key, expected, new)
+        })
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        self.measure(Op::Cas, |engine| {
+            engine.compare_and_swap_bytes_with_ttl(key, expected, new, ttl)
+        })
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.measure(Op::Batch, |engine| engine.apply_batch(batch))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        self.measure_scan(|engine| engine.scan_bytes(range))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        self.measure_scan(|engine| engine.scan_prefix_bytes(prefix))
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        self.measure_scan(|engine| engine.scan_keys(range))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+}
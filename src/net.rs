@@ -0,0 +1,412 @@
+//! Streams and listeners over TCP or Unix domain sockets, and TLS sessions over them.
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// prefix of the addresses of Unix domain sockets
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a server: TCP socket addresses, or the path of a Unix domain socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerAddr {
+    /// The addresses to try in turn.
+    Tcp(Vec<SocketAddr>),
+    /// The path of the socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addrs) => match addrs.first() {
+                Some(addr) => write!(f, "{}", addr),
+                None => write!(f, "no address"),
+            },
+            ServerAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Types which can be resolved to the address of a server.
+///
+/// It is implemented for the types of `ToSocketAddrs` which address a single host, and
+/// for strings of the form `unix:/path` naming a Unix domain socket.
+pub trait ToServerAddr {
+    /// Resolves to the address of a server.
+    fn to_server_addr(&self) -> io::Result<ServerAddr>;
+}
+
+impl ToServerAddr for ServerAddr {
+    fn to_server_addr(&self) -> io::Result<ServerAddr> {
+        Ok(self.clone())
+    }
+}
+
+impl ToServerAddr for str {
+    fn to_server_addr(&self) -> io::Result<ServerAddr> {
+        match self.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Ok(ServerAddr::Unix(path.into())),
+            None => tcp(self),
+        }
+    }
+}
+
+impl ToServerAddr for String {
+    fn to_server_addr(&self) -> io::Result<ServerAddr> {
+        self.as_str().to_server_addr()
+    }
+}
+
+impl<T: ToServerAddr + ?Sized> ToServerAddr for &T {
+    fn to_server_addr(&self) -> io::Result<ServerAddr> {
+        (**self).to_server_addr()
+    }
+}
+
+macro_rules! impl_tcp_addr {
+    ($($ty:ty),*) => {
+        $(
+            impl ToServerAddr for $ty {
+                fn to_server_addr(&self) -> io::Result<ServerAddr> {
+                    tcp(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_tcp_addr!(
+    SocketAddr,
+    (IpAddr, u16),
+    (Ipv4Addr, u16),
+    (Ipv6Addr, u16),
+    (&str, u16),
+    (String, u16)
+);
+
+fn tcp<A: ToSocketAddrs + ?Sized>(addr: &A) -> io::Result<ServerAddr> {
+    Ok(ServerAddr::Tcp(addr.to_socket_addrs()?.collect()))
+}
+
+#[cfg(not(unix))]
+fn no_unix_sockets() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+// A connection over TCP or a Unix domain socket, possibly with TLS.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // The session is shared by the clones of the stream, which are never read from and
+    // written to at the same time. The socket is for what does not go through the
+    // session, so that it does not wait for a read to finish.
+    Tls(Arc<Mutex<TlsSession>>, Box<Stream>),
+}
+
+#[derive(Debug)]
+pub(crate) enum TlsSession {
+    Client(StreamOwned<ClientConnection, Stream>),
+    Server(StreamOwned<ServerConnection, Stream>),
+}
+
+impl TlsSession {
+    // Tells the peer that nothing more is sent. A session still in its handshake is left
+    // as it is, as flushing it would wait for the peer.
+    fn close(&mut self) {
+        let handshaking = match self {
+            TlsSession::Client(stream) => stream.conn.is_handshaking(),
+            TlsSession::Server(stream) => stream.conn.is_handshaking(),
+        };
+        if handshaking {
+            return;
+        }
+        match self {
+            TlsSession::Client(stream) => stream.conn.send_close_notify(),
+            TlsSession::Server(stream) => stream.conn.send_close_notify(),
+        }
+        let _ = self.flush();
+    }
+}
+
+// the peer can tell a closed session from a connection cut short
+impl Drop for TlsSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Read for TlsSession {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsSession::Client(stream) => stream.read(buf),
+            TlsSession::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for TlsSession {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsSession::Client(stream) => stream.write(buf),
+            TlsSession::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsSession::Client(stream) => stream.flush(),
+            TlsSession::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Stream {
+    pub(crate) fn connect(addr: &ServerAddr) -> io::Result<Stream> {
+        match addr {
+            ServerAddr::Tcp(addrs) => Ok(Stream::Tcp(TcpStream::connect(&addrs[..])?)),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            ServerAddr::Unix(_) => Err(no_unix_sockets()),
+        }
+    }
+
+    // Connects, giving up after `timeout` for TCP.
+    pub(crate) fn connect_timeout(addr: &ServerAddr, timeout: Duration) -> io::Result<Stream> {
+        match addr {
+            ServerAddr::Tcp(addrs) => {
+                let mut last_err = None;
+                for addr in addrs {
+                    match TcpStream::connect_timeout(addr, timeout) {
+                        Ok(stream) => return Ok(Stream::Tcp(stream)),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.unwrap_or_else(no_address))
+            }
+            _ => Stream::connect(addr),
+        }
+    }
+
+    // Starts the TLS session of a client over the stream.
+    pub(crate) fn into_tls_client(self, conn: ClientConnection) -> io::Result<Stream> {
+        let socket = Box::new(self.try_clone()?);
+        let session = TlsSession::Client(StreamOwned::new(conn, self));
+        Ok(Stream::Tls(Arc::new(Mutex::new(session)), socket))
+    }
+
+    // Starts the TLS session of a server over the stream.
+    pub(crate) fn into_tls_server(self, conn: ServerConnection) -> io::Result<Stream> {
+        let socket = Box::new(self.try_clone()?);
+        let session = TlsSession::Server(StreamOwned::new(conn, self));
+        Ok(Stream::Tls(Arc::new(Mutex::new(session)), socket))
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Tls(session, socket) => {
+                Ok(Stream::Tls(session.clone(), Box::new(socket.try_clone()?)))
+            }
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::Tls(session, socket) => {
+                // the session is busy if a read is waiting
+                if how != Shutdown::Read {
+                    if let Ok(mut session) = session.try_lock() {
+                        session.close();
+                    }
+                }
+                socket.shutdown(how)
+            }
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(_, socket) => socket.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(_, socket) => socket.set_write_timeout(timeout),
+        }
+    }
+
+    // Describes the other end, for the logs.
+    pub(crate) fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "a disconnected TCP client".to_owned(),
+            },
+            #[cfg(unix)]
+            Stream::Unix(_) => "a Unix socket client".to_owned(),
+            Stream::Tls(_, socket) => socket.peer(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(session, _) => session.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(session, _) => session.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(session, _) => session.lock().unwrap().flush(),
+        }
+    }
+}
+
+// A listener over TCP or a Unix domain socket. The socket file of a Unix listener is
+// removed when it is dropped.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // Binds to the first address which works, with a backlog of `backlog` connections.
+    //
+    // A socket file left behind by a server which is gone is replaced.
+    pub(crate) fn bind(addr: &ServerAddr, backlog: u32) -> io::Result<Listener> {
+        let backlog = backlog.min(i32::MAX as u32) as i32;
+        match addr {
+            ServerAddr::Tcp(addrs) => {
+                let mut last_err = None;
+                for &addr in addrs {
+                    match bind_tcp(addr, backlog) {
+                        Ok(listener) => return Ok(Listener::Tcp(listener)),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.unwrap_or_else(no_address))
+            }
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => {
+                let is_socket = std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket());
+                if is_socket && UnixStream::connect(path).is_err() {
+                    debug!("Removing the stale socket {}", path.display());
+                    std::fs::remove_file(path)?;
+                }
+                let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+                socket.bind(&SockAddr::unix(path)?)?;
+                socket.listen(backlog)?;
+                let listener = UnixListener::from(OwnedFd::from(socket));
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ServerAddr::Unix(_) => Err(no_unix_sockets()),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    // The address to connect to, which is a loopback one for a TCP listener on all
+    // interfaces.
+    pub(crate) fn local_addr(&self) -> io::Result<ServerAddr> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut addr = listener.local_addr()?;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Ok(ServerAddr::Tcp(vec![addr]))
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(ServerAddr::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = self {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // as `TcpListener::bind` does, so that a restarted server can bind the address again
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+fn no_address() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+    )
+}
@@ -0,0 +1,200 @@
+//! The JSON protocol of earlier versions, kept while clients migrate to the binary one.
+//!
+//! Requests and responses are JSON objects following each other on the stream, with
+//! keys and values as strings. A value which is not valid UTF-8 cannot be sent, so
+//! reading it is an error.
+
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::time::Duration;
+
+use super::{Request, Response, ScanRange};
+use crate::engines::bytes_bound;
+use crate::WriteBatch;
+
+// the longest request accepted, so that a request which never ends cannot take up memory
+// without limit
+pub(crate) const MAX_LEGACY_REQUEST_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub enum JsonRequest {
+    Set {
+        key: String,
+        value: String,
+    },
+    SetWithTtl {
+        key: String,
+        value: String,
+        ttl: Duration,
+    },
+    Rm {
+        key: String,
+    },
+    Get {
+        key: String,
+    },
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    Scan(JsonScanRange),
+    Batch(JsonWriteBatch),
+}
+
+#[derive(Deserialize, Debug)]
+pub enum JsonScanRange {
+    Range(Bound<String>, Bound<String>),
+    Prefix(String),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JsonWriteBatch {
+    ops: Vec<JsonBatchOp>,
+}
+
+#[derive(Deserialize, Debug)]
+enum JsonBatchOp {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+#[derive(Serialize, Debug)]
+pub enum JsonResponse {
+    Ok(Option<String>),
+    Entry(String, String),
+    Swapped(bool),
+    Err(String),
+}
+
+impl From<JsonRequest> for Request {
+    fn from(request: JsonRequest) -> Self {
+        match request {
+            JsonRequest::Set { key, value } => Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            },
+            JsonRequest::SetWithTtl { key, value, ttl } => Request::SetWithTtl {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl,
+            },
+            JsonRequest::Rm { key } => Request::Rm {
+                key: key.into_bytes(),
+            },
+            JsonRequest::Get { key } => Request::Get {
+                key: key.into_bytes(),
+            },
+            JsonRequest::Cas { key, expected, new } => Request::Cas {
+                key: key.into_bytes(),
+                expected: expected.map(String::into_bytes),
+                new: new.map(String::into_bytes),
+            },
+            JsonRequest::Scan(JsonScanRange::Range(start, end)) => {
+                Request::Scan(ScanRange::Range(bytes_bound(start), bytes_bound(end)))
+            }
+            JsonRequest::Scan(JsonScanRange::Prefix(prefix)) => {
+                Request::Scan(ScanRange::Prefix(prefix.into_bytes()))
+            }
+            JsonRequest::Batch(json_batch) => {
+                let mut batch = WriteBatch::new();
+                for op in json_batch.ops {
+                    match op {
+                        JsonBatchOp::Set { key, value } => batch.set(key, value),
+                        JsonBatchOp::Rm { key } => batch.remove(key),
+                    }
+                }
+                Request::Batch(batch)
+            }
+        }
+    }
+}
+
+impl From<Response> for JsonResponse {
+    fn from(response: Response) -> Self {
+        let result = match response {
+            Response::Ok(value) => value
+                .map(String::from_utf8)
+                .transpose()
+                .map(JsonResponse::Ok),
+            Response::Entry(key, value) => String::from_utf8(key)
+                .and_then(|key| Ok(JsonResponse::Entry(key, String::from_utf8(value)?))),
+            Response::Swapped(swapped) => Ok(JsonResponse::Swapped(swapped)),
+            Response::Err(_, msg) => Ok(JsonResponse::Err(msg)),
+            Response::Stats(_) | Response::Info(_) => {
+                unreachable!("the legacy protocol has no admin requests")
+            }
+        };
+        result.unwrap_or_else(|e| JsonResponse::Err(e.to_string()))
+    }
+}
+
+/// Converts the responses to a request one at a time. A scan stops with an error at the
+/// first key or value which is not a string.
+#[derive(Default)]
+pub struct JsonResponses {
+    failed: bool,
+}
+
+impl JsonResponses {
+    /// Converts a response, or returns `None` for those coming after an error.
+    pub fn convert(&mut self, response: Response) -> Option<JsonResponse> {
+        if self.failed {
+            return None;
+        }
+        let response = JsonResponse::from(response);
+        self.failed = matches!(response, JsonResponse::Err(_));
+        Some(response)
+    }
+}
+
+// Finds where the JSON values arriving on a connection end, going on from where the last
+// call stopped, so that a request arriving in many reads is scanned only once.
+#[derive(Default)]
+pub(crate) struct JsonSplitter {
+    // bytes scanned at the start of the buffer
+    scanned: usize,
+    // nesting of objects and arrays
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonSplitter {
+    // Returns the length of the first complete value at the start of `buf`, which is then
+    // expected to be removed from it.
+    pub(crate) fn next_value(&mut self, buf: &[u8]) -> Option<usize> {
+        while self.scanned < buf.len() {
+            let c = buf[self.scanned];
+            self.scanned += 1;
+            if self.in_string {
+                match (self.escaped, c) {
+                    (true, _) => self.escaped = false,
+                    (false, b'\\') => self.escaped = true,
+                    (false, b'"') => self.in_string = false,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    // an unbalanced bracket ends the value, which fails to deserialize
+                    b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                    c if c.is_ascii_whitespace() => continue,
+                    // no request is a bare number or literal, which fails to deserialize
+                    _ if self.depth == 0 => return Some(self.take(buf.len())),
+                    _ => {}
+                }
+            }
+            if !self.in_string && self.depth == 0 {
+                return Some(self.take(self.scanned));
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: usize) -> usize {
+        *self = JsonSplitter::default();
+        len
+    }
+}
@@ -0,0 +1,350 @@
+//! Messages exchanged between `KvsClient` and `KvsServer`.
+//!
+//! A connection starts with the client sending the magic bytes `KVSP` and a `Hello`
+//! listing the protocol versions and capabilities it supports. The server answers with a
+//! `HelloReply` picking the version and the capabilities both sides have, or rejecting the
+//! connection. If both sides have the `auth` capability, the client then sends an `Auth`
+//! with its credentials, which the server answers with `Response::Ok(None)`, or with
+//! `Response::Err` before closing the connection.
+//!
+//! Every message, including the handshake, is framed as
+//!
+//! ```text
+//! | payload length: u32 | payload |
+//! ```
+//!
+//! with the length in little-endian and the payload encoded with bincode, so keys and
+//! values are carried as raw bytes. A frame which cannot be decoded is skipped without
+//! losing track of the next ones.
+//!
+//! From version 2 on, the payload of requests and responses starts with the ID of the
+//! request as a little-endian `u64`, so that a client can send many requests before
+//! reading the responses and still match them up. The server answers requests in the
+//! order they arrive.
+//!
+//! Clients sending JSON instead of the magic bytes are served with the legacy protocol
+//! of `legacy`.
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*};
+use std::ops::Bound;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{EngineStats, ErrorKind, KvsError, Result, WriteBatch};
+
+pub mod legacy;
+
+/// Magic bytes starting a connection with the binary protocol.
+pub const MAGIC: &[u8; 4] = b"KVSP";
+/// Oldest protocol version this build speaks.
+pub const MIN_VERSION: u32 = 1;
+/// Newest protocol version this build speaks.
+pub const MAX_VERSION: u32 = 2;
+/// First protocol version in which requests and responses carry a request ID.
+pub const VERSION_REQUEST_IDS: u32 = 2;
+
+/// Keys with a time to live, `Request::SetWithTtl`.
+pub const CAP_TTL: &str = "ttl";
+/// `Request::Cas`.
+pub const CAP_CAS: &str = "cas";
+/// `Request::Batch`.
+pub const CAP_BATCH: &str = "batch";
+/// `Request::Scan`.
+pub const CAP_SCAN: &str = "scan";
+/// `Auth` after the handshake.
+pub const CAP_AUTH: &str = "auth";
+/// `Request::Stats`, `Request::Compact`, `Request::Flush` and `Request::Info`.
+pub const CAP_ADMIN: &str = "admin";
+/// `Request::Replicate` and `Request::Promote`.
+pub const CAP_REPLICATION: &str = "replication";
+/// Capabilities this build supports.
+pub const CAPABILITIES: &[&str] = &[
+    CAP_TTL,
+    CAP_CAS,
+    CAP_BATCH,
+    CAP_SCAN,
+    CAP_AUTH,
+    CAP_ADMIN,
+    CAP_REPLICATION,
+];
+
+// the largest message accepted, so that a bogus length cannot make the peer allocate
+// without limit
+const MAX_MESSAGE_LEN: u64 = 64 * 1024 * 1024;
+
+/// First message of a connection, sent by the client.
+///
+/// Its encoding must never change, so that any two versions can at least agree that
+/// they cannot talk to each other.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// Answer of the server to a `Hello`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelloReply {
+    /// The version used for the rest of the connection, and the capabilities of the
+    /// `Hello` which the server supports.
+    Accept {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// The server closes the connection for the given reason.
+    Reject(String),
+}
+
+impl Hello {
+    /// The `Hello` of this build, without `CAP_AUTH` which only a client with credentials
+    /// asks for.
+    pub fn new() -> Self {
+        Hello {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            capabilities: CAPABILITIES
+                .iter()
+                .filter(|&&cap| cap != CAP_AUTH)
+                .map(|&cap| cap.to_owned())
+                .collect(),
+        }
+    }
+
+    /// Answers the `Hello` of a client with the newest version and the capabilities both
+    /// sides support, but those in `without`.
+    pub fn reply(&self, without: &[&str]) -> HelloReply {
+        let version = self.max_version.min(MAX_VERSION);
+        if version < self.min_version.max(MIN_VERSION) {
+            return HelloReply::Reject(format!(
+                "No common protocol version: the client speaks {} to {}, the server {} to {}",
+                self.min_version, self.max_version, MIN_VERSION, MAX_VERSION
+            ));
+        }
+        let capabilities = self
+            .capabilities
+            .iter()
+            .filter(|cap| CAPABILITIES.contains(&cap.as_str()) && !without.contains(&cap.as_str()))
+            .cloned()
+            .collect();
+        HelloReply::Accept {
+            version,
+            capabilities,
+        }
+    }
+}
+
+/// Credentials of a principal, sent after the handshake if both sides have `CAP_AUTH`.
+///
+/// Not `Debug`, so that the secret cannot end up in a log.
+#[derive(Serialize, Deserialize)]
+pub struct Auth {
+    pub name: String,
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Rm {
+        key: Vec<u8>,
+    },
+    Get {
+        key: Vec<u8>,
+    },
+    Cas {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Scan(ScanRange),
+    Batch(WriteBatch),
+    /// Answered with `Response::Stats`.
+    Stats,
+    /// Compacts the engine now.
+    Compact,
+    /// Makes every write so far durable.
+    Flush,
+    /// Answered with `Response::Info`.
+    Info,
+    /// Answered with `Response::Ok(None)`, after which the connection carries the
+    /// `Replicated` messages of the server. See `replication`.
+    Replicate,
+    /// Makes a follower take writes from clients, and stop following its leader.
+    Promote,
+}
+
+/// What a server sends a follower after accepting its `Request::Replicate`: an `Entry`
+/// for each key of its engine, in key order, then `EndOfSnapshot`, then the writes it
+/// applies from then on.
+///
+/// Expiry times are in milliseconds since the Unix epoch, so that a key expires at the same
+/// time on every server, however late it is copied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Replicated {
+    Entry {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    },
+    EndOfSnapshot,
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    },
+    Rm {
+        key: Vec<u8>,
+    },
+    Batch(WriteBatch),
+}
+
+/// Keys selected by a `Request::Scan`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ScanRange {
+    Range(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    Prefix(Vec<u8>),
+}
+
+/// The server answers a `Request::Scan` with an `Entry` for each key found, in key order,
+/// followed by `Ok(None)`, or by `Err` if reading the keys fails midway. The entries are
+/// sent as they are read.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Ok(Option<Vec<u8>>),
+    Entry(Vec<u8>, Vec<u8>),
+    Swapped(bool),
+    Err(ErrorKind, String),
+    Stats(EngineStats),
+    Info(ServerInfo),
+}
+
+/// What a server tells about itself.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ServerInfo {
+    /// Version of the server build.
+    pub version: String,
+    /// Name of the engine, as given to `kvs-server --engine`.
+    pub engine: String,
+    /// Time since the server started.
+    pub uptime: Duration,
+    /// Address of the leader, if the server is a follower.
+    pub leader: Option<String>,
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_LEN)
+}
+
+/// Writes a message in its frame, leaving the flushing to the caller.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    write_frame(writer, &options().serialize(message)?)
+}
+
+/// Writes a message in its frame after the ID of the request it belongs to, leaving the
+/// flushing to the caller.
+pub fn write_tagged<W: Write, T: Serialize>(writer: &mut W, id: u64, message: &T) -> Result<()> {
+    let mut payload = id.to_le_bytes().to_vec();
+    options().serialize_into(&mut payload, message)?;
+    write_frame(writer, &payload)
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Splits the payload of a frame written by `write_tagged` into the request ID and the
+/// message.
+pub fn split_id(payload: &[u8]) -> Result<(u64, &[u8])> {
+    if payload.len() < 8 {
+        return Err(KvsError::Protocol("Message without request ID".to_owned()));
+    }
+    let (id, message) = payload.split_at(8);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(id);
+    Ok((u64::from_le_bytes(bytes), message))
+}
+
+/// Reads the payload of the next frame.
+///
+/// Returns `Ok(None)` if the peer closed the connection between two frames.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let read = loop {
+        match reader.read(&mut len) {
+            Ok(read) => break read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[read..])?;
+    let len = u32::from_le_bytes(len) as u64;
+    if len > MAX_MESSAGE_LEN {
+        return Err(KvsError::Protocol(format!(
+            "Message of {} bytes is too long",
+            len
+        )));
+    }
+    let mut payload = Vec::new();
+    if reader.take(len).read_to_end(&mut payload)? as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(payload))
+}
+
+/// Like `read_frame`, from an asynchronous reader.
+#[cfg(feature = "async")]
+pub async fn read_frame_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let read = reader.read(&mut len).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[read..]).await?;
+    let len = u32::from_le_bytes(len) as u64;
+    if len > MAX_MESSAGE_LEN {
+        return Err(KvsError::Protocol(format!(
+            "Message of {} bytes is too long",
+            len
+        )));
+    }
+    let mut payload = Vec::new();
+    if reader.take(len).read_to_end(&mut payload).await? as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(payload))
+}
+
+/// Decodes the payload of a frame. A message which cannot be decoded is a protocol error.
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    options()
+        .deserialize(payload)
+        .map_err(|e| KvsError::Protocol(e.to_string()))
+}
+
+/// Reads the next message.
+///
+/// Returns `Ok(None)` if the peer closed the connection between two messages.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    read_frame(reader)?
+        .map(|payload| decode(&payload))
+        .transpose()
+}
@@ -0,0 +1,585 @@
+//! Leader-follower replication between `KvsServer`s.
+//!
+//! A follower connects to its leader with the binary protocol and sends
+//! `Request::Replicate`. The leader accepts it with `Response::Ok(None)`, then sends a
+//! snapshot of its engine, a `Replicated::Entry` for each key followed by
+//! `Replicated::EndOfSnapshot`, then each write it applies from then on as a
+//! `Replicated::Set`, `Replicated::Rm` or `Replicated::Batch`, all tagged with the ID of the
+//! `Replicate`. A compare-and-swap is sent as the write it turned into, if any. Keys carry
+//! their expiry time, so that they expire on the followers when they do on the leader.
+//!
+//! The writes only pay for the followers while there are any. Then the writes to the keys
+//! of a stripe are applied and queued one at a time, so that the followers apply the
+//! writes of each key in the order the leader did, while the writes to other keys commit
+//! together. A follower is added between writes, and its snapshot is read from the engine
+//! as it is sent: a write made meanwhile is queued whether the snapshot has it or not, and
+//! applying the queue after the snapshot gives the follower the same keys as its leader. A
+//! follower which falls too far behind is dropped, and starts over from a new snapshot
+//! like after any lost connection.
+//!
+//! A follower refuses the writes of clients with `KvsError::ReadOnly` until
+//! `Request::Promote` makes it a leader. The writes it applies for its leader are sent on
+//! to its own followers.
+
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::iter;
+use std::mem;
+use std::net::Shutdown;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::engines::expiry;
+use crate::engines::{EngineStats, KeyIter, ScanIter};
+use crate::net::{ServerAddr, Stream};
+use crate::protocol::{write_message, write_tagged, Replicated, Response};
+use crate::{ClientOptions, KvsClient, KvsEngine, KvsError, Result, WriteBatch};
+
+// how many writes can wait to be sent to a follower before it is dropped
+const FOLLOWER_QUEUE_LEN: usize = 64 * 1024;
+
+// how long a follower waits before connecting to its leader again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// how many locks the keys are spread over while there are followers
+const STRIPES: usize = 64;
+
+// The replication state of a server, shared by its connections.
+pub(crate) struct Replication {
+    // held shared by every write, and exclusively while a follower is added
+    gate: RwLock<()>,
+    // whether there are followers to send the writes to
+    replicating: AtomicBool,
+    // held by the writes to the keys of a stripe while there are followers
+    stripes: Vec<Mutex<()>>,
+    // the queues of the followers
+    followers: Mutex<Vec<SyncSender<Replicated>>>,
+    state: Mutex<FollowerState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct FollowerState {
+    // set while following a leader
+    leader: Option<ServerAddr>,
+    // the connection to the leader, shut down to stop following it
+    connection: Option<Stream>,
+    closed: bool,
+}
+
+// A write in progress, which holds the stripes of its keys if there are followers.
+struct PendingWrite<'a> {
+    replication: &'a Replication,
+    // released before the gate
+    stripes: Option<Vec<MutexGuard<'a, ()>>>,
+    _gate: RwLockReadGuard<'a, ()>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            gate: RwLock::default(),
+            replicating: AtomicBool::new(false),
+            stripes: (0..STRIPES).map(|_| Mutex::default()).collect(),
+            followers: Mutex::default(),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl Replication {
+    // The address of the leader, if the server is a follower.
+    pub(crate) fn leader(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.leader.as_ref().map(ServerAddr::to_string)
+    }
+
+    // Makes a follower a leader.
+    pub(crate) fn promote(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(leader) = state.leader.take() {
+            info!("Promoted to leader, no longer following {}", leader);
+            state.stop_following();
+            self.changed.notify_all();
+        }
+    }
+
+    // Stops following the leader and replicating to the followers, once the server is
+    // shutting down. The followers are sent the writes queued so far.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.stop_following();
+        self.changed.notify_all();
+        drop(state);
+        let mut followers = self.followers.lock().unwrap();
+        followers.clear();
+        self.replicating.store(false, Ordering::SeqCst);
+    }
+
+    // Registers a follower, returning the queue of the writes it is sent. Every write which
+    // has not finished yet is queued.
+    fn subscribe(&self) -> Receiver<Replicated> {
+        // waits for the writes in progress, which do not queue themselves
+        let _gate = self.gate.write().unwrap();
+        let (sender, receiver) = mpsc::sync_channel(FOLLOWER_QUEUE_LEN);
+        self.followers.lock().unwrap().push(sender);
+        self.replicating.store(true, Ordering::SeqCst);
+        receiver
+    }
+
+    // Starts a write to `keys`, which are only looked at if there are followers.
+    fn begin_write<'k>(&self, keys: impl IntoIterator<Item = &'k [u8]>) -> PendingWrite<'_> {
+        let gate = self.gate.read().unwrap();
+        let stripes = if self.replicating.load(Ordering::SeqCst) {
+            let mut stripes: Vec<usize> = keys.into_iter().map(stripe).collect();
+            // locked in order, so that two batches never wait for each other
+            stripes.sort_unstable();
+            stripes.dedup();
+            Some(
+                stripes
+                    .into_iter()
+                    .map(|i| self.stripes[i].lock().unwrap())
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        PendingWrite {
+            replication: self,
+            stripes,
+            _gate: gate,
+        }
+    }
+
+    // Queues a write for the followers, dropping those which are too far behind.
+    fn send(&self, message: Replicated) {
+        let mut followers = self.followers.lock().unwrap();
+        followers.retain(|follower| match follower.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Dropping a follower more than {} writes behind",
+                    FOLLOWER_QUEUE_LEN
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        if followers.is_empty() {
+            self.replicating.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // Starts following the leader at `addr`.
+    fn follow(&self, addr: ServerAddr) {
+        self.state.lock().unwrap().leader = Some(addr);
+    }
+
+    fn is_following(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.leader.is_some() && !state.closed
+    }
+
+    // Keeps the connection to the leader to shut it down when the server stops following
+    // it. Returns `false` if it has stopped already.
+    fn set_connection(&self, connection: Stream) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.leader.is_none() || state.closed {
+            return false;
+        }
+        state.connection = Some(connection);
+        true
+    }
+
+    // Waits for `timeout`, or until the server stops following its leader.
+    fn wait(&self, timeout: Duration) {
+        let state = self.state.lock().unwrap();
+        if state.leader.is_some() && !state.closed {
+            let _ = self.changed.wait_timeout(state, timeout).unwrap();
+        }
+    }
+}
+
+impl FollowerState {
+    fn stop_following(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl PendingWrite<'_> {
+    // Whether the write is to be sent to followers, so that its message is only built if
+    // needed.
+    fn replicating(&self) -> bool {
+        self.stripes.is_some()
+    }
+
+    // Ends a write applied to the engine, queueing `message` for the followers while the
+    // stripes are still held.
+    fn finish(self, message: Option<Replicated>) {
+        if let Some(message) = message {
+            self.replication.send(message);
+        }
+    }
+}
+
+fn stripe(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % STRIPES as u64) as usize
+}
+
+// An engine which sends its writes to the followers, or refuses the writes of clients on
+// a follower.
+#[derive(Clone)]
+pub(crate) struct ReplicatedEngine<E> {
+    engine: E,
+    replication: Arc<Replication>,
+    // applies the writes of the leader, which a follower does not refuse
+    from_leader: bool,
+}
+
+impl<E: KvsEngine> ReplicatedEngine<E> {
+    pub(crate) fn new(engine: E, replication: Arc<Replication>) -> Self {
+        ReplicatedEngine {
+            engine,
+            replication,
+            from_leader: false,
+        }
+    }
+
+    fn begin_write<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) -> Result<PendingWrite<'_>> {
+        if !self.from_leader {
+            if let Some(leader) = self.replication.leader() {
+                return Err(KvsError::ReadOnly(leader));
+            }
+        }
+        Ok(self.replication.begin_write(keys))
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReplicatedEngine<E> {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let write = self.begin_write(iter::once(&key[..]))?;
+        let message = write.replicating().then(|| Replicated::Set {
+            key: key.clone(),
+            value: value.clone(),
+            expire_at: None,
+        });
+        self.engine.set_bytes(key, value)?;
+        write.finish(message);
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let write = self.begin_write(iter::once(&key[..]))?;
+        let message = write.replicating().then(|| Replicated::Set {
+            key: key.clone(),
+            value: value.clone(),
+            expire_at: Some(expiry::expire_at(ttl)),
+        });
+        self.engine.set_bytes_with_ttl(key, value, ttl)?;
+        write.finish(message);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(key)
+    }
+
+    fn get_bytes_with_expiry(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        self.engine.get_bytes_with_expiry(key)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let write = self.begin_write(iter::once(&key[..]))?;
+        let message = write
+            .replicating()
+            .then(|| Replicated::Rm { key: key.clone() });
+        self.engine.remove_bytes(key)?;
+        write.finish(message);
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let write = self.begin_write(iter::once(&key[..]))?;
+        let message = write.replicating().then(|| match &new {
+            Some(value) => Replicated::Set {
+                key: key.clone(),
+                value: value.clone(),
+                expire_at: None,
+            },
+            None => Replicated::Rm { key: key.clone() },
+        });
+        let swapped = self.engine.compare_and_swap_bytes(key, expected, new)?;
+        write.finish(message.filter(|_| swapped));
+        Ok(swapped)
+    }
+
+    fn compare_and_swap_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool> {
+        let write = self.begin_write(iter::once(&key[..]))?;
+        let message = write.replicating().then(|| Replicated::Set {
+            key: key.clone(),
+            value: new.clone(),
+            expire_at: Some(expiry::expire_at(ttl)),
+        });
+        let swapped = self
+            .engine
+            .compare_and_swap_bytes_with_ttl(key, expected, new, ttl)?;
+        write.finish(message.filter(|_| swapped));
+        Ok(swapped)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let write = self.begin_write(batch.keys())?;
+        let message = write
+            .replicating()
+            .then(|| Replicated::Batch(batch.clone()));
+        self.engine.apply_batch(batch)?;
+        write.finish(message);
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        self.engine.scan_bytes(range)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        self.engine.scan_prefix_bytes(prefix)
+    }
+
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KeyIter> {
+        self.engine.scan_keys(range)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+}
+
+// Accepts a `Request::Replicate`, then sends the snapshot of `engine` and the writes of
+// the server. The follower is served from a thread of its own, so that it does not hold
+// up a thread of the pool for as long as it follows.
+pub(crate) fn serve_follower<E: KvsEngine>(
+    engine: &E,
+    replication: &Replication,
+    mut writer: BufWriter<Stream>,
+    peer_addr: String,
+    // the ID of the `Replicate`, from protocol version 2 on
+    id: Option<u64>,
+) -> Result<()> {
+    let writes = replication.subscribe();
+    send(&mut writer, id, &Response::Ok(None))?;
+    info!("Replicating to {}", peer_addr);
+    let engine = engine.clone();
+    thread::Builder::new()
+        .name("follower".to_owned())
+        .spawn(move || {
+            if let Err(e) = send_to_follower(&engine, writer, id, &peer_addr, writes) {
+                warn!("Stopped replicating to {}: {}", peer_addr, e);
+            }
+        })?;
+    Ok(())
+}
+
+fn send_to_follower<E: KvsEngine>(
+    engine: &E,
+    mut writer: BufWriter<Stream>,
+    id: Option<u64>,
+    peer_addr: &str,
+    writes: Receiver<Replicated>,
+) -> Result<()> {
+    let mut keys = 0;
+    for key in engine.scan_keys(..)? {
+        let key = key?;
+        // the value is read with its expiry time, unless the key is gone by now
+        if let Some((value, expire_at)) = engine.get_bytes_with_expiry(key.clone())? {
+            send(
+                &mut writer,
+                id,
+                &Replicated::Entry {
+                    key,
+                    value,
+                    expire_at,
+                },
+            )?;
+            keys += 1;
+        }
+    }
+    send(&mut writer, id, &Replicated::EndOfSnapshot)?;
+    writer.flush()?;
+    info!("Sent a snapshot of {} keys to {}", keys, peer_addr);
+    // until the server closes the queue
+    while let Ok(message) = writes.recv() {
+        send(&mut writer, id, &message)?;
+        // the writes already queued go out together
+        while let Ok(message) = writes.try_recv() {
+            send(&mut writer, id, &message)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn send<T: Serialize>(writer: &mut BufWriter<Stream>, id: Option<u64>, message: &T) -> Result<()> {
+    match id {
+        Some(id) => write_tagged(writer, id, message),
+        None => write_message(writer, message),
+    }
+}
+
+// Follows the leader at `addr` from a thread of its own, until the server is promoted or
+// closes the replication. The connection to the leader is opened again whenever it drops.
+pub(crate) fn follow<E: KvsEngine>(
+    engine: ReplicatedEngine<E>,
+    addr: ServerAddr,
+    options: Arc<ClientOptions>,
+) -> Result<JoinHandle<()>> {
+    let replication = engine.replication.clone();
+    replication.follow(addr.clone());
+    let engine = ReplicatedEngine {
+        from_leader: true,
+        ..engine
+    };
+    Ok(thread::Builder::new()
+        .name("leader".to_owned())
+        .spawn(move || {
+            while replication.is_following() {
+                match follow_once(&engine, &addr, &options) {
+                    Ok(()) => info!("The leader {} closed the connection", addr),
+                    Err(e) if replication.is_following() => {
+                        warn!("Lost the leader {}: {}", addr, e)
+                    }
+                    Err(_) => {}
+                }
+                replication.wait(RECONNECT_DELAY);
+            }
+        })?)
+}
+
+// Copies the snapshot of the leader, then applies its writes until the connection drops.
+fn follow_once<E: KvsEngine>(
+    engine: &ReplicatedEngine<E>,
+    addr: &ServerAddr,
+    options: &ClientOptions,
+) -> Result<()> {
+    let mut leader = KvsClient::reconnect(addr.clone(), options)?;
+    if !engine
+        .replication
+        .set_connection(leader.try_clone_stream()?)
+    {
+        return Ok(());
+    }
+    leader.replicate()?;
+    // the snapshot comes in key order, so the keys between two of its entries are those
+    // the leader has removed since this server last followed it
+    let mut last = Bound::Unbounded;
+    let mut keys = 0;
+    loop {
+        match leader.next_replicated()? {
+            Some(Replicated::Entry {
+                key,
+                value,
+                expire_at,
+            }) => {
+                let start = mem::replace(&mut last, Bound::Excluded(key.clone()));
+                remove_range(engine, (start, Bound::Excluded(key.clone())))?;
+                set(engine, key, value, expire_at)?;
+                keys += 1;
+            }
+            Some(Replicated::EndOfSnapshot) => {
+                remove_range(engine, (last, Bound::Unbounded))?;
+                break;
+            }
+            Some(message) => return Err(unexpected(message)),
+            None => return Ok(()),
+        }
+    }
+    info!("Following {}, from a snapshot of {} keys", addr, keys);
+
+    while let Some(message) = leader.next_replicated()? {
+        match message {
+            Replicated::Set {
+                key,
+                value,
+                expire_at,
+            } => set(engine, key, value, expire_at)?,
+            // the key may have expired here first
+            Replicated::Rm { key } => ignore_missing(engine.remove_bytes(key))?,
+            Replicated::Batch(batch) => engine.apply_batch(batch)?,
+            message => return Err(unexpected(message)),
+        }
+    }
+    Ok(())
+}
+
+// Sets a key of the leader until its expiry time, or removes it if that time has passed.
+fn set<E: KvsEngine>(
+    engine: &ReplicatedEngine<E>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expire_at: Option<u64>,
+) -> Result<()> {
+    match expire_at.map(|expire_at| expire_at.saturating_sub(expiry::now_millis())) {
+        None => engine.set_bytes(key, value),
+        Some(0) => ignore_missing(engine.remove_bytes(key)),
+        Some(ttl) => engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl)),
+    }
+}
+
+fn remove_range<E: KvsEngine>(
+    engine: &ReplicatedEngine<E>,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+) -> Result<()> {
+    for key in engine.scan_keys(range)? {
+        ignore_missing(engine.remove_bytes(key?))?;
+    }
+    Ok(())
+}
+
+fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        result => result,
+    }
+}
+
+fn unexpected(message: Replicated) -> KvsError {
+    KvsError::Protocol(format!("Unexpected message from the leader: {:?}", message))
+}
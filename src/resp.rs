@@ -0,0 +1,603 @@
+//! Front-end speaking the Redis protocol (RESP2), so that Redis tools and client libraries
+//! can use the store.
+//!
+//! Commands are arrays of bulk strings, or inline commands made of words on a line as
+//! typed into a terminal. The supported commands are `GET`, `SET` with `EX`, `PX`, `NX`
+//! or `XX`, `DEL`, `EXISTS`, `KEYS`, `SCAN`, `EXPIRE`, `PING`, `ECHO`, `INFO`, `SELECT`
+//! of database 0, `AUTH` and `QUIT`.
+
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::{Access, Credentials};
+use crate::engines::prefix_end;
+use crate::net::Stream;
+use crate::server::Shared;
+use crate::server::{turn_away, Timeouts};
+use crate::{KvsEngine, KvsError, Result};
+
+// the largest bulk string and the most arguments accepted in a command
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+// the longest line accepted, as an inline command or the header of a bulk string
+const MAX_LINE_LEN: u64 = 64 * 1024;
+// the longest pattern accepted by `KEYS` and `SCAN`
+const MAX_PATTERN_LEN: usize = 1024;
+// number of keys a `SCAN` returns if the client does not say
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn bulk(bytes: Vec<u8>) -> Self {
+        Reply::Bulk(Some(bytes))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            // line breaks would end the error early
+            Reply::Error(msg) => write!(writer, "-{}\r\n", msg.replace(&['\r', '\n'][..], " "))?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves a client speaking RESP2, which is anonymous until it sends `AUTH`.
+pub fn handle_client<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    timeouts: Timeouts,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let credentials = shared.credentials.as_deref();
+    let peer_addr = stream.peer();
+    debug!("Connected to {} with RESP", peer_addr);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut access = Access::anonymous(credentials);
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            // the stream cannot be followed any more
+            Err(KvsError::Protocol(msg)) => {
+                Reply::Error(format!("ERR Protocol error: {}", msg)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(KvsError::Protocol(msg));
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        debug!("Receive RESP command {} from {}", name, peer_addr);
+        let reply = if name == "AUTH" {
+            auth(credentials, &args[1..], &mut access)
+        } else {
+            execute(&engine, &access, &name, &args[1..]).unwrap_or_else(|e| match e {
+                KvsError::PermissionDenied(msg) => Reply::Error(format!("NOPERM {}", msg)),
+                KvsError::ReadOnly(_) => Reply::Error(format!("READONLY {}", e)),
+                e => {
+                    error!("engine error: {}", e);
+                    Reply::Error(format!("ERR {}", e))
+                }
+            })
+        };
+        reply.write_to(&mut writer)?;
+        if name == "QUIT" {
+            writer.flush()?;
+            return Ok(());
+        }
+        // the replies to pipelined commands which have already arrived go out together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Turns away a client, as Redis does with more clients than `maxclients`.
+pub fn reject_client(stream: Stream) -> Result<()> {
+    turn_away(stream, b"-ERR max number of clients reached\r\n")
+}
+
+// AUTH [name] secret
+//
+// Without a name, authenticates as `default` like Redis does.
+fn auth(credentials: Option<&Credentials>, args: &[Vec<u8>], access: &mut Access) -> Reply {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            return Reply::Error("ERR AUTH called without any credentials configured".to_owned())
+        }
+    };
+    let (name, secret) = match args {
+        [secret] => ("default".into(), secret),
+        [name, secret] => (String::from_utf8_lossy(name), secret),
+        _ => return Reply::Error("ERR wrong number of arguments for 'auth' command".to_owned()),
+    };
+    match credentials.authenticate(&name, &String::from_utf8_lossy(secret)) {
+        Ok(authenticated) => {
+            *access = authenticated;
+            Reply::ok()
+        }
+        Err(_) => {
+            Reply::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned())
+        }
+    }
+}
+
+// Runs a command, returning the errors of the engine and the refusals of the ACL as `Err`.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    access: &Access,
+    name: &str,
+    args: &[Vec<u8>],
+) -> Result<Reply> {
+    let arity_ok = match name {
+        "PING" => args.len() <= 1,
+        "ECHO" | "GET" | "KEYS" | "SELECT" => args.len() == 1,
+        "SET" => args.len() >= 2,
+        "DEL" | "EXISTS" => !args.is_empty(),
+        "SCAN" => !args.is_empty(),
+        "EXPIRE" => args.len() == 2,
+        "INFO" => args.len() <= 1,
+        "QUIT" => true,
+        _ => {
+            return Ok(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )))
+        }
+    };
+    if !arity_ok {
+        return Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )));
+    }
+
+    let reply = match name {
+        "PING" => match args.first() {
+            Some(msg) => Reply::bulk(msg.clone()),
+            None => Reply::Simple("PONG"),
+        },
+        "ECHO" => Reply::bulk(args[0].clone()),
+        "QUIT" => Reply::ok(),
+        "SELECT" => match parse_int(&args[0]) {
+            Some(0) => Reply::ok(),
+            _ => Reply::Error("ERR DB index is out of range".to_owned()),
+        },
+        "GET" => {
+            access.check_read(&args[0])?;
+            Reply::Bulk(engine.get_bytes(args[0].clone())?)
+        }
+        "SET" => {
+            access.check_write(&args[0])?;
+            set(engine, args)?
+        }
+        "DEL" => {
+            for key in args {
+                access.check_write(key)?;
+            }
+            let mut removed = 0;
+            for key in args {
+                match engine.remove_bytes(key.clone()) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        "EXISTS" => {
+            for key in args {
+                access.check_read(key)?;
+            }
+            let mut found = 0;
+            for key in args {
+                if engine.get_bytes(key.clone())?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        "KEYS" if args[0].len() > MAX_PATTERN_LEN => pattern_too_long(),
+        "KEYS" => Reply::Array(
+            matching_keys(engine, access, &args[0], None)?
+                .map(|key| key.map(Reply::bulk))
+                .collect::<Result<_>>()?,
+        ),
+        "SCAN" => scan(engine, access, args)?,
+        "EXPIRE" => {
+            let seconds = match parse_int(&args[1]) {
+                Some(seconds) => seconds,
+                None => return Ok(not_an_integer()),
+            };
+            access.check_read(&args[0])?;
+            access.check_write(&args[0])?;
+            expire(engine, args[0].clone(), seconds)?
+        }
+        "INFO" => Reply::bulk(
+            format!(
+                "# Server\r\nkvs_version:{}\r\nprocess_id:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id()
+            )
+            .into_bytes(),
+        ),
+        _ => unreachable!(),
+    };
+    Ok(reply)
+}
+
+// SET key value [EX seconds | PX milliseconds] [NX | XX]
+fn set<E: KvsEngine>(engine: &E, args: &[Vec<u8>]) -> Result<Reply> {
+    let (key, value) = (args[0].clone(), args[1].clone());
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+        match option.as_str() {
+            "EX" | "PX" if ttl.is_none() => {
+                let n = match options.next().and_then(|n| parse_int(n)) {
+                    Some(n) if n > 0 => n as u64,
+                    Some(_) => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                    None => return Ok(not_an_integer()),
+                };
+                ttl = Some(if option == "EX" {
+                    Duration::from_secs(n)
+                } else {
+                    Duration::from_millis(n)
+                });
+            }
+            "NX" | "XX" if condition.is_none() => condition = Some(option),
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    let swap = |expected| match ttl {
+        Some(ttl) => {
+            engine.compare_and_swap_bytes_with_ttl(key.clone(), expected, value.clone(), ttl)
+        }
+        None => engine.compare_and_swap_bytes(key.clone(), expected, Some(value.clone())),
+    };
+    match condition.as_deref() {
+        None => match ttl {
+            Some(ttl) => engine.set_bytes_with_ttl(key, value, ttl)?,
+            None => engine.set_bytes(key, value)?,
+        },
+        Some("NX") => {
+            if !swap(None)? {
+                return Ok(Reply::Bulk(None));
+            }
+        }
+        Some(_) => loop {
+            // swap whatever value is there, unless the key goes away
+            let current = match engine.get_bytes(key.clone())? {
+                Some(current) => current,
+                None => return Ok(Reply::Bulk(None)),
+            };
+            if swap(Some(current))? {
+                break;
+            }
+        },
+    }
+    Ok(Reply::ok())
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count]
+//
+// The cursor is the last key returned, so that the next call goes on right after it. A key
+// present during the whole scan is returned at least once.
+fn scan<E: KvsEngine>(engine: &E, access: &Access, args: &[Vec<u8>]) -> Result<Reply> {
+    let after = match decode_cursor(&args[0]) {
+        Some(after) => after,
+        None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = b"*".to_vec();
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (
+            String::from_utf8_lossy(option)
+                .to_ascii_uppercase()
+                .as_str(),
+            options.next(),
+        ) {
+            ("MATCH", Some(p)) if p.len() > MAX_PATTERN_LEN => return Ok(pattern_too_long()),
+            ("MATCH", Some(p)) => pattern = p.clone(),
+            ("COUNT", Some(n)) => match parse_int(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            },
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    let mut keys = matching_keys(engine, access, &pattern, after)?;
+    let page = keys.by_ref().take(count).collect::<Result<Vec<_>>>()?;
+    // the scan is over once no key is left after the page
+    let next = match (keys.next(), page.last()) {
+        (Some(key), Some(last)) => {
+            key?;
+            encode_cursor(last)
+        }
+        _ => b"0".to_vec(),
+    };
+    Ok(Reply::Array(vec![
+        Reply::bulk(next),
+        Reply::Array(page.into_iter().map(Reply::bulk).collect()),
+    ]))
+}
+
+// A `SCAN` cursor is `0` to start with, or `1` followed by each byte of the last key
+// returned as three decimal digits. Clients parsing cursors as integers can take it.
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    let mut cursor = b"1".to_vec();
+    for b in key {
+        cursor.extend_from_slice(format!("{:03}", b).as_bytes());
+    }
+    cursor
+}
+
+// Returns the key a cursor goes on after, if any, or `None` if it is not a cursor.
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Vec<u8>>> {
+    match cursor.split_first() {
+        Some((b'0', [])) => Some(None),
+        Some((b'1', digits)) if digits.len() % 3 == 0 => digits
+            .chunks(3)
+            .map(|b| std::str::from_utf8(b).ok()?.parse::<u8>().ok())
+            .collect::<Option<_>>()
+            .map(Some),
+        _ => None,
+    }
+}
+
+// EXPIRE key seconds
+//
+// The value is swapped for itself with the TTL, so that a write to the key racing with the
+// EXPIRE is never undone.
+fn expire<E: KvsEngine>(engine: &E, key: Vec<u8>, seconds: i64) -> Result<Reply> {
+    loop {
+        let current = match engine.get_bytes(key.clone())? {
+            Some(current) => current,
+            None => return Ok(Reply::Integer(0)),
+        };
+        let swapped = if seconds <= 0 {
+            engine.compare_and_swap_bytes(key.clone(), Some(current), None)?
+        } else {
+            let ttl = Duration::from_secs(seconds as u64);
+            engine.compare_and_swap_bytes_with_ttl(
+                key.clone(),
+                Some(current.clone()),
+                current,
+                ttl,
+            )?
+        };
+        if swapped {
+            return Ok(Reply::Integer(1));
+        }
+    }
+}
+
+// Iterates over the keys matching a glob-style pattern which the client may read, in order,
+// starting after `after` if given. The values are not read.
+fn matching_keys<'a, E: KvsEngine>(
+    engine: &E,
+    access: &'a Access,
+    pattern: &'a [u8],
+    after: Option<Vec<u8>>,
+) -> Result<impl Iterator<Item = Result<Vec<u8>>> + 'a> {
+    // only the keys starting with the literal part of the pattern can match
+    let prefix: Vec<u8> = pattern
+        .iter()
+        .take_while(|&&c| !b"*?[\\".contains(&c))
+        .cloned()
+        .collect();
+    let end = prefix_end(&prefix);
+    let start = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    };
+    Ok(engine
+        .scan_keys((start, end))?
+        .filter(move |key| match key {
+            Ok(key) => glob_match(pattern, key) && access.can_read(key),
+            Err(_) => true,
+        }))
+}
+
+// Matches a Redis glob-style pattern: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+//
+// On a mismatch the last `*` takes one more byte and matching resumes after it, which takes
+// at most `pattern.len() * s.len()` steps and no extra memory.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where matching resumes after the last `*`, and the bytes it has taken so far
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+        } else if let Some(next) = match_one(pattern, p, s[i]) {
+            p = next;
+            i += 1;
+        } else if let Some((after, taken)) = star {
+            p = after;
+            i = taken + 1;
+            star = Some((after, i));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches `c` against the element of the pattern at `p` other than `*`, returning where the
+// next element starts.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match &pattern[p.min(pattern.len())..] {
+        [] | [b'*', ..] => None,
+        [b'?', ..] => Some(p + 1),
+        [b'\\', x, ..] => Some(p + 2).filter(|_| *x == c),
+        [b'[', rest @ ..] => {
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // an unclosed class matches like a closed one
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', x, tail @ ..] => {
+                        matched |= *x == c;
+                        class = tail;
+                    }
+                    [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                        let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                        matched |= *lo <= c && c <= *hi;
+                        class = tail;
+                    }
+                    [x, tail @ ..] => {
+                        matched |= *x == c;
+                        class = tail;
+                    }
+                }
+            }
+            Some(pattern.len() - class.len()).filter(|_| matched != negate)
+        }
+        [x, ..] => Some(p + 1).filter(|_| *x == c),
+    }
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn pattern_too_long() -> Reply {
+    Reply::Error(format!("ERR pattern longer than {} bytes", MAX_PATTERN_LEN))
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+// Reads the arguments of the next command.
+//
+// Returns `Ok(None)` if the client closed the connection between two commands.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        // an inline command
+        return Ok(Some(
+            line.split(|c| c.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    }
+
+    let len = parse_len(&line[1..], MAX_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.first() != Some(&b'$') {
+            return Err(KvsError::Protocol(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..line.len().min(1)])
+            )));
+        }
+        let len = parse_len(&line[1..], MAX_BULK_LEN, "bulk length")?;
+        // the buffer only grows as the announced bytes actually arrive
+        let mut arg = Vec::new();
+        if reader.take(len as u64 + 2).read_to_end(&mut arg)? != len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol(
+                "bulk string not ended by CRLF".to_owned(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Reads a line without its line break.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // a line is short, unlike the bulk strings it may announce
+    let read = reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        // the connection may end before the limit is reached
+        return Err(if read as u64 == MAX_LINE_LEN {
+            KvsError::Protocol("too big inline request".to_owned())
+        } else {
+            unexpected_eof()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize, what: &str) -> Result<usize> {
+    match parse_int(digits) {
+        Some(len) if len >= 0 && len as usize <= max => Ok(len as usize),
+        _ => Err(KvsError::Protocol(format!("invalid {}", what))),
+    }
+}
+
+fn unexpected_eof() -> KvsError {
+    KvsError::Protocol("connection closed in the middle of a command".to_owned())
+}
@@ -1,79 +1,1004 @@
-use serde_json::Deserializer;
+use rustls::{ServerConfig, ServerConnection};
+use std::collections::HashMap;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Shutdown, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::protocol::{Request, Response};
+use crate::auth::{Access, Credentials};
+use crate::metrics::{MeteredEngine, Metrics};
+use crate::net::{Listener, ServerAddr, Stream, ToServerAddr};
+use crate::protocol::legacy::{
+    JsonRequest, JsonResponse, JsonResponses, JsonSplitter, MAX_LEGACY_REQUEST_LEN,
+};
+use crate::protocol::{
+    decode, read_frame, read_message, split_id, write_message, write_tagged, Auth, Hello,
+    HelloReply, Request, Response, ScanRange, ServerInfo, CAP_AUTH, MAGIC, VERSION_REQUEST_IDS,
+};
+use crate::replication::{self, ReplicatedEngine, Replication};
 use crate::thread_pool::*;
-use crate::{KvsEngine, Result};
+use crate::tls::ServerTls;
+use crate::{http, resp};
+use crate::{ClientOptions, ErrorKind, KvsEngine, KvsError, Result};
+
+/// How long `KvsServer::run` waits for the connections to finish after a shutdown, by
+/// default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many connections the OS queues for a listener of `KvsServer` before refusing
+/// more, by default.
+pub const DEFAULT_BACKLOG: u32 = 128;
+
+// What the clients turned away by `max_connections` are told
+pub(crate) const TOO_MANY_CONNECTIONS: &str = "Too many connections";
+
+// How long a client being turned away has to send its first request and take the response
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How many clients of a listener can wait to be turned away, the next ones being closed
+// without a response
+const REJECT_QUEUE_LEN: usize = 64;
+
+// Serves a connection of a listener until the client leaves, checking the requests against
+// the credentials of the server if it has some.
+type Handler<E> = fn(E, Stream, Timeouts, Arc<Shared>) -> Result<()>;
+
+// The engine as the connections use it
+type ServedEngine<E> = MeteredEngine<ReplicatedEngine<E>>;
+
+// What the connections of a running server share besides the engine.
+pub(crate) struct Shared {
+    pub(crate) credentials: Option<Arc<Credentials>>,
+    pub(crate) started: Instant,
+    pub(crate) replication: Arc<Replication>,
+}
 
 /// K-V store server.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    pool: P,
+    pool: Arc<P>,
+    unix_addr: Option<ServerAddr>,
+    resp_addr: Option<ServerAddr>,
+    http_addr: Option<ServerAddr>,
+    metrics_addr: Option<ServerAddr>,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    backlog: u32,
+    tls: Option<Arc<ServerConfig>>,
+    credentials: Option<Arc<Credentials>>,
+    metrics: Arc<Metrics>,
+    replication: Arc<Replication>,
+    replica_of: Option<(ServerAddr, Arc<ClientOptions>)>,
+    registry: Arc<Registry>,
+}
+
+/// Stops a running `KvsServer` from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    registry: Arc<Registry>,
+}
+
+impl ShutdownHandle {
+    /// Makes the server stop accepting connections and read no more requests. The
+    /// requests already read are answered, then `KvsServer::run` flushes the engine and
+    /// returns.
+    pub fn shutdown(&self) {
+        let wake_addrs = {
+            let mut state = self.registry.state.lock().unwrap();
+            if state.stopping {
+                return;
+            }
+            info!("Shutting down with {} connections", state.streams.len());
+            state.stopping = true;
+            for stream in state.streams.values() {
+                // the handler sees the end of the stream after the requests already sent
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+            state.wake_addrs.clone()
+        };
+        // a listener only notices the shutdown once its `accept` returns
+        for addr in wake_addrs {
+            let _ = Stream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+}
+
+// The timeouts of the connections of a server, `None` waiting forever.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timeouts {
+    // for more of a request which has started
+    read: Option<Duration>,
+    // for the client to take more of a response
+    write: Option<Duration>,
+    // for the next request to start
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    fn apply(&self, stream: &Stream) -> io::Result<()> {
+        stream.set_read_timeout(self.read)?;
+        stream.set_write_timeout(self.write)
+    }
+
+    // Waits for the next request to start, for no longer than the idle timeout.
+    //
+    // Returns `false` if the client closed the connection or stayed idle for too long.
+    pub(crate) fn wait_for_request(&self, reader: &mut BufReader<Stream>) -> Result<bool> {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        reader.get_ref().set_read_timeout(self.idle)?;
+        let started = reader.fill_buf().map(|buf| !buf.is_empty());
+        reader.get_ref().set_read_timeout(self.read)?;
+        match started {
+            Ok(started) => Ok(started),
+            Err(e) if is_timeout(&e) => {
+                debug!("Closing the connection to an idle client");
+                Ok(false)
+            }
+            // a TLS client which hung up without closing its session
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Whether an I/O error comes from a socket timeout.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// The listeners and connections of a server, for the shutdown.
+#[derive(Default)]
+struct Registry {
+    state: Mutex<RegistryState>,
+    drained: Condvar,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    stopping: bool,
+    // addresses to connect to, to wake up the listeners
+    wake_addrs: Vec<ServerAddr>,
+    // clones of the streams being served, or queued in the pool
+    streams: HashMap<u64, Stream>,
+    next_id: u64,
+}
+
+impl Registry {
+    // Returns whether the server is shutting down already.
+    fn add_listener(&self, listener: &Listener) -> Result<bool> {
+        let addr = listener.local_addr()?;
+        let mut state = self.state.lock().unwrap();
+        state.wake_addrs.push(addr);
+        Ok(state.stopping)
+    }
+
+    // Registers a connection, unless the server is shutting down or has `max` connections
+    // already.
+    fn add_connection(self: &Arc<Self>, stream: &Stream, max: Option<usize>) -> Result<Admission> {
+        let clone = stream.try_clone()?;
+        let mut state = self.state.lock().unwrap();
+        if state.stopping {
+            return Ok(Admission::Stopping);
+        }
+        if max.is_some_and(|max| state.streams.len() >= max) {
+            return Ok(Admission::Full);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.streams.insert(id, clone);
+        Ok(Admission::Accepted(Connection {
+            registry: self.clone(),
+            id,
+        }))
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.state.lock().unwrap().stopping
+    }
+
+    // Waits until no connection is left, closing those still open after `timeout`.
+    fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "Closing {} connections still busy after {:?}",
+                    state.streams.len(),
+                    timeout
+                );
+                for stream in state.streams.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            state = self.drained.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+enum Admission {
+    Accepted(Connection),
+    Full,
+    Stopping,
+}
+
+// A connection in the registry, removed when dropped.
+struct Connection {
+    registry: Arc<Registry>,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.registry.state.lock().unwrap();
+        state.streams.remove(&self.id);
+        if state.streams.is_empty() {
+            self.registry.drained.notify_all();
+        }
+    }
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
     /// Create a `KvsServer` with given store engine
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool: Arc::new(pool),
+            unix_addr: None,
+            resp_addr: None,
+            http_addr: None,
+            metrics_addr: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            timeouts: Timeouts::default(),
+            max_connections: None,
+            backlog: DEFAULT_BACKLOG,
+            tls: None,
+            credentials: None,
+            metrics: Arc::default(),
+            replication: Arc::default(),
+            replica_of: None,
+            registry: Arc::new(Registry::default()),
+        }
+    }
+
+    /// Also serves clients on the Unix domain socket at `path`.
+    pub fn unix_socket<Q: AsRef<Path>>(mut self, path: Q) -> Self {
+        self.unix_addr = Some(ServerAddr::Unix(path.as_ref().to_owned()));
+        self
     }
 
-    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    /// Also serves clients speaking the Redis protocol on `addr`.
+    pub fn resp_addr<A: ToSocketAddrs>(mut self, addr: A) -> Result<Self> {
+        self.resp_addr = Some(ServerAddr::Tcp(addr.to_socket_addrs()?.collect()));
+        Ok(self)
+    }
+
+    /// Also serves the HTTP gateway on `addr`.
+    pub fn http_addr<A: ToSocketAddrs>(mut self, addr: A) -> Result<Self> {
+        self.http_addr = Some(ServerAddr::Tcp(addr.to_socket_addrs()?.collect()));
+        Ok(self)
+    }
+
+    /// Also serves the metrics of the server on `addr`, at `GET /metrics` in the
+    /// Prometheus text format. The metrics are not behind the credentials, so `addr`
+    /// should only be reachable by the scraper.
+    pub fn metrics_addr<A: ToSocketAddrs>(mut self, addr: A) -> Result<Self> {
+        self.metrics_addr = Some(ServerAddr::Tcp(addr.to_socket_addrs()?.collect()));
+        Ok(self)
+    }
+
+    /// Sets how long a shutdown waits for the requests being served before closing their
+    /// connections.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a connection waits for more of a request which has started, before
+    /// it is closed. By default it waits forever.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Sets how long a connection waits for the client to take more of a response,
+    /// before it is closed. By default it waits forever.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Sets how long a connection waits for the next request, before it is closed. By
+    /// default it waits forever.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Sets how many connections are served at once, over all listeners. The clients
+    /// connecting beyond that are sent an error in their protocol and disconnected. By
+    /// default there is no limit, and the connections the pool has no thread for wait in
+    /// its queue.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets how many connections the OS queues for each listener until they are
+    /// accepted, beyond which it refuses them. Defaults to `DEFAULT_BACKLOG`.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Serves the clients of the address given to `run`, of `resp_addr` and of `http_addr`
+    /// over TLS. The Unix domain socket and the metrics, which take no credentials, stay in
+    /// plaintext.
+    pub fn tls(mut self, tls: &ServerTls) -> Result<Self> {
+        self.tls = Some(tls.config()?);
+        Ok(self)
+    }
+
+    /// Makes the clients of every listener authenticate as one of the principals of
+    /// `credentials`, which may only use the keys their ACL allows. The clients which do
+    /// not authenticate are anonymous.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(Arc::new(credentials));
+        self
+    }
+
+    /// Makes the server a read-only follower of the leader at `addr`, which it connects to
+    /// with `options`. The server copies the keys of the leader, then applies its writes as
+    /// they happen, until it is promoted with `KvsClient::promote`.
+    ///
+    /// A leader with credentials only replicates to a principal with `admin` set.
+    pub fn replica_of<A: ToServerAddr>(mut self, addr: A, options: ClientOptions) -> Result<Self> {
+        self.replica_of = Some((addr.to_server_addr()?, Arc::new(options)));
+        Ok(self)
+    }
+
+    /// Returns a handle to stop `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Serves clients on `addr`, and the other listeners configured, until a shutdown.
+    /// `addr` may be a TCP address, or `unix:/path` for a Unix domain socket.
+    ///
+    /// On a shutdown, waits for the connections to finish, flushes the engine and returns.
+    pub fn run<A: ToServerAddr>(&mut self, addr: A) -> Result<()> {
+        let listener = Listener::bind(&addr.to_server_addr()?, self.backlog)?;
+        let shared = Arc::new(Shared {
+            credentials: self.credentials.clone(),
+            started: Instant::now(),
+            replication: self.replication.clone(),
+        });
+        let follower_thread = match &self.replica_of {
+            Some((addr, options)) => {
+                info!("Following {}", addr);
+                let engine = ReplicatedEngine::new(self.engine.clone(), self.replication.clone());
+                Some(replication::follow(engine, addr.clone(), options.clone())?)
+            }
+            None => None,
+        };
+        let mut listener_threads = Vec::new();
+        if let Some(addr) = &self.unix_addr {
+            listener_threads.push(self.spawn_listener(
+                &shared,
+                "Unix",
+                addr,
+                handle_client,
+                reject_client,
+            )?);
+        }
+        if let Some(addr) = &self.resp_addr {
+            listener_threads.push(self.spawn_listener(
+                &shared,
+                "RESP",
+                addr,
+                resp::handle_client,
+                resp::reject_client,
+            )?);
+        }
+        if let Some(addr) = &self.http_addr {
+            listener_threads.push(self.spawn_listener(
+                &shared,
+                "HTTP",
+                addr,
+                http::handle_client,
+                http::reject_client,
+            )?);
+        }
+        if let Some(addr) = &self.metrics_addr {
+            listener_threads.push(self.spawn_metrics_listener(addr)?);
+        }
         info!("KvsServer: start working!");
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let engine = self.engine.clone();
-                    self.pool.spawn(|| {
-                        if let Err(e) = handle_client(engine, stream) {
-                            error!("Error when serving client: {}", e);
-                        }
-                    })
-                }
-                Err(e) => error!("Connection failed: {}", e),
+        self.acceptor(&shared, self.tls.clone())
+            .accept(listener, handle_client, reject_client)?;
+        for thread in listener_threads {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Listener failed: {}", e),
+                Err(_) => error!("Listener panicked"),
+            }
+        }
+
+        self.registry.drain(self.shutdown_timeout);
+        self.replication.close();
+        if let Some(thread) = follower_thread {
+            if thread.join().is_err() {
+                error!("Follower panicked");
             }
         }
+        self.engine.flush()?;
+        info!("KvsServer: shut down");
         Ok(())
     }
+
+    // Serves the connections of another listener from a thread of its own.
+    fn spawn_listener(
+        &self,
+        shared: &Arc<Shared>,
+        name: &str,
+        addr: &ServerAddr,
+        handle: Handler<ServedEngine<E>>,
+        reject: fn(Stream) -> Result<()>,
+    ) -> Result<JoinHandle<Result<()>>> {
+        let listener = Listener::bind(addr, self.backlog)?;
+        info!("Serving {} on {}", name, listener.local_addr()?);
+        // a local socket is only reachable from this host, so it has no need for TLS
+        let tls = match addr {
+            ServerAddr::Tcp(_) => self.tls.clone(),
+            _ => None,
+        };
+        let acceptor = self.acceptor(shared, tls);
+        Ok(thread::Builder::new()
+            .name(format!("{}-listener", name.to_lowercase()))
+            .spawn(move || acceptor.accept(listener, handle, reject))?)
+    }
+
+    // Serves the metrics from a thread of its own rather than the pool, so that they can
+    // be scraped while the pool is busy.
+    fn spawn_metrics_listener(&self, addr: &ServerAddr) -> Result<JoinHandle<Result<()>>> {
+        let listener = Listener::bind(addr, self.backlog)?;
+        info!("Serving metrics on {}", listener.local_addr()?);
+        let engine = self.engine.clone();
+        let metrics = self.metrics.clone();
+        let registry = self.registry.clone();
+        Ok(thread::Builder::new()
+            .name("metrics-listener".to_owned())
+            .spawn(move || {
+                if registry.add_listener(&listener)? {
+                    return Ok(());
+                }
+                loop {
+                    let stream = listener.accept();
+                    if registry.is_stopping() {
+                        return Ok(());
+                    }
+                    // a connection which fails, even by panicking, does not stop the listener
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        stream.map_err(KvsError::from).and_then(|stream| {
+                            stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+                            stream.set_write_timeout(Some(REJECT_TIMEOUT))?;
+                            http::serve_metrics(stream, || Ok(metrics.render(&engine.stats()?)))
+                        })
+                    }));
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!("Error when serving metrics: {}", e),
+                        Err(_) => error!("Panicked when serving metrics"),
+                    }
+                }
+            })?)
+    }
+
+    fn acceptor(&self, shared: &Arc<Shared>, tls: Option<Arc<ServerConfig>>) -> Acceptor<E, P> {
+        Acceptor {
+            tls,
+            engine: MeteredEngine::new(
+                ReplicatedEngine::new(self.engine.clone(), self.replication.clone()),
+                self.metrics.clone(),
+            ),
+            pool: self.pool.clone(),
+            registry: self.registry.clone(),
+            timeouts: self.timeouts,
+            max_connections: self.max_connections,
+            shared: shared.clone(),
+        }
+    }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let mut writer = stream.try_clone()?;
-    let peer_addr = stream.peer_addr()?;
-    debug!("Connected to {}", peer_addr);
+// What the listeners of a server share.
+struct Acceptor<E, P> {
+    engine: ServedEngine<E>,
+    pool: Arc<P>,
+    registry: Arc<Registry>,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    tls: Option<Arc<ServerConfig>>,
+    shared: Arc<Shared>,
+}
 
-    for request in Deserializer::from_reader(stream).into_iter::<Request>() {
-        let request =
-            request.map_err(|e| failure::err_msg(format!("deserializing error {}", e)))?;
-        debug!("Receive request from {}: {:?}", peer_addr, request);
-        let response = match request {
-            Request::Get { key } => match engine.get(key) {
-                Ok(value) => Response::Ok(value),
+impl<E: KvsEngine, P: ThreadPool> Acceptor<E, P> {
+    // Serves each connection of `listener` on the pool with `handle`, until a shutdown.
+    // The connections beyond the maximum are turned away with `reject`.
+    fn accept(
+        self,
+        listener: Listener,
+        handle: Handler<ServedEngine<E>>,
+        reject: fn(Stream) -> Result<()>,
+    ) -> Result<()> {
+        if self.registry.add_listener(&listener)? {
+            return Ok(());
+        }
+        let rejections = spawn_rejecter(reject)?;
+        loop {
+            let stream = listener.accept();
+            if self.registry.is_stopping() {
+                break;
+            }
+            let stream = match stream.map_err(KvsError::from).and_then(|s| self.secure(s)) {
+                Ok(stream) => stream,
                 Err(e) => {
-                    error!("engine error: {}", e);
-                    Response::Err(format!("{}", e))
+                    error!("Connection failed: {}", e);
+                    continue;
                 }
-            },
-            Request::Set { key, value } => match engine.set(key, value) {
-                Ok(()) => Response::Ok(None),
-                Err(e) => {
-                    error!("engine error: {}", e);
-                    Response::Err(format!("{}", e))
+            };
+            let connection = match self.registry.add_connection(&stream, self.max_connections) {
+                Ok(Admission::Accepted(connection)) => connection,
+                Ok(Admission::Full) => {
+                    warn!(
+                        "Turning a client away, at the maximum of {} connections",
+                        self.max_connections.unwrap()
+                    );
+                    if let Err(TrySendError::Full(_)) = rejections.try_send(stream) {
+                        debug!("Closing the connection of a client without a response");
+                    }
+                    continue;
                 }
-            },
-            Request::Rm { key } => match engine.remove(key) {
-                Ok(()) => Response::Ok(None),
+                Ok(Admission::Stopping) => break,
                 Err(e) => {
-                    error!("engine error: {}", e);
-                    Response::Err(format!("{}", e))
+                    error!("Connection failed: {}", e);
+                    continue;
                 }
-            },
+            };
+            if let Err(e) = self.timeouts.apply(&stream) {
+                error!("Connection failed: {}", e);
+                continue;
+            }
+            let engine = self.engine.clone();
+            let timeouts = self.timeouts;
+            let shared = self.shared.clone();
+            let metrics = engine.metrics();
+            metrics.connection_opened();
+            metrics.connection_queued();
+            self.pool.spawn(move || {
+                engine.metrics().connection_dequeued();
+                // the connection is counted as closed even if serving it panicked
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle(engine.clone(), stream, timeouts, shared)
+                }));
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("Error when serving client: {}", e),
+                    Err(_) => error!("Panicked when serving client"),
+                }
+                engine.metrics().connection_closed();
+                drop(connection);
+            })
+        }
+        Ok(())
+    }
+
+    // Starts a TLS session over the stream if the listener has TLS.
+    fn secure(&self, stream: Stream) -> Result<Stream> {
+        match &self.tls {
+            Some(config) => Ok(stream.into_tls_server(ServerConnection::new(config.clone())?)?),
+            None => Ok(stream),
+        }
+    }
+}
+
+// Turns away the clients sent to it from a thread of its own, so that they cannot hold
+// back the listener. The thread ends once the sender is dropped.
+fn spawn_rejecter(reject: fn(Stream) -> Result<()>) -> Result<SyncSender<Stream>> {
+    let (sender, receiver) = mpsc::sync_channel::<Stream>(REJECT_QUEUE_LEN);
+    thread::Builder::new()
+        .name("rejecter".to_owned())
+        .spawn(move || {
+            for stream in receiver {
+                if let Err(e) = reject(stream) {
+                    debug!("Error when turning a client away: {}", e);
+                }
+            }
+        })?;
+    Ok(sender)
+}
+
+// Sends `response` to a client being turned away, then lets it hang up first, so that
+// the requests it sent do not reset the connection before it reads the response.
+pub(crate) fn turn_away(mut stream: Stream, response: &[u8]) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    stream.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    stream.write_all(response)?;
+    stream.shutdown(Shutdown::Write)?;
+    io::copy(&mut stream.take(1 << 16), &mut io::sink())?;
+    Ok(())
+}
+
+fn handle_client<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    timeouts: Timeouts,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let peer_addr = stream.peer();
+    debug!("Connected to {}", peer_addr);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = BufWriter::new(stream);
+
+    if !timeouts.wait_for_request(&mut reader)? {
+        return Ok(());
+    }
+    // a JSON request cannot start with the magic bytes
+    if reader.buffer()[0] == MAGIC[0] {
+        serve(engine, reader, writer, peer_addr, timeouts, shared)
+    } else {
+        debug!("Serve {} with the legacy JSON protocol", peer_addr);
+        // which has no way to authenticate
+        let access = Access::anonymous(shared.credentials.as_deref());
+        serve_legacy(engine, reader, writer, peer_addr, timeouts, shared, access)
+    }
+}
+
+// Turns a client away in the protocol of its first request.
+fn reject_client(mut stream: Stream) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    let mut first = [0];
+    let mut response = Vec::new();
+    match stream.read(&mut first) {
+        Ok(1) if first[0] == MAGIC[0] => write_message(
+            &mut response,
+            &HelloReply::Reject(TOO_MANY_CONNECTIONS.to_owned()),
+        )?,
+        Ok(1) => serde_json::to_writer(
+            &mut response,
+            &JsonResponse::Err(TOO_MANY_CONNECTIONS.to_owned()),
+        )?,
+        // the client left or sent nothing
+        _ => return Ok(()),
+    }
+    turn_away(stream, &response)
+}
+
+// Serves a client of the binary protocol, starting with the handshake.
+fn serve<E: KvsEngine>(
+    engine: E,
+    mut reader: BufReader<Stream>,
+    mut writer: BufWriter<Stream>,
+    peer_addr: String,
+    timeouts: Timeouts,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != *MAGIC {
+        return Err(KvsError::Protocol("bad magic bytes".to_owned()));
+    }
+    let hello: Hello = match read_message(&mut reader)? {
+        Some(hello) => hello,
+        None => return Ok(()),
+    };
+    let without: &[&str] = match shared.credentials {
+        Some(_) => &[],
+        None => &[CAP_AUTH],
+    };
+    let reply = hello.reply(without);
+    write_message(&mut writer, &reply)?;
+    writer.flush()?;
+    let (version, auth) = match reply {
+        HelloReply::Accept {
+            version,
+            capabilities,
+        } => {
+            debug!(
+                "Speak protocol version {} to {} with capabilities {:?}",
+                version, peer_addr, capabilities
+            );
+            (version, capabilities.iter().any(|cap| cap == CAP_AUTH))
+        }
+        HelloReply::Reject(reason) => return Err(KvsError::Protocol(reason)),
+    };
+    let tagged = version >= VERSION_REQUEST_IDS;
+    let access = match shared.credentials.as_deref() {
+        Some(credentials) if auth => {
+            let auth: Auth = match read_message(&mut reader)? {
+                Some(auth) => auth,
+                None => return Ok(()),
+            };
+            let access = credentials.authenticate(&auth.name, &auth.secret);
+            let response = match &access {
+                Ok(_) => Response::Ok(None),
+                Err(e) => Response::Err(e.kind(), e.to_string()),
+            };
+            write_message(&mut writer, &response)?;
+            writer.flush()?;
+            access?
+        }
+        credentials => Access::anonymous(credentials),
+    };
+
+    while timeouts.wait_for_request(&mut reader)? {
+        let frame = match read_frame(&mut reader)? {
+            Some(frame) => frame,
+            None => break,
+        };
+        let (id, payload) = if tagged {
+            split_id(&frame)?
+        } else {
+            (0, &frame[..])
+        };
+        let request = decode(payload);
+        // the connection only carries the writes of the server from then on
+        if let Ok(Request::Replicate) = request {
+            if access.check(&Request::Replicate).is_ok() {
+                let id = if tagged { Some(id) } else { None };
+                return replication::serve_follower(
+                    &engine,
+                    &shared.replication,
+                    writer,
+                    peer_addr,
+                    id,
+                );
+            }
+        }
+        // the entries of a scan go out as they are read
+        let mut sent = 0;
+        let mut respond = |response: Response| {
+            sent += 1;
+            if tagged {
+                write_tagged(&mut writer, id, &response)
+            } else {
+                write_message(&mut writer, &response)
+            }
+        };
+        match request {
+            Ok(request) => {
+                debug!("Receive request from {}: {:?}", peer_addr, request);
+                execute_as(&engine, &access, &shared, request, &mut respond)?;
+            }
+            // the frame is skipped, so the next requests can still be served
+            Err(e) => {
+                error!("Bad request from {}: {}", peer_addr, e);
+                respond(Response::Err(e.kind(), e.to_string()))?;
+            }
+        }
+        debug!("Send {} responses to {}", sent, peer_addr);
+        // the responses to pipelined requests which have already arrived go out together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Serves a client of the legacy JSON protocol.
+fn serve_legacy<E: KvsEngine>(
+    engine: E,
+    mut reader: BufReader<Stream>,
+    mut writer: BufWriter<Stream>,
+    peer_addr: String,
+    timeouts: Timeouts,
+    shared: Arc<Shared>,
+    access: Access,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut splitter = JsonSplitter::default();
+    loop {
+        let len = match splitter.next_value(&buf) {
+            Some(len) => len,
+            None => {
+                if buf.len() > MAX_LEGACY_REQUEST_LEN {
+                    return Err(KvsError::Protocol(format!(
+                        "Request longer than {} bytes",
+                        MAX_LEGACY_REQUEST_LEN
+                    )));
+                }
+                // whitespace between the requests does not start one
+                let idle = buf.iter().all(u8::is_ascii_whitespace);
+                if idle {
+                    buf.clear();
+                    splitter = JsonSplitter::default();
+                    if !timeouts.wait_for_request(&mut reader)? {
+                        return Ok(());
+                    }
+                }
+                let read = reader.fill_buf()?;
+                if read.is_empty() {
+                    return if idle {
+                        Ok(())
+                    } else {
+                        Err(KvsError::Protocol(
+                            "Connection closed in the middle of a request".to_owned(),
+                        ))
+                    };
+                }
+                buf.extend_from_slice(read);
+                let read = read.len();
+                reader.consume(read);
+                continue;
+            }
         };
-        serde_json::to_writer(&mut writer, &response)?;
+        let request = serde_json::from_slice::<JsonRequest>(&buf[..len])
+            .map_err(|e| KvsError::Protocol(format!("deserializing error {}", e)))?;
+        buf.drain(..len);
+        debug!("Receive request from {}: {:?}", peer_addr, request);
+        let mut responses = JsonResponses::default();
+        let mut sent = 0;
+        execute_as(&engine, &access, &shared, request.into(), &mut |response| {
+            if let Some(response) = responses.convert(response) {
+                serde_json::to_writer(&mut writer, &response)?;
+                sent += 1;
+            }
+            Ok(())
+        })?;
         writer.flush()?;
-        debug!("Send response to {}: {:?}", peer_addr, response);
+        debug!("Send {} responses to {}", sent, peer_addr);
     }
+}
 
-    Ok(())
+// Runs a request of a client with the given access: a request the ACL does not allow is
+// refused, and a scan skips the keys the client may not read.
+fn execute_as<E, F>(
+    engine: &E,
+    access: &Access,
+    shared: &Shared,
+    request: Request,
+    respond: &mut F,
+) -> Result<()>
+where
+    E: KvsEngine,
+    F: FnMut(Response) -> Result<()>,
+{
+    if let Err(e) = access.check(&request) {
+        return respond(Response::Err(e.kind(), e.to_string()));
+    }
+    match request {
+        Request::Info => {
+            let leader = shared.replication.leader();
+            respond(Response::Info(server_info(engine, shared.started, leader)))
+        }
+        Request::Promote => {
+            shared.replication.promote();
+            respond(Response::Ok(None))
+        }
+        request => execute(
+            engine,
+            shared.started,
+            request,
+            &mut |response| match &response {
+                Response::Entry(key, _) if !access.can_read(key) => Ok(()),
+                _ => respond(response),
+            },
+        ),
+    }
+}
+
+// Runs a request against the engine of a server started at `started`, passing the
+// responses to `respond`. A scan is answered with its entries as they are read, followed by
+// `Ok(None)`, or by an error if reading fails; anything else with a single response.
+pub(crate) fn execute<E, F>(
+    engine: &E,
+    started: Instant,
+    request: Request,
+    respond: &mut F,
+) -> Result<()>
+where
+    E: KvsEngine,
+    F: FnMut(Response) -> Result<()> + ?Sized,
+{
+    let response = match request {
+        Request::Get { key } => match engine.get_bytes(key) {
+            Ok(value) => Response::Ok(value),
+            Err(e) => error_response(e),
+        },
+        Request::Set { key, value } => match engine.set_bytes(key, value) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => error_response(e),
+        },
+        Request::SetWithTtl { key, value, ttl } => {
+            match engine.set_bytes_with_ttl(key, value, ttl) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => error_response(e),
+            }
+        }
+        Request::Rm { key } => match engine.remove_bytes(key) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => error_response(e),
+        },
+        Request::Cas { key, expected, new } => {
+            match engine.compare_and_swap_bytes(key, expected, new) {
+                Ok(swapped) => Response::Swapped(swapped),
+                Err(e) => error_response(e),
+            }
+        }
+        Request::Batch(batch) => match engine.apply_batch(batch) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => error_response(e),
+        },
+        Request::Scan(range) => {
+            let entries = match range {
+                ScanRange::Range(start, end) => engine.scan_bytes((start, end)),
+                ScanRange::Prefix(prefix) => engine.scan_prefix_bytes(prefix),
+            };
+            match entries {
+                Ok(entries) => {
+                    for entry in entries {
+                        match entry {
+                            Ok((key, value)) => respond(Response::Entry(key, value))?,
+                            Err(e) => return respond(error_response(e)),
+                        }
+                    }
+                    Response::Ok(None)
+                }
+                Err(e) => error_response(e),
+            }
+        }
+        Request::Stats => match engine.stats() {
+            Ok(stats) => Response::Stats(stats),
+            Err(e) => error_response(e),
+        },
+        Request::Compact => match engine.compact() {
+            Ok(()) => Response::Ok(None),
+            Err(e) => error_response(e),
+        },
+        Request::Flush => match engine.flush() {
+            Ok(()) => Response::Ok(None),
+            Err(e) => error_response(e),
+        },
+        Request::Info => Response::Info(server_info(engine, started, None)),
+        Request::Replicate | Request::Promote => Response::Err(
+            ErrorKind::Protocol,
+            "Replication is not supported by this server".to_owned(),
+        ),
+    };
+    respond(response)
+}
+
+pub(crate) fn server_info<E: KvsEngine>(
+    engine: &E,
+    started: Instant,
+    leader: Option<String>,
+) -> ServerInfo {
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        engine: engine.name().to_owned(),
+        uptime: started.elapsed(),
+        leader,
+    }
+}
+
+// Reports an engine error to the client along with its kind.
+fn error_response(e: KvsError) -> Response {
+    match e {
+        KvsError::KeyNotFound | KvsError::ReadOnly(_) => debug!("{}", e),
+        _ => error!("engine error: {}", e),
+    }
+    Response::Err(e.kind(), e.to_string())
 }
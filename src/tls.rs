@@ -0,0 +1,141 @@
+//! TLS settings of `KvsServer` and `KvsClient`, loaded from PEM files.
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::net::{ServerAddr, Stream};
+use crate::{KvsError, Result};
+
+/// TLS settings of a server: its certificate chain and private key, and the CAs which
+/// must have signed the certificates of the clients, if they need one.
+pub struct ServerTls {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Arc<RootCertStore>>,
+}
+
+impl ServerTls {
+    /// Loads the certificate chain and the private key of the server from PEM files.
+    pub fn from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, key: Q) -> Result<Self> {
+        Ok(ServerTls {
+            certs: load_certs(cert.as_ref())?,
+            key: load_key(key.as_ref())?,
+            client_roots: None,
+        })
+    }
+
+    /// Requires the clients to present a certificate signed by one of the CAs in the PEM
+    /// file `ca`.
+    pub fn client_ca<P: AsRef<Path>>(mut self, ca: P) -> Result<Self> {
+        self.client_roots = Some(Arc::new(load_roots(ca.as_ref())?));
+        Ok(self)
+    }
+
+    pub(crate) fn config(&self) -> Result<Arc<ServerConfig>> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_roots {
+            Some(roots) => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(roots.clone(), provider)
+                    .build()
+                    .map_err(|e| KvsError::Tls(e.to_string()))?,
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(builder.with_single_cert(
+            self.certs.clone(),
+            self.key.clone_key(),
+        )?))
+    }
+}
+
+/// TLS settings of a client: the CAs which must have signed the certificate of the
+/// server, and the certificate of the client if the server asks for one.
+pub struct ClientTls {
+    roots: Arc<RootCertStore>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// Trusts the CAs in the PEM file `ca`.
+    pub fn from_ca_file<P: AsRef<Path>>(ca: P) -> Result<Self> {
+        Ok(ClientTls {
+            roots: Arc::new(load_roots(ca.as_ref())?),
+            identity: None,
+            server_name: None,
+        })
+    }
+
+    /// Presents the certificate chain in the PEM file `cert` to the server, with the
+    /// private key in the PEM file `key`.
+    pub fn identity<P: AsRef<Path>, Q: AsRef<Path>>(mut self, cert: P, key: Q) -> Result<Self> {
+        self.identity = Some((load_certs(cert.as_ref())?, load_key(key.as_ref())?));
+        Ok(self)
+    }
+
+    /// Sets the name the certificate of the server must be valid for. By default it is
+    /// the IP address connected to, or `localhost` over a Unix domain socket.
+    pub fn server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name)
+            .map_err(|e| KvsError::Tls(format!("{}: {}", name, e)))?
+            .to_owned();
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    // Starts a TLS session over `stream`, connected to `addr`.
+    pub(crate) fn connect(&self, stream: Stream, addr: &ServerAddr) -> Result<Stream> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots.clone());
+        let config = match &self.identity {
+            Some((certs, key)) => builder.with_client_auth_cert(certs.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = match (&self.server_name, addr) {
+            (Some(name), _) => name.clone(),
+            (None, ServerAddr::Tcp(addrs)) if !addrs.is_empty() => addrs[0].ip().into(),
+            (None, _) => ServerName::try_from("localhost").unwrap(),
+        };
+        let conn = ClientConnection::new(Arc::new(config), server_name)?;
+        Ok(stream.into_tls_client(conn)?)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "{}: no certificate found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
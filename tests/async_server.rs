@@ -0,0 +1,217 @@
+#![cfg(feature = "async")]
+
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, KvStore, KvsClient, KvsEngine, KvsError, Result, SledKvsEngine,
+    WriteBatch,
+};
+use serde_json::{json, Deserializer, Value};
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+// An `AsyncKvsServer` running on a runtime of its own, shut down when dropped
+struct Server {
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Server {
+    fn start<E, P>(engine: E, pool: P, addr: &'static str) -> Server
+    where
+        E: KvsEngine,
+        P: ThreadPool + Send + Sync + 'static,
+    {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = AsyncKvsServer::new(engine, pool).shutdown_timeout(Duration::from_secs(5));
+        let thread = thread::spawn(move || {
+            Runtime::new()?.block_on(server.run_until(addr, async {
+                let _ = stopped.await;
+            }))
+        });
+        thread::sleep(Duration::from_millis(500));
+        Server {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    // Shuts the server down, and waits for `run_until` to return.
+    fn shutdown(mut self) -> Result<()> {
+        let _ = self.stop.take().unwrap().send(());
+        self.thread.take().unwrap().join().unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[tokio::test]
+async fn async_client() -> Result<()> {
+    let addr = "127.0.0.1:4130";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        addr,
+    );
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert_eq!(client.protocol_version(), 2);
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.remove("key2".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(
+        client
+            .compare_and_swap("key2".to_owned(), None, Some("value2".to_owned()))
+            .await?
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    client.batch(batch).await?;
+    assert_eq!(
+        client.scan_prefix("key".to_owned()).await?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    client
+        .set_with_ttl(
+            "key4".to_owned(),
+            "value4".to_owned(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(client.get("key4".to_owned()).await?, None);
+    drop(client);
+
+    // the admin requests are not offered to unauthenticated clients
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.stats(), Err(KvsError::Protocol(_))));
+    assert!(matches!(client.compact(), Err(KvsError::Protocol(_))));
+    Ok(())
+}
+
+// Idle connections should not hold the only thread of the pool
+#[tokio::test]
+async fn idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4131";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        SledKvsEngine::open(temp_dir.path())?,
+        RayonThreadPool::new(1)?,
+        addr,
+    );
+    let mut idle = Vec::new();
+    for _ in 0..500 {
+        idle.push(AsyncKvsClient::connect(addr).await?);
+    }
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        idle[0].get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Should speak both protocols of the blocking server
+#[test]
+fn blocking_clients() -> Result<()> {
+    let addr = "127.0.0.1:4132";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = Server::start(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        addr,
+    );
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let mut pipeline = client.pipeline();
+    pipeline.set("key3".to_owned(), "value3".to_owned())?;
+    pipeline.get("key3".to_owned())?;
+    pipeline.remove("key4".to_owned())?;
+    let results = pipeline.finish()?;
+    assert!(matches!(results[0], Ok(None)));
+    assert_eq!(results[1].as_ref().unwrap(), &Some(b"value3".to_vec()));
+    assert!(matches!(results[2], Err(KvsError::KeyNotFound)));
+
+    // the requests arrive in two parts, splitting the second one
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set": {"key": "key1", "value": "value1"}} {"Get": "#)?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(br#"{"key": "key2"}} {"Set": {"key": "key4", "value": "}\"{"#)?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(br#"["}} {"Scan": {"Prefix": "key"}}"#)?;
+    let responses: Vec<Value> = Deserializer::from_reader(&mut stream)
+        .into_iter()
+        .take(8)
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(
+        responses,
+        vec![
+            json!({"Ok": null}),
+            json!({"Ok": "value2"}),
+            json!({"Ok": null}),
+            json!({"Entry": ["key1", "value1"]}),
+            json!({"Entry": ["key2", "value2"]}),
+            json!({"Entry": ["key3", "value3"]}),
+            json!({"Entry": ["key4", "}\"{["]}),
+            json!({"Ok": null}),
+        ]
+    );
+
+    // a request which never ends is cut off once it is too long
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set": {"key": "key5", "value": ""#)?;
+    let chunk = vec![b'a'; 1024 * 1024];
+    assert!((0..80).any(|_| stream.write_all(&chunk).is_err()));
+    Ok(())
+}
+
+#[test]
+fn shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4133";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        addr,
+    );
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    // an idle connection does not hold the shutdown back
+    server.shutdown()?;
+    assert!(TcpStream::connect(addr).is_err());
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // the active generation was sealed with a hint
+    assert!(temp_dir.path().join("1.hint").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
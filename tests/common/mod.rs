@@ -0,0 +1,9 @@
+//! Helpers shared by the integration tests.
+
+/// Turns string pairs into the owned pairs returned by scans.
+pub fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+    entries
+        .iter()
+        .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
@@ -1,9 +1,21 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Durability, ErrorKind, KvStore, KvStoreOptions, KvsEngine, KvsError, RecoveryMode, Result,
+    SledKvsEngine, WriteBatch,
+};
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::mem;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+mod common;
+
+use common::pairs;
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -67,7 +79,10 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
     Ok(())
 }
 
@@ -209,3 +224,794 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should split the log into several generation files and restore them all
+#[test]
+fn log_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    for i in 0..2000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    let segments = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(OsStr::new("log")))
+        .count();
+    assert!(segments > 1, "log is not split into segments");
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Should restore a store written as a single `log.json`
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.json"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("log.json").exists());
+
+    Ok(())
+}
+
+// Should drop a command cut off at the end of a legacy `log.json`, or refuse to if asked to
+#[test]
+fn open_torn_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.json"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","val"#,
+    )?;
+
+    let options = KvStoreOptions::default().recovery(RecoveryMode::Refuse);
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options),
+        Err(e) if e.kind() == ErrorKind::Corruption
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Reads and writes should keep working while the log is being compacted
+#[test]
+fn concurrent_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", 0))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for iter in 0..200 {
+                let key = format!("key{}", (iter + thread_id) % 100);
+                let value = store.get(key.clone()).unwrap();
+                assert!(value.is_some(), "{} is lost", key);
+                if thread_id % 2 == 0 {
+                    store.set(key, format!("value{}", iter)).unwrap();
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for _ in 0..10 {
+        store.compact()?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert!(store.get(format!("key{}", i))?.is_some());
+    }
+
+    Ok(())
+}
+
+// Should detect a damaged record instead of returning wrong data
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip a byte of the first value
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    let pos = content
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("value not found in the log");
+    content[pos] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    // the hint lets the store open without reading the value
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.get("key1".to_owned()), Err(e) if e.kind() == ErrorKind::Corruption));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // replaying the log finds the damage
+    fs::remove_file(temp_dir.path().join("1.hint"))?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(e) if e.kind() == ErrorKind::Corruption
+    ));
+
+    Ok(())
+}
+
+// Should fail a read whose index entry points past the end of the log
+#[test]
+fn detect_missing_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the hint still has the key once the log is emptied
+    let store = KvStore::open(temp_dir.path())?;
+    fs::write(temp_dir.path().join("1.log"), b"")?;
+    assert!(matches!(store.get("key1".to_owned()), Err(e) if e.kind() == ErrorKind::Corruption));
+    Ok(())
+}
+
+// Should cut a torn write at the end of the log back to the last good record
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // lose the end of the last record
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should leave a damaged log untouched if asked to
+#[test]
+fn refuse_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = OpenOptions::new().append(true).open(&log_path)?;
+    log.write_all(&[7, 0, 0])?;
+    drop(log);
+    let len = fs::metadata(&log_path)?.len();
+
+    let options = KvStoreOptions::default().recovery(RecoveryMode::Refuse);
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options),
+        Err(e) if e.kind() == ErrorKind::Corruption
+    ));
+    assert_eq!(fs::metadata(&log_path)?.len(), len);
+
+    Ok(())
+}
+
+// Should restore the index from the hint files and the log written after them
+#[test]
+fn open_with_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    // write more without a clean shutdown, so it is not covered by the hint
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "new_value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new_value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for i in 2..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Should fall back to the log if a hint file is damaged
+#[test]
+fn ignore_damaged_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join("1.hint");
+    let mut content = fs::read(&hint_path)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&hint_path, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+fn concurrent_durable_set<E: KvsEngine>(engine: E) -> Result<()> {
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let engine = engine.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..20 {
+                engine
+                    .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Should keep all writes with every durability option
+#[test]
+fn durability() -> Result<()> {
+    let modes = vec![
+        Durability::None,
+        Durability::EveryWrite,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::GroupCommit,
+    ];
+    for durability in modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().durability(durability);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        concurrent_durable_set(store.clone())?;
+        store.remove("key0-0".to_owned())?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0-0".to_owned())?, None);
+        for thread_id in 0..8 {
+            for i in 1..20 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", thread_id, i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sled_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), Durability::GroupCommit)?;
+    concurrent_durable_set(engine.clone())?;
+    for thread_id in 0..8 {
+        assert_eq!(
+            engine.get(format!("key{}-19", thread_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+fn scan_keys<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in &["b/2", "a/1", "b/1", "c", "b/3", "a/2"] {
+        engine.set(key.to_string(), format!("v{}", key))?;
+    }
+    engine.remove("b/2".to_owned())?;
+    engine.set("a/1".to_owned(), "new".to_owned())?;
+
+    assert_eq!(
+        engine.scan_prefix("b/".to_owned())?,
+        pairs(&[("b/1", "vb/1"), ("b/3", "vb/3")])
+    );
+    assert_eq!(
+        engine.scan_prefix("a".to_owned())?,
+        pairs(&[("a/1", "new"), ("a/2", "va/2")])
+    );
+    assert_eq!(engine.scan_prefix("d".to_owned())?, pairs(&[]));
+    assert_eq!(
+        engine.scan("a/2".to_owned().."b/3".to_owned())?,
+        pairs(&[("a/2", "va/2"), ("b/1", "vb/1")])
+    );
+    assert_eq!(
+        engine.scan("b/3".to_owned()..)?,
+        pairs(&[("b/3", "vb/3"), ("c", "vc")])
+    );
+    assert_eq!(engine.scan(..)?.len(), 5);
+    assert_eq!(engine.scan("c".to_owned().."a".to_owned())?, pairs(&[]));
+    assert_eq!(
+        engine
+            .scan_keys(b"a/2".to_vec()..=b"b/3".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![b"a/2".to_vec(), b"b/1".to_vec(), b"b/3".to_vec()]
+    );
+    Ok(())
+}
+
+// Should list keys in order within a range or with a prefix
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    scan_keys(store.clone())?;
+
+    // Compact and open from disk again, then scan again
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan_prefix("b/".to_owned())?,
+        pairs(&[("b/1", "vb/1"), ("b/3", "vb/3")])
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_keys(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Should read a large scan as it goes, seeing the writes made to the keys not read yet
+#[test]
+fn scan_large_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let mut entries = store.scan_bytes(..)?;
+    let (key, _) = entries.next().unwrap()?;
+    assert_eq!(key, b"key0000");
+    store.remove("key1500".to_owned())?;
+    store.set("key1999".to_owned(), "new".to_owned())?;
+    let rest = entries.collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest.len(), 1998);
+    assert!(rest.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(rest.last().unwrap().1, b"new");
+
+    // prefixes of 0xff bytes end past every other key
+    store.set_bytes(vec![0xff, 0xff], vec![1])?;
+    store.set_bytes(vec![0xff, 0xff, 0x00], vec![2])?;
+    assert_eq!(
+        store
+            .scan_prefix_bytes(vec![0xff])?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            (vec![0xff, 0xff], vec![1]),
+            (vec![0xff, 0xff, 0x00], vec![2])
+        ]
+    );
+    Ok(())
+}
+
+fn apply_batches<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "new".to_owned());
+    batch.set("key3".to_owned(), "newer".to_owned());
+    batch.remove("key4".to_owned());
+    assert_eq!(batch.len(), 5);
+    engine.apply_batch(batch)?;
+    engine.apply_batch(WriteBatch::new())?;
+
+    assert_eq!(
+        engine.scan(..)?,
+        pairs(&[("key2", "new"), ("key3", "newer")])
+    );
+    Ok(())
+}
+
+// Should apply all writes of a batch
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    apply_batches(store.clone())?;
+
+    // Open from disk again, without and with hints
+    mem::forget(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan(..)?,
+        pairs(&[("key2", "new"), ("key3", "newer")])
+    );
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan(..)?,
+        pairs(&[("key2", "new"), ("key3", "newer")])
+    );
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    apply_batches(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Should drop a batch which was not completely written before a crash
+#[test]
+fn recover_incomplete_batch() -> Result<()> {
+    // a torn last command, and a batch missing its last command
+    let set_record_len = 21;
+    for &cut in &[3, set_record_len] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("k1".to_owned(), "v1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.remove("k1".to_owned());
+        batch.set("k2".to_owned(), "v2".to_owned());
+        batch.set("k3".to_owned(), "v3".to_owned());
+        store.apply_batch(batch)?;
+        mem::forget(store);
+
+        let log_path = temp_dir.path().join("1.log");
+        let len = fs::metadata(&log_path)?.len();
+        let log = OpenOptions::new().write(true).open(&log_path)?;
+        log.set_len(len - cut)?;
+        drop(log);
+
+        let options = KvStoreOptions::default().recovery(RecoveryMode::Refuse);
+        assert!(matches!(
+            KvStore::open_with(temp_dir.path(), options),
+            Err(e) if e.kind() == ErrorKind::Corruption
+        ));
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(..)?, pairs(&[("k1", "v1")]));
+        store.set("k4".to_owned(), "v4".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(..)?, pairs(&[("k1", "v1"), ("k4", "v4")]));
+    }
+    Ok(())
+}
+
+fn compare_and_swap_counter<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "counter".to_owned();
+    assert!(!engine.compare_and_swap(key(), Some("0".to_owned()), Some("1".to_owned()))?);
+    assert!(engine.compare_and_swap(key(), None, Some("0".to_owned()))?);
+    assert!(!engine.compare_and_swap(key(), None, Some("1".to_owned()))?);
+    assert_eq!(engine.get(key())?, Some("0".to_owned()));
+
+    // increment the counter from many threads, interleaved with plain writes
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let engine = engine.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..50 {
+                loop {
+                    let current = engine.get(key()).unwrap().unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if engine
+                        .compare_and_swap(key(), Some(current), Some(next))
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+                engine
+                    .set(format!("key{}", thread_id), format!("value{}", i))
+                    .unwrap();
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get(key())?, Some("400".to_owned()));
+
+    assert!(!engine.compare_and_swap(key(), Some("0".to_owned()), None)?);
+    assert!(engine.compare_and_swap(key(), Some("400".to_owned()), None)?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+    Ok(())
+}
+
+// Should swap values atomically with respect to concurrent writes
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compare_and_swap_counter(store.clone())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value49".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_counter(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn expire_keys<E: KvsEngine>(engine: E) -> Result<()> {
+    let ttl = Duration::from_millis(300);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl("key1".to_owned(), "short".to_owned(), ttl)?;
+    engine.set_with_ttl("key2".to_owned(), "long".to_owned(), ttl * 100)?;
+    engine.set_with_ttl("key3".to_owned(), "short".to_owned(), ttl)?;
+    engine.set_with_ttl("key4".to_owned(), "short".to_owned(), ttl)?;
+    // setting again without a TTL makes the key persistent
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("short".to_owned()));
+    assert_eq!(engine.scan(..)?.len(), 4);
+    let expire_at = match engine.get_bytes_with_expiry(b"key2".to_vec())? {
+        Some((value, Some(expire_at))) if value == b"long" => expire_at,
+        entry => panic!("unexpected entry {:?}", entry),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    assert!(expire_at > now.as_millis() as u64 + (ttl * 99).as_millis() as u64);
+    assert_eq!(
+        engine.get_bytes_with_expiry(b"key4".to_vec())?,
+        Some((b"value4".to_vec(), None))
+    );
+    assert_eq!(engine.get_bytes_with_expiry(b"key5".to_vec())?, None);
+    assert!(engine.compare_and_swap_bytes_with_ttl(
+        b"key5".to_vec(),
+        None,
+        b"short".to_vec(),
+        ttl
+    )?);
+    assert!(!engine.compare_and_swap_bytes_with_ttl(
+        b"key5".to_vec(),
+        None,
+        b"other".to_vec(),
+        ttl
+    )?);
+    assert!(matches!(
+        engine.get_bytes_with_expiry(b"key5".to_vec())?,
+        Some((value, Some(_))) if value == b"short"
+    ));
+
+    thread::sleep(ttl + Duration::from_millis(100));
+    assert_eq!(engine.get("key5".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("long".to_owned()));
+    assert_eq!(
+        engine.scan(..)?,
+        pairs(&[("key2", "long"), ("key4", "value4")])
+    );
+    assert!(engine.remove("key3".to_owned()).is_err());
+    assert!(engine.compare_and_swap("key3".to_owned(), None, Some("value3".to_owned()))?);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should hide keys once their TTL is over
+#[test]
+fn expire_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    expire_keys(store.clone())?;
+
+    // Open from disk again, without and with hints
+    let expected = pairs(&[("key2", "long"), ("key3", "value3"), ("key4", "value4")]);
+    mem::forget(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?, expected);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?, expected);
+
+    // compaction drops the expired keys, without bringing back older values
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?, expected);
+    Ok(())
+}
+
+#[test]
+fn sled_expire_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Should report a damaged expiry time instead of panicking
+#[test]
+fn sled_corrupted_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    db.insert(&b"key1"[..], &b"value1"[..])?;
+    db.open_tree("kvs-expiry")?
+        .insert(&b"key1"[..], &[0x01][..])?;
+    drop(db);
+    // sled releases its lock from a background thread, shortly after the drop
+    let mut retries = 0;
+    let engine = loop {
+        match SledKvsEngine::open(temp_dir.path()) {
+            Err(KvsError::Sled(_)) if retries < 100 => {
+                retries += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            engine => break engine?,
+        }
+    };
+    let corrupted =
+        |result: Result<_>| matches!(result, Err(e) if e.kind() == ErrorKind::Corruption);
+    assert!(corrupted(engine.get("key1".to_owned()).map(drop)));
+    assert!(corrupted(
+        engine.get_bytes_with_expiry(b"key1".to_vec()).map(drop)
+    ));
+    assert!(corrupted(engine.scan(..).map(drop)));
+    Ok(())
+}
+
+// Should reclaim the space of expired keys in the background
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let value = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set_with_ttl(
+            format!("key{}", i),
+            value.clone(),
+            Duration::from_millis(100),
+        )?;
+    }
+    let size = dir_size();
+
+    // the sweeper runs every second, then compaction runs in the background
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        if dir_size() < size / 10 {
+            return Ok(());
+        }
+    }
+    panic!("expired keys are not reclaimed");
+}
+
+fn binary_keys_values<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = vec![0xff, 0x00, b'"', 0x80];
+    let value = vec![0x00, 0xfe, b'\\', 0xc3];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(vec![0xff, 0x01], vec![0x01])?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+
+    // the string API cannot return such a value
+    engine.set_bytes(b"text".to_vec(), vec![0xff])?;
+    assert!(matches!(
+        engine.get("text".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+
+    assert_eq!(
+        engine
+            .scan_prefix_bytes(vec![0xff])?
+            .collect::<Result<Vec<_>>>()?,
+        vec![(key.clone(), value), (vec![0xff, 0x01], vec![0x01])]
+    );
+    engine.remove_bytes(key.clone())?;
+    assert_eq!(engine.get_bytes(key)?, None);
+    Ok(())
+}
+
+// Should store keys and values which are not valid UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    binary_keys_values(&store)?;
+
+    // the hint and the log written after it keep the bytes too
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(vec![0xfe], vec![0xfd])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xff, 0x01])?, Some(vec![0x01]));
+    assert_eq!(store.get_bytes(vec![0xfe])?, Some(vec![0xfd]));
+    Ok(())
+}
+
+#[test]
+fn sled_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_values(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    // bytes stored in the tree by another program are read as they are
+    let db = sled::open(temp_dir.path().join("raw"))?;
+    db.insert(&[0xff][..], &[0x80, 0x81][..])?;
+    drop(db);
+    // sled releases its lock from a background thread, shortly after the drop
+    let mut retries = 0;
+    let engine = loop {
+        match SledKvsEngine::open(temp_dir.path().join("raw")) {
+            Err(KvsError::Sled(_)) if retries < 100 => {
+                retries += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            engine => break engine?,
+        }
+    };
+    assert_eq!(engine.get_bytes(vec![0xff])?, Some(vec![0x80, 0x81]));
+    Ok(())
+}
@@ -0,0 +1,1796 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ClientOptions, ClientTls, Credentials, ErrorKind, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, Result, ServerTls, ShutdownHandle, SledKvsEngine, WriteBatch,
+};
+use predicates::str::{contains, starts_with};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_json::{json, Deserializer, Value};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+use common::pairs;
+
+// A `kvs-server` running in a temporary directory, killed when dropped
+struct Server {
+    child: Child,
+    _temp_dir: TempDir,
+}
+
+impl Server {
+    fn start(engine: &str, addr: &str) -> Server {
+        Server::start_with(engine, addr, &[])
+    }
+
+    fn start_with(engine: &str, addr: &str, args: &[&str]) -> Server {
+        let temp_dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server {
+            child,
+            _temp_dir: temp_dir,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child.wait().unwrap();
+    }
+}
+
+fn client_batch(engine: &str, addr: &str) -> Result<()> {
+    let _server = Server::start(engine, addr);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    client.batch(batch)?;
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(
+        client.scan_prefix("key".to_owned())?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn client_batch_kvs_engine() -> Result<()> {
+    client_batch("kvs", "127.0.0.1:4101")
+}
+
+#[test]
+fn client_batch_sled_engine() -> Result<()> {
+    client_batch("sled", "127.0.0.1:4102")
+}
+
+#[test]
+fn client_compare_and_swap() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    let _server = Server::start("kvs", addr);
+    let mut client = KvsClient::connect(addr)?;
+    let key = || "key1".to_owned();
+
+    assert!(client.compare_and_swap(key(), None, Some("value1".to_owned()))?);
+    assert!(!client.compare_and_swap(key(), None, Some("value2".to_owned()))?);
+    assert!(client.compare_and_swap(
+        key(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(client.get(key())?, Some("value2".to_owned()));
+    assert!(client.compare_and_swap(key(), Some("value2".to_owned()), None)?);
+    assert_eq!(client.get(key())?, None);
+    Ok(())
+}
+
+// Should carry keys and values which are not valid UTF-8
+#[test]
+fn client_binary_keys_and_values() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let _server = Server::start("kvs", addr);
+    let mut client = KvsClient::connect(addr)?;
+    let key = vec![0xff, 0x00, b'"'];
+    let value: Vec<u8> = (0..=255).collect();
+
+    client.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(
+        client.scan_bytes(vec![0xff]..)?,
+        vec![(key.clone(), value.clone())]
+    );
+    assert!(client.get_bytes(vec![0xfe])?.is_none());
+
+    // the string API fails on the value, but leaves the connection usable
+    client.set_bytes(b"text".to_vec(), value)?;
+    assert!(client.get("text".to_owned()).is_err());
+    client.remove_bytes(key.clone())?;
+    assert_eq!(client.get_bytes(key)?, None);
+    Ok(())
+}
+
+// Should stream a scan larger than the chunks the server reads, then serve the next request
+#[test]
+fn client_scan_large_range() -> Result<()> {
+    let addr = "127.0.0.1:4149";
+    let _server = Server::start("kvs", addr);
+    let mut client = KvsClient::connect(addr)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..3000 {
+        batch.set(format!("key{:04}", i), format!("value{}", i));
+    }
+    client.batch(batch)?;
+
+    let entries = client.scan("key1000".to_owned()..)?;
+    assert_eq!(entries.len(), 2000);
+    assert_eq!(entries[0], ("key1000".to_owned(), "value1000".to_owned()));
+    assert_eq!(
+        entries[1999],
+        ("key2999".to_owned(), "value2999".to_owned())
+    );
+    assert_eq!(client.scan_prefix("key29".to_owned())?.len(), 100);
+    assert_eq!(client.get("key0000".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// Should tell the kind of an error which happened in the server
+#[test]
+fn client_error_kinds() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let _server = Server::start("sled", addr);
+    let mut client = KvsClient::connect(addr)?;
+
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    // a value which is not UTF-8 fails in the client itself
+    client.set_bytes(b"key1".to_vec(), vec![0xff])?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    client.remove("key1".to_owned())?;
+    assert_eq!(
+        client.remove("key1".to_owned()).map_err(|e| e.kind()),
+        Err(ErrorKind::KeyNotFound)
+    );
+    Ok(())
+}
+
+// Should agree on a protocol version and capabilities with the server
+#[test]
+fn client_handshake() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let _server = Server::start("kvs", addr);
+    let client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), 2);
+    for cap in &["ttl", "cas", "batch", "scan"] {
+        assert!(client.capabilities().iter().any(|c| c == cap));
+    }
+    Ok(())
+}
+
+// Should keep serving clients of the JSON protocol
+#[test]
+fn legacy_json_client() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    let _server = Server::start("kvs", addr);
+    // the server may have a single worker thread, so connections take turns
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let requests = vec![
+        json!({"Set": {"key": "key1", "value": "value1"}}),
+        json!({"Get": {"key": "key1"}}),
+        json!({"Get": {"key": "key2"}}),
+        json!({"Rm": {"key": "key3"}}),
+        json!({"Scan": {"Prefix": "key"}}),
+    ];
+    for request in &requests {
+        serde_json::to_writer(&mut stream, request)?;
+    }
+    stream.flush()?;
+    let responses: Vec<Value> = Deserializer::from_reader(&mut stream)
+        .into_iter()
+        .take(7)
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(
+        responses,
+        vec![
+            json!({"Ok": null}),
+            json!({"Ok": "value1"}),
+            json!({"Ok": "value2"}),
+            json!({"Err": "Key not found"}),
+            json!({"Entry": ["key1", "value1"]}),
+            json!({"Entry": ["key2", "value2"]}),
+            json!({"Ok": null}),
+        ]
+    );
+    drop(stream);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // the request arrives in parts, splitting a string with brackets in it
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#" {"Set": {"key": "key4", "value": "}\"{"#)?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(br#"["}} {"Get": {"key": "key4"}}"#)?;
+    let responses: Vec<Value> = Deserializer::from_reader(&mut stream)
+        .into_iter()
+        .take(2)
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(responses, vec![json!({"Ok": null}), json!({"Ok": "}\"{["})]);
+    drop(stream);
+
+    // a request which never ends is cut off once it is too long
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set": {"key": "key5", "value": ""#)?;
+    let chunk = vec![b'a'; 1024 * 1024];
+    assert!((0..80).any(|_| stream.write_all(&chunk).is_err()));
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+// Should reject a client with no common protocol version, and skip a request which
+// cannot be decoded
+#[test]
+fn protocol_frames() -> Result<()> {
+    let addr = "127.0.0.1:4108";
+    let _server = Server::start("kvs", addr);
+
+    // Hello { min_version: 99, max_version: 99, capabilities: [] }
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP")?;
+    write_frame(&mut stream, &[99, 99, 0])?;
+    // HelloReply::Reject
+    assert_eq!(read_frame(&mut stream)?[0], 1);
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    drop(stream);
+
+    // Hello { min_version: 1, max_version: 1, capabilities: [] }
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP")?;
+    write_frame(&mut stream, &[1, 1, 0])?;
+    // HelloReply::Accept { version: 1, capabilities: [] }
+    assert_eq!(read_frame(&mut stream)?, vec![0, 1, 0]);
+
+    // no request has variant 200
+    write_frame(&mut stream, &[200])?;
+    // Request::Get { key: b"a" }
+    write_frame(&mut stream, &[3, 1, b'a'])?;
+    // Response::Err(ErrorKind::Protocol, ..)
+    assert_eq!(read_frame(&mut stream)?[..2], [3, 5]);
+    // Response::Ok(None)
+    assert_eq!(read_frame(&mut stream)?, vec![0, 0]);
+    drop(stream);
+
+    // from version 2 on, a response starts with the ID of its request
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP")?;
+    write_frame(&mut stream, &[1, 2, 0])?;
+    assert_eq!(read_frame(&mut stream)?, vec![0, 2, 0]);
+    let mut request = 7u64.to_le_bytes().to_vec();
+    request.extend_from_slice(&[3, 1, b'a']);
+    write_frame(&mut stream, &request)?;
+    let mut response = 7u64.to_le_bytes().to_vec();
+    response.extend_from_slice(&[0, 0]);
+    assert_eq!(read_frame(&mut stream)?, response);
+    Ok(())
+}
+
+// Should send many requests before reading their responses
+#[test]
+fn client_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let _server = Server::start("kvs", addr);
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let results = pipeline.finish()?;
+    assert_eq!(results.len(), 1000);
+    assert!(results.iter().all(|result| matches!(result, Ok(None))));
+
+    let mut pipeline = client.pipeline();
+    pipeline.get("key1".to_owned())?;
+    pipeline.remove("key2".to_owned())?;
+    pipeline.remove("key2".to_owned())?;
+    pipeline.get("key2".to_owned())?;
+    pipeline.get("key999".to_owned())?;
+    let mut results = pipeline.finish()?.into_iter();
+    assert_eq!(results.next().unwrap()?, Some(b"value1".to_vec()));
+    assert_eq!(results.next().unwrap()?, None);
+    assert!(matches!(results.next(), Some(Err(KvsError::KeyNotFound))));
+    assert_eq!(results.next().unwrap()?, None);
+    assert_eq!(results.next().unwrap()?, Some(b"value999".to_vec()));
+
+    // the responses of a pipeline given up on are not mixed up with later ones
+    let mut pipeline = client.pipeline();
+    pipeline.get("key3".to_owned())?;
+    pipeline.set("key4".to_owned(), "value".to_owned())?;
+    drop(pipeline);
+    assert_eq!(client.get("key4".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Sends RESP commands and checks the raw replies.
+fn resp_exchange(stream: &mut TcpStream, exchanges: &[(&[u8], &[u8])]) -> Result<()> {
+    for (command, reply) in exchanges {
+        stream.write_all(command)?;
+        let mut buf = vec![0; reply.len()];
+        stream.read_exact(&mut buf)?;
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(reply),
+            "reply to {}",
+            String::from_utf8_lossy(command)
+        );
+    }
+    Ok(())
+}
+
+// Should serve clients of the Redis protocol on the side
+fn resp_commands(engine: &str, addr: &str, resp_addr: &str) -> Result<()> {
+    let _server = Server::start_with(engine, addr, &["--resp-addr", resp_addr]);
+    let mut stream = TcpStream::connect(resp_addr)?;
+    resp_exchange(
+        &mut stream,
+        &[
+            (b"PING\r\n", b"+PONG\r\n"),
+            (b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n", b"$2\r\nhi\r\n"),
+            (
+                b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+                b"+OK\r\n",
+            ),
+            (b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", b"$6\r\nvalue1\r\n"),
+            (b"GET missing\r\n", b"$-1\r\n"),
+            // binary-safe values, in pipelined commands
+            (
+                b"*3\r\n$3\r\nset\r\n$4\r\nkey2\r\n$3\r\n\x00\r\xff\r\n*2\r\n$3\r\nget\r\n$4\r\nkey2\r\n",
+                b"+OK\r\n$3\r\n\x00\r\xff\r\n",
+            ),
+            (b"SET key3 value3 NX\r\n", b"+OK\r\n"),
+            (b"SET key3 other NX\r\n", b"$-1\r\n"),
+            (b"SET key4 value4 XX\r\n", b"$-1\r\n"),
+            (b"SET key3 value3 XX\r\n", b"+OK\r\n"),
+            (b"EXISTS key1 key2 missing key1\r\n", b":3\r\n"),
+            (b"KEYS key[12]\r\n", b"*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"),
+            (
+                b"KEYS *\r\n",
+                b"*3\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n",
+            ),
+            // the cursor is the last key returned, "key2"
+            (
+                b"SCAN 0 COUNT 2\r\n",
+                b"*2\r\n$13\r\n1107101121050\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+            ),
+            (
+                b"SCAN 1107101121050 COUNT 2\r\n",
+                b"*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey3\r\n",
+            ),
+            (b"SCAN 2\r\n", b"-ERR invalid cursor\r\n"),
+            (
+                b"SCAN 0 MATCH *3\r\n",
+                b"*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey3\r\n",
+            ),
+            (b"KEYS *k*e*y*[^12]\r\n", b"*1\r\n$4\r\nkey3\r\n"),
+            (b"KEYS *a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b\r\n", b"*0\r\n"),
+            (b"DEL key1 key2 missing\r\n", b":2\r\n"),
+            (b"EXISTS key1\r\n", b":0\r\n"),
+            (b"SET key4 value4 PX 100\r\n", b"+OK\r\n"),
+            (b"SET key5 value5 NX PX 100\r\n", b"+OK\r\n"),
+            (b"SET key5 other NX PX 100\r\n", b"$-1\r\n"),
+            (b"SET key6 value6 XX EX 1\r\n", b"$-1\r\n"),
+            (b"SET key6 value6\r\n", b"+OK\r\n"),
+            (b"SET key6 other XX EX 1\r\n", b"+OK\r\n"),
+            (b"GET key6\r\n", b"$5\r\nother\r\n"),
+            (b"EXPIRE key3 1\r\n", b":1\r\n"),
+            (b"EXPIRE missing 1\r\n", b":0\r\n"),
+            (b"GET key3\r\n", b"$6\r\nvalue3\r\n"),
+            (b"SET key5 value5 EX 0\r\n", b"-ERR invalid expire time in 'set' command\r\n"),
+            (b"GET\r\n", b"-ERR wrong number of arguments for 'get' command\r\n"),
+            (b"FLUSHALL\r\n", b"-ERR unknown command 'flushall'\r\n"),
+        ],
+    )?;
+    let mut keys = b"KEYS ".to_vec();
+    keys.extend_from_slice(&[b'*'; 1025]);
+    keys.extend_from_slice(b"\r\n");
+    resp_exchange(
+        &mut stream,
+        &[(&keys, b"-ERR pattern longer than 1024 bytes\r\n")],
+    )?;
+    thread::sleep(Duration::from_millis(1100));
+    resp_exchange(
+        &mut stream,
+        &[(b"EXISTS key3 key4 key5 key6\r\n", b":0\r\n")],
+    )?;
+    stream.write_all(b"INFO\r\nQUIT\r\n")?;
+    let mut info = String::new();
+    stream.read_to_string(&mut info)?;
+    assert!(info.starts_with('$'));
+    assert!(info.contains("kvs_version:"));
+    assert!(info.ends_with("+OK\r\n"));
+
+    // the RESP listener shares the store with the binary protocol
+    let mut stream = TcpStream::connect(resp_addr)?;
+    resp_exchange(
+        &mut stream,
+        &[(b"SET shared yes\r\nQUIT\r\n", b"+OK\r\n+OK\r\n")],
+    )?;
+    assert_eq!(stream.read(&mut [0])?, 0);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("shared".to_owned())?, Some("yes".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn resp_commands_kvs_engine() -> Result<()> {
+    resp_commands("kvs", "127.0.0.1:4110", "127.0.0.1:4111")
+}
+
+#[test]
+fn resp_commands_sled_engine() -> Result<()> {
+    resp_commands("sled", "127.0.0.1:4112", "127.0.0.1:4113")
+}
+
+// Should close a Redis connection which does not follow the protocol
+#[test]
+fn resp_protocol_error() -> Result<()> {
+    let addr = "127.0.0.1:4114";
+    let resp_addr = "127.0.0.1:4115";
+    let _server = Server::start_with("kvs", addr, &["--resp-addr", resp_addr]);
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.write_all(b"*1\r\n+PING\r\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(reply, "-ERR Protocol error: expected '$', got '+'\r\n");
+
+    // a connection ending in the middle of a line is not taken for a too big command
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.write_all(b"GET ke")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(
+        reply,
+        "-ERR Protocol error: connection closed in the middle of a command\r\n"
+    );
+    Ok(())
+}
+
+// Should not undo a SET racing with an EXPIRE of the same key
+#[test]
+fn resp_expire_racing_set() -> Result<()> {
+    let addr = "127.0.0.1:4155";
+    let resp_addr = "127.0.0.1:4156";
+    let temp_dir = TempDir::new().unwrap();
+    // a connection holds a thread of the pool, and both race
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .resp_addr(resp_addr)?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    resp_exchange(&mut stream, &[(b"SET key value0\r\n", b"+OK\r\n")])?;
+
+    let expiring = thread::spawn(move || -> Result<()> {
+        let mut stream = TcpStream::connect(resp_addr)?;
+        for _ in 0..200 {
+            resp_exchange(&mut stream, &[(b"EXPIRE key 100\r\n", b":1\r\n")])?;
+        }
+        Ok(())
+    });
+    for i in 1..200 {
+        let set = format!("SET key value{}\r\n", i);
+        resp_exchange(&mut stream, &[(set.as_bytes(), b"+OK\r\n")])?;
+    }
+    expiring.join().unwrap()?;
+    resp_exchange(&mut stream, &[(b"GET key\r\n", b"$8\r\nvalue199\r\n")])?;
+    drop(stream);
+
+    handle.shutdown();
+    server_thread.join().unwrap()
+}
+
+// Sends an HTTP request on a connection of its own, returning the status and the body.
+fn http(addr: &str, method: &str, target: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        addr,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("no end of head")
+        + 4;
+    let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+    Ok((status, response.split_off(head_len)))
+}
+
+fn http_json(addr: &str, target: &str) -> Result<(u16, Value)> {
+    let (status, body) = http(addr, "GET", target, b"")?;
+    Ok((status, serde_json::from_slice(&body)?))
+}
+
+// Should serve the HTTP gateway on the side
+#[test]
+fn http_gateway() -> Result<()> {
+    let addr = "127.0.0.1:4116";
+    let http_addr = "127.0.0.1:4117";
+    let _server = Server::start_with("kvs", addr, &["--http-addr", http_addr]);
+
+    assert_eq!(
+        http(http_addr, "PUT", "/keys/key1", b"value1")?,
+        (204, vec![])
+    );
+    assert_eq!(
+        http(http_addr, "GET", "/keys/key1", b"")?,
+        (200, b"value1".to_vec())
+    );
+    // percent-encoded keys and binary values
+    assert_eq!(
+        http(http_addr, "PUT", "/keys/a%2Fb%00", b"\xff\x00")?.0,
+        204
+    );
+    assert_eq!(
+        http(http_addr, "GET", "/keys/a%2Fb%00", b"")?,
+        (200, b"\xff\x00".to_vec())
+    );
+    assert_eq!(http(http_addr, "DELETE", "/keys/a%2Fb%00", b"")?.0, 204);
+
+    let (status, body) = http_json(http_addr, "/keys/missing")?;
+    assert_eq!(status, 404);
+    assert_eq!(body["kind"], "KeyNotFound");
+    assert_eq!(http(http_addr, "DELETE", "/keys/missing", b"")?.0, 404);
+
+    for i in 2..5 {
+        let key = format!("/keys/key{}", i);
+        http(http_addr, "PUT", &key, format!("value{}", i).as_bytes())?;
+    }
+    http(http_addr, "PUT", "/keys/other", b"value")?;
+    assert_eq!(
+        http_json(http_addr, "/keys?prefix=key&limit=2")?,
+        (
+            200,
+            json!({
+                "entries": [
+                    {"key": "key1", "value": "value1"},
+                    {"key": "key2", "value": "value2"},
+                ],
+                "next": "key3",
+            })
+        )
+    );
+    assert_eq!(
+        http_json(http_addr, "/keys?prefix=key&limit=2&start=key3")?,
+        (
+            200,
+            json!({
+                "entries": [
+                    {"key": "key3", "value": "value3"},
+                    {"key": "key4", "value": "value4"},
+                ],
+                "next": null,
+            })
+        )
+    );
+    assert_eq!(
+        http_json(http_addr, "/keys?start=key2&end=key4")?,
+        (
+            200,
+            json!({
+                "entries": [
+                    {"key": "key2", "value": "value2"},
+                    {"key": "key3", "value": "value3"},
+                ],
+                "next": null,
+            })
+        )
+    );
+    assert_eq!(
+        http_json(http_addr, "/keys")?.1["entries"]
+            .as_array()
+            .unwrap()
+            .len(),
+        5
+    );
+
+    assert_eq!(
+        http(http_addr, "PUT", "/keys/key5?ttl=1", b"value5")?.0,
+        204
+    );
+    assert_eq!(http(http_addr, "GET", "/keys/key5", b"")?.0, 200);
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(http(http_addr, "GET", "/keys/key5", b"")?.0, 404);
+
+    assert_eq!(
+        http(http_addr, "PUT", "/keys/key6?ttl=x", b"value6")?.0,
+        400
+    );
+    assert_eq!(http(http_addr, "POST", "/keys/key1", b"")?.0, 405);
+    assert_eq!(http(http_addr, "GET", "/other", b"")?.0, 404);
+    http(http_addr, "PUT", "/keys/binary", b"\xff")?;
+    assert_eq!(http_json(http_addr, "/keys?prefix=binary")?.0, 406);
+    // a key which is only continued from is percent-encoded
+    http(http_addr, "PUT", "/keys/page1", b"value")?;
+    http(http_addr, "PUT", "/keys/page%FF", b"value")?;
+    assert_eq!(
+        http_json(http_addr, "/keys?prefix=page&limit=1")?,
+        (
+            200,
+            json!({
+                "entries": [{"key": "page1", "value": "value"}],
+                "next": "page%FF",
+            })
+        )
+    );
+
+    // the gateway shares the store with the binary protocol
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should keep an HTTP connection alive between requests
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let addr = "127.0.0.1:4118";
+    let http_addr = "127.0.0.1:4119";
+    let _server = Server::start_with("sled", addr, &["--http-addr", http_addr]);
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue1")?;
+    // empty lines between requests are skipped
+    stream.write_all(b"\r\n\r\n\nGET /keys/key1 HTTP/1.1\r\n\r\n")?;
+    stream.write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 3);
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.ends_with("Connection: close\r\n\r\nvalue1"));
+
+    // a body shorter than announced
+    drop(stream);
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"PUT /keys/key2 HTTP/1.1\r\nContent-Length: 1000000\r\n\r\nshort")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.contains("Connection closed in the middle of a request"));
+    Ok(())
+}
+
+// Should answer the requests already sent, then flush the engine and return from `run`
+#[test]
+fn shutdown_handle() -> Result<()> {
+    let addr = "127.0.0.1:4120";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .shutdown_timeout(Duration::from_secs(5));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut requests = Vec::new();
+    for i in 0..100 {
+        let request = json!({"Set": {"key": format!("key{}", i), "value": format!("value{}", i)}});
+        serde_json::to_writer(&mut requests, &request)?;
+    }
+    stream.write_all(&requests)?;
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    handle.shutdown();
+    // the connection stays open, so `run` waits for the responses to be sent
+    let responses: Vec<Value> = Deserializer::from_reader(&mut stream)
+        .into_iter()
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(responses, vec![json!({"Ok": null}); 100]);
+    server_thread.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(TcpStream::connect(addr).is_err());
+
+    // the active generation was sealed with a hint
+    assert!(temp_dir.path().join("1.hint").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Should not wait longer than the timeout for a connection in the middle of a request
+#[test]
+fn shutdown_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4121";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(
+        SledKvsEngine::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .shutdown_timeout(Duration::from_millis(500));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // half a frame, which the server keeps waiting for
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP\x10\x00")?;
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(3));
+    Ok(())
+}
+
+// Should shut down cleanly on SIGTERM
+#[test]
+fn server_sigterm() -> Result<()> {
+    let addr = "127.0.0.1:4122";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let set = KvsClient::connect(addr)
+        .and_then(|mut client| client.set("key1".to_owned(), "value1".to_owned()));
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            panic!("server still running after SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    set?;
+    assert!(status.success());
+    assert!(temp_dir.path().join("1.hint").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should close the connections which stay idle, freeing the worker they hold
+#[test]
+fn idle_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4123";
+    let _server = Server::start_with("kvs", addr, &["--idle-timeout", "1"]);
+    let mut idle = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    // the server may have a single worker thread, which the idle client holds first
+    let start = Instant::now();
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() < Duration::from_secs(3));
+    let mut buf = [0; 1];
+    assert_eq!(idle.read(&mut buf)?, 0);
+    Ok(())
+}
+
+// Should close the connections whose requests stop arriving
+#[test]
+fn read_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4124";
+    let http_addr = "127.0.0.1:4125";
+    let _server = Server::start_with(
+        "sled",
+        addr,
+        &["--http-addr", http_addr, "--read-timeout", "1"],
+    );
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"GET /keys/key1 HTTP/1.1\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+
+    // half a frame
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP\x10\x00")?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// Should turn the clients beyond the maximum away in their own protocol
+#[test]
+fn max_connections() -> Result<()> {
+    let addr = "127.0.0.1:4126";
+    let resp_addr = "127.0.0.1:4127";
+    let http_addr = "127.0.0.1:4128";
+    let _server = Server::start_with(
+        "kvs",
+        addr,
+        &[
+            "--resp-addr",
+            resp_addr,
+            "--http-addr",
+            http_addr,
+            "--max-connections",
+            "1",
+        ],
+    );
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvsClient::connect(addr) {
+        Err(KvsError::Protocol(reason)) => assert_eq!(reason, "Too many connections"),
+        other => panic!("connected beyond the maximum: {:?}", other.map(|_| ())),
+    }
+    let mut stream = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut stream, &json!({"Get": {"key": "key1"}}))?;
+    let response: Value = serde_json::from_reader(&mut stream)?;
+    assert_eq!(response, json!({"Err": "Too many connections"}));
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.write_all(b"GET key1\r\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+    let (status, body) = http(http_addr, "GET", "/keys/key1", b"")?;
+    assert_eq!(status, 503);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body)?,
+        json!({"kind": "Other", "error": "Too many connections"})
+    );
+
+    // a connection is free again once the client leaves
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should serve clients on a Unix domain socket as well as TCP
+#[test]
+fn unix_socket() -> Result<()> {
+    let addr = "127.0.0.1:4129";
+    let socket_dir = TempDir::new().unwrap();
+    let path = socket_dir.path().join("kvs.sock");
+    let unix_addr = format!("unix:{}", path.display());
+    let _server = Server::start_with("kvs", addr, &["--unix-socket", path.to_str().unwrap()]);
+
+    let mut client = KvsClient::connect(&unix_addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &unix_addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Ok(())
+}
+
+// Should replace a stale socket file, and remove its own on a shutdown
+#[test]
+fn unix_socket_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path)?);
+    assert!(path.exists());
+
+    let mut server = KvsServer::new(
+        SledKvsEngine::open(temp_dir.path().join("db"))?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let handle = server.shutdown_handle();
+    let unix_addr = format!("unix:{}", path.display());
+    let server_thread = {
+        let unix_addr = unix_addr.clone();
+        thread::spawn(move || server.run(unix_addr))
+    };
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = UnixStream::connect(&path)?;
+    serde_json::to_writer(
+        &mut stream,
+        &json!({"Set": {"key": "key1", "value": "value1"}}),
+    )?;
+    let response = Deserializer::from_reader(&mut stream)
+        .into_iter::<Value>()
+        .next()
+        .unwrap()?;
+    assert_eq!(response, json!({"Ok": null}));
+    drop(stream);
+    let mut client = KvsClient::connect(&unix_addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// Writes a CA, a certificate of the server for 127.0.0.1 and localhost and one of a
+// client signed by it, and another CA, as PEM files in `dir`
+fn write_certs(dir: &Path) {
+    let write = |name: &str, pem: String| fs::write(dir.join(name), pem).unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params.clone(), KeyPair::generate().unwrap()).unwrap();
+    write("ca.pem", ca.pem());
+    let other_ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+    write("other-ca.pem", other_ca.pem());
+
+    for (name, names) in [
+        (
+            "server",
+            vec!["localhost".to_owned(), "127.0.0.1".to_owned()],
+        ),
+        ("client", vec!["client".to_owned()]),
+    ] {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(names)
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        write(&format!("{}.pem", name), cert.pem());
+        write(&format!("{}.key", name), key.serialize_pem());
+    }
+}
+
+// Starts a `KvsServer` serving `addr` over TLS in another thread
+fn start_tls_server(
+    addr: &'static str,
+    dir: &Path,
+    tls: ServerTls,
+) -> Result<(ShutdownHandle, JoinHandle<Result<()>>)> {
+    let mut server = KvsServer::new(
+        KvStore::open(dir.join("db"))?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .tls(&tls)?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok((handle, server_thread))
+}
+
+#[test]
+fn tls() -> Result<()> {
+    let addr = "127.0.0.1:4134";
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    write_certs(dir);
+    let tls = ServerTls::from_pem_files(dir.join("server.pem"), dir.join("server.key"))?;
+    let (handle, server_thread) = start_tls_server(addr, dir, tls)?;
+
+    let client_tls = ClientTls::from_ca_file(dir.join("ca.pem"))?;
+    let mut client = KvsClient::connect_tls(addr, &client_tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    let client_tls = client_tls.server_name("localhost")?;
+    let mut client = KvsClient::connect_tls(addr, &client_tls)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // a certificate signed by another CA, or for another name, is not trusted
+    let untrusted = ClientTls::from_ca_file(dir.join("other-ca.pem"))?;
+    assert!(matches!(
+        KvsClient::connect_tls(addr, &untrusted),
+        Err(KvsError::Tls(_))
+    ));
+    let wrong_name = ClientTls::from_ca_file(dir.join("ca.pem"))?.server_name("example.com")?;
+    assert!(matches!(
+        KvsClient::connect_tls(addr, &wrong_name),
+        Err(KvsError::Tls(_))
+    ));
+    // nor is a plain client served
+    assert!(KvsClient::connect(addr).is_err());
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn tls_client_certificates() -> Result<()> {
+    let addr = "127.0.0.1:4135";
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    write_certs(dir);
+    let tls = ServerTls::from_pem_files(dir.join("server.pem"), dir.join("server.key"))?
+        .client_ca(dir.join("ca.pem"))?;
+    let (handle, server_thread) = start_tls_server(addr, dir, tls)?;
+
+    let client_tls = ClientTls::from_ca_file(dir.join("ca.pem"))?;
+    let refused = KvsClient::connect_tls(addr, &client_tls).and_then(|mut client| {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        Ok(client)
+    });
+    assert!(refused.is_err());
+
+    let client_tls = client_tls.identity(dir.join("client.pem"), dir.join("client.key"))?;
+    let mut client = KvsClient::connect_tls(addr, &client_tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Sends `request` over TLS to `addr`, trusting the CA of `dir`, and returns the response.
+fn tls_exchange(addr: &str, dir: &Path, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(dir.join("ca.pem")).unwrap())
+        .unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+        .unwrap();
+    let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+    stream.write_all(request)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+// Should serve the RESP and HTTP listeners over TLS too
+#[test]
+fn tls_gateways() -> Result<()> {
+    let addr = "127.0.0.1:4150";
+    let http_addr = "127.0.0.1:4151";
+    let resp_addr = "127.0.0.1:4152";
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    write_certs(dir);
+    let tls = ServerTls::from_pem_files(dir.join("server.pem"), dir.join("server.key"))?;
+    let mut server = KvsServer::new(
+        KvStore::open(dir.join("db"))?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .tls(&tls)?
+    .http_addr(http_addr)?
+    .resp_addr(resp_addr)?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let response = tls_exchange(
+        http_addr,
+        dir,
+        b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\nConnection: close\r\n\r\nvalue1",
+    )?;
+    assert!(response.starts_with(b"HTTP/1.1 204 No Content\r\n"));
+    let response = tls_exchange(resp_addr, dir, b"GET key1\r\nQUIT\r\n")?;
+    assert_eq!(response, b"$6\r\nvalue1\r\n+OK\r\n");
+
+    // a plain client is not served
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/"));
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn tls_command_line() -> Result<()> {
+    let addr = "127.0.0.1:4136";
+    let cert_dir = TempDir::new().unwrap();
+    let dir = cert_dir.path();
+    write_certs(dir);
+    let file = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+    let _server = Server::start_with(
+        "kvs",
+        addr,
+        &[
+            "--tls-cert",
+            &file("server.pem"),
+            "--tls-key",
+            &file("server.key"),
+            "--tls-client-ca",
+            &file("ca.pem"),
+        ],
+    );
+
+    let tls_args = [
+        "--tls-ca".to_owned(),
+        file("ca.pem"),
+        "--tls-cert".to_owned(),
+        file("client.pem"),
+        "--tls-key".to_owned(),
+        file("client.key"),
+    ];
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(&tls_args)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            addr,
+            "--tls-server-name",
+            "localhost",
+        ])
+        .args(&tls_args)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .args(&tls_args[..2])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+const CREDENTIALS: &str = r#"{
+    "admin": {"secret": "hunter2", "read": [""], "write": [""], "admin": true},
+    "app": {"secret": "t0ken", "read": ["app/", "shared/"], "write": ["app/"]},
+    "anonymous": {"read": ["public/"]}
+}"#;
+
+fn is_permission_denied<T>(result: Result<T>) -> bool {
+    matches!(result, Err(e) if e.kind() == ErrorKind::PermissionDenied)
+}
+
+// Should let clients authenticate in the handshake, and hold them to their ACL
+#[test]
+fn authentication() -> Result<()> {
+    let addr = "127.0.0.1:4137";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("credentials.json"), CREDENTIALS)?;
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path().join("db"))?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .credentials(Credentials::from_file(
+        temp_dir.path().join("credentials.json"),
+    )?);
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut admin =
+        KvsClient::connect_with(addr, ClientOptions::default().login("admin", "hunter2"))?;
+    for key in &["app/key1", "shared/key2", "public/key3", "secret/key4"] {
+        admin.set(key.to_string(), "value".to_owned())?;
+    }
+    drop(admin);
+
+    let mut app = KvsClient::connect_with(addr, ClientOptions::default().login("app", "t0ken"))?;
+    app.set("app/key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(app.get("shared/key2".to_owned())?, Some("value".to_owned()));
+    assert!(is_permission_denied(
+        app.set("shared/key2".to_owned(), "value2".to_owned())
+    ));
+    assert!(is_permission_denied(app.get("secret/key4".to_owned())));
+    let mut batch = WriteBatch::new();
+    batch.set("app/key5".to_owned(), "value5".to_owned());
+    batch.remove("secret/key4".to_owned());
+    assert!(is_permission_denied(app.batch(batch)));
+    assert_eq!(app.get("app/key5".to_owned())?, None);
+    // a scan skips the keys which cannot be read
+    assert_eq!(
+        app.scan(..)?,
+        vec![
+            ("app/key1".to_owned(), "value1".to_owned()),
+            ("shared/key2".to_owned(), "value".to_owned()),
+        ]
+    );
+    drop(app);
+
+    assert!(is_permission_denied(KvsClient::connect_with(
+        addr,
+        ClientOptions::default().login("app", "hunter2")
+    )));
+    assert!(is_permission_denied(KvsClient::connect_with(
+        addr,
+        ClientOptions::default().login("nobody", "hunter2")
+    )));
+
+    // the clients which do not authenticate are anonymous
+    let mut anonymous = KvsClient::connect(addr)?;
+    assert_eq!(
+        anonymous.get("public/key3".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert!(is_permission_denied(
+        anonymous.remove("public/key3".to_owned())
+    ));
+    drop(anonymous);
+    let mut stream = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut stream, &json!({"Get": {"key": "app/key1"}}))?;
+    let response = Deserializer::from_reader(&mut stream)
+        .into_iter::<Value>()
+        .next()
+        .unwrap()?;
+    assert_eq!(
+        response,
+        json!({"Err": "Permission denied: anonymous may not read the key app/key1"})
+    );
+    drop(stream);
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Should hold the clients of the Redis protocol and the HTTP gateway to their ACL too
+#[test]
+fn authentication_resp_and_http() -> Result<()> {
+    let addr = "127.0.0.1:4138";
+    let resp_addr = "127.0.0.1:4139";
+    let http_addr = "127.0.0.1:4140";
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("credentials.json");
+    fs::write(&path, CREDENTIALS)?;
+    let _server = Server::start_with(
+        "kvs",
+        addr,
+        &[
+            "--credentials",
+            path.to_str().unwrap(),
+            "--resp-addr",
+            resp_addr,
+            "--http-addr",
+            http_addr,
+        ],
+    );
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    resp_exchange(
+        &mut stream,
+        &[
+            (
+                b"SET app/key1 value1\r\n",
+                b"-NOPERM anonymous may not write the key app/key1\r\n",
+            ),
+            (
+                b"AUTH app hunter2\r\n",
+                b"-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+            ),
+            (b"AUTH app t0ken\r\n", b"+OK\r\n"),
+            (b"SET app/key1 value1\r\n", b"+OK\r\n"),
+            (b"GET app/key1\r\n", b"$6\r\nvalue1\r\n"),
+            (
+                b"DEL app/key1 shared/key2\r\n",
+                b"-NOPERM app may not write the key shared/key2\r\n",
+            ),
+            (b"KEYS *\r\n", b"*1\r\n$8\r\napp/key1\r\n"),
+        ],
+    )?;
+    drop(stream);
+
+    let get = |authorization: &str| -> Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect(http_addr)?;
+        write!(
+            stream,
+            "GET /keys/app%2Fkey1 HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+            authorization
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        Ok((status, response.split_off(head_len)))
+    };
+    let (status, body) = get("")?;
+    assert_eq!(status, 403);
+    let body: Value = serde_json::from_slice(&body)?;
+    assert_eq!(body["kind"], "PermissionDenied");
+    // app:t0ken
+    assert_eq!(
+        get("Authorization: Basic YXBwOnQwa2Vu\r\n")?,
+        (200, b"value1".to_vec())
+    );
+    // app:wrong
+    assert_eq!(get("Authorization: Basic YXBwOndyb25n\r\n")?.0, 401);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app/key1", "--addr", addr, "--user", "app"])
+        .env("KVS_SECRET", "t0ken")
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app/key1", "--addr", addr])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// Should count the requests of every protocol and serve the metrics while the pool is busy
+#[test]
+fn metrics() -> Result<()> {
+    let addr = "127.0.0.1:4141";
+    let metrics_addr = "127.0.0.1:4142";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .metrics_addr(metrics_addr)?;
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // the only thread of the pool stays busy with this client
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    let (status, body) = http(metrics_addr, "GET", "/metrics", b"")?;
+    assert_eq!(status, 200);
+    let body = String::from_utf8(body).unwrap();
+    let value = |name: &str| -> f64 {
+        body.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {} in {}", name, body))
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(value(r#"kvs_requests_total{type="set"}"#), 2.0);
+    assert_eq!(value(r#"kvs_requests_total{type="get"}"#), 2.0);
+    assert_eq!(value(r#"kvs_requests_total{type="rm"}"#), 2.0);
+    assert_eq!(value(r#"kvs_request_errors_total{type="rm"}"#), 0.0);
+    assert_eq!(
+        value(r#"kvs_request_duration_seconds_bucket{type="get",le="+Inf"}"#),
+        2.0
+    );
+    assert_eq!(
+        value(r#"kvs_request_duration_seconds_count{type="set"}"#),
+        2.0
+    );
+    assert!(value(r#"kvs_request_duration_seconds_sum{type="set"}"#) > 0.0);
+    assert_eq!(value("kvs_active_connections"), 1.0);
+    assert_eq!(value("kvs_pool_queued_connections"), 0.0);
+    assert_eq!(value("kvs_engine_keys"), 0.0);
+    assert_eq!(value("kvs_engine_dead_entries"), 3.0);
+    assert_eq!(value("kvs_engine_compactions_total"), 0.0);
+    assert!(value("kvs_engine_disk_bytes") > 0.0);
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram\n"));
+
+    // another connection waits for the pool
+    let _queued = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+    let (_, body) = http(metrics_addr, "GET", "/metrics", b"")?;
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("\nkvs_active_connections 2\n"));
+    assert!(body.contains("\nkvs_pool_queued_connections 1\n"));
+    assert_eq!(http(metrics_addr, "GET", "/keys", b"")?.0, 404);
+    assert_eq!(http(metrics_addr, "POST", "/metrics", b"")?.0, 405);
+    // a head without a request line does not stop the listener
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    stream.write_all(b"\r\n\r\n")?;
+    stream.shutdown(Shutdown::Write)?;
+    stream.read_to_end(&mut Vec::new())?;
+    assert_eq!(http(metrics_addr, "GET", "/metrics", b"")?.0, 200);
+
+    drop(client);
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Should run the admin requests for the principals allowed to
+#[test]
+fn admin_requests() -> Result<()> {
+    let addr = "127.0.0.1:4143";
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("credentials.json"), CREDENTIALS)?;
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path().join("db"))?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .credentials(Credentials::from_file(
+        temp_dir.path().join("credentials.json"),
+    )?);
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut admin =
+        KvsClient::connect_with(addr, ClientOptions::default().login("admin", "hunter2"))?;
+    admin.set("key1".to_owned(), "value1".to_owned())?;
+    admin.set("key1".to_owned(), "value2".to_owned())?;
+    admin.set("key2".to_owned(), "value3".to_owned())?;
+    admin.remove("key2".to_owned())?;
+    let stats = admin.stats()?;
+    assert_eq!(stats.keys, Some(1));
+    assert_eq!(stats.dead_entries, Some(3));
+    assert_eq!(stats.compactions, Some(0));
+    assert!(stats.disk_bytes > 0);
+
+    admin.compact()?;
+    let stats = admin.stats()?;
+    assert_eq!(stats.dead_entries, Some(0));
+    assert_eq!(stats.compactions, Some(1));
+    assert_eq!(admin.get("key1".to_owned())?, Some("value2".to_owned()));
+    admin.flush()?;
+    let info = admin.info()?;
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine, "kvs");
+    assert!(info.uptime >= Duration::from_millis(500));
+    drop(admin);
+
+    let mut app = KvsClient::connect_with(addr, ClientOptions::default().login("app", "t0ken"))?;
+    assert!(is_permission_denied(app.stats()));
+    assert!(is_permission_denied(app.compact()));
+    drop(app);
+    let mut anonymous = KvsClient::connect(addr)?;
+    assert!(is_permission_denied(anonymous.info()));
+    drop(anonymous);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "stats", "--addr", addr, "--user", "admin"])
+        .env("KVS_SECRET", "hunter2")
+        .assert()
+        .success()
+        .stdout(starts_with("keys\t1\ndisk_bytes\t"))
+        .stdout(contains("\ndead_entries\t0\ncompactions\t1\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "compact", "--addr", addr, "--user", "app"])
+        .env("KVS_SECRET", "t0ken")
+        .assert()
+        .failure()
+        .stderr(contains("app may not run admin requests"));
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Should answer the admin commands of kvs-client for an engine without compaction
+#[test]
+fn admin_commands_sled_engine() -> Result<()> {
+    let addr = "127.0.0.1:4144";
+    let _server = Server::start("sled", addr);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "compact", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "flush", "--addr", addr])
+        .assert()
+        .success();
+    // sled does not count its keys
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "stats", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(starts_with("disk_bytes\t"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "info", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("\nengine\tsled\n"));
+    Ok(())
+}
+
+// Polls `check` until it holds, for up to 5 seconds
+fn eventually<F: FnMut() -> Result<bool>>(mut check: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check().unwrap() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn scan_all(addr: &str) -> Result<Vec<(String, String)>> {
+    KvsClient::connect(addr)?.scan(..)
+}
+
+// Should copy the keys of the leader to a follower, then its writes, until promoted
+#[test]
+fn replication() -> Result<()> {
+    let leader_addr = "127.0.0.1:4145";
+    let follower_addr = "127.0.0.1:4146";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(follower_dir.path())?;
+        store.set("key1".to_owned(), "stale".to_owned())?;
+        store.set("stale".to_owned(), "value".to_owned())?;
+    }
+
+    let mut leader = KvsServer::new(
+        KvStore::open(leader_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let leader_handle = leader.shutdown_handle();
+    let leader_thread = thread::spawn(move || leader.run(leader_addr));
+    thread::sleep(Duration::from_millis(500));
+    let mut client = KvsClient::connect(leader_addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    drop(client);
+
+    let mut follower = KvsServer::new(
+        KvStore::open(follower_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .replica_of(leader_addr, ClientOptions::default())?;
+    let follower_handle = follower.shutdown_handle();
+    let follower_thread = thread::spawn(move || follower.run(follower_addr));
+    thread::sleep(Duration::from_millis(500));
+    eventually(|| Ok(scan_all(follower_addr)? == pairs(&[("key1", "value1"), ("key2", "value2")])));
+
+    let mut client = KvsClient::connect(leader_addr)?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert!(client.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value4".to_owned())
+    )?);
+    assert!(!client.compare_and_swap("key1".to_owned(), None, None)?);
+    let mut batch = WriteBatch::new();
+    batch.remove("key3".to_owned());
+    batch.set("key5".to_owned(), "value5".to_owned());
+    client.batch(batch)?;
+    assert_eq!(client.info()?.leader, None);
+    drop(client);
+    eventually(|| Ok(scan_all(follower_addr)? == pairs(&[("key1", "value4"), ("key5", "value5")])));
+
+    let mut client = KvsClient::connect(follower_addr)?;
+    assert_eq!(client.get("key5".to_owned())?, Some("value5".to_owned()));
+    match client.set("key6".to_owned(), "value6".to_owned()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ReadOnly),
+        Ok(()) => panic!("a follower accepted a write"),
+    }
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(client.info()?.leader, Some(leader_addr.to_owned()));
+
+    client.promote()?;
+    assert_eq!(client.info()?.leader, None);
+    client.set("key6".to_owned(), "value6".to_owned())?;
+    drop(client);
+    // the old leader is no longer followed
+    KvsClient::connect(leader_addr)?.set("key7".to_owned(), "value7".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        scan_all(follower_addr)?,
+        pairs(&[("key1", "value4"), ("key5", "value5"), ("key6", "value6")])
+    );
+
+    follower_handle.shutdown();
+    follower_thread.join().unwrap()?;
+    leader_handle.shutdown();
+    leader_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Should expire the keys on a follower when they expire on the leader, whether they came
+// with the snapshot or with the writes
+#[test]
+fn replication_ttl() -> Result<()> {
+    let leader_addr = "127.0.0.1:4153";
+    let follower_addr = "127.0.0.1:4154";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+
+    let mut leader = KvsServer::new(
+        SledKvsEngine::open(leader_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let leader_handle = leader.shutdown_handle();
+    let leader_thread = thread::spawn(move || leader.run(leader_addr));
+    thread::sleep(Duration::from_millis(500));
+    let start = Instant::now();
+    let ttl = Duration::from_secs(2);
+    let mut client = KvsClient::connect(leader_addr)?;
+    client.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    drop(client);
+
+    let mut follower = KvsServer::new(
+        KvStore::open(follower_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .replica_of(leader_addr, ClientOptions::default())?;
+    let follower_handle = follower.shutdown_handle();
+    let follower_thread = thread::spawn(move || follower.run(follower_addr));
+    thread::sleep(Duration::from_millis(500));
+    eventually(|| Ok(scan_all(follower_addr)? == pairs(&[("key1", "value1"), ("key2", "value2")])));
+    let mut client = KvsClient::connect(leader_addr)?;
+    client.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        ttl - start.elapsed(),
+    )?;
+    drop(client);
+
+    // both keys expire when they were set to on the leader, though copied later
+    thread::sleep(ttl - start.elapsed() + Duration::from_millis(100));
+    assert_eq!(scan_all(follower_addr)?, vec![]);
+    assert_eq!(scan_all(leader_addr)?, vec![]);
+
+    follower_handle.shutdown();
+    follower_thread.join().unwrap()?;
+    leader_handle.shutdown();
+    leader_thread.join().unwrap()?;
+    Ok(())
+}
+
+// Should follow a leader with credentials from the command line, and promote the follower
+// with kvs-client
+#[test]
+fn replication_command_line() -> Result<()> {
+    let leader_addr = "127.0.0.1:4147";
+    let follower_addr = "127.0.0.1:4148";
+    let temp_dir = TempDir::new().unwrap();
+    let credentials = temp_dir.path().join("credentials.json");
+    fs::write(&credentials, CREDENTIALS)?;
+    let _leader = Server::start_with(
+        "kvs",
+        leader_addr,
+        &["--credentials", credentials.to_str().unwrap()],
+    );
+    let login = ClientOptions::default().login("admin", "hunter2");
+    KvsClient::connect_with(leader_addr, login)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let _follower = Server::start_with(
+        "kvs",
+        follower_addr,
+        &[
+            "--replica-of",
+            leader_addr,
+            "--replica-user",
+            "admin",
+            "--replica-secret",
+            "hunter2",
+        ],
+    );
+    eventually(|| Ok(scan_all(follower_addr)? == pairs(&[("key1", "value1")])));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", follower_addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", follower_addr])
+        .assert()
+        .failure()
+        .stderr(contains(format!(
+            "send writes to the leader {}",
+            leader_addr
+        )));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "info", "--addr", follower_addr])
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "\nrole\tfollower\nleader\t{}\n",
+            leader_addr
+        )));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "promote", "--addr", follower_addr])
+        .assert()
+        .success()
+        .stdout("");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", follower_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "info", "--addr", follower_addr])
+        .assert()
+        .success()
+        .stdout(contains("\nrole\tleader\n"));
+    Ok(())
+}